@@ -0,0 +1,173 @@
+//! Guest-physical address map of a VM.
+//!
+//! Memory regions, emulated devices and passthrough devices all claim ranges of the guest
+//! physical address (GPA) space. [`GpaMap`] collects these ranges in one place so that
+//! overlapping claims can be detected before the VM is created.
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{Display, Formatter};
+
+use crate::{AxVMCrateConfig, VMDevicesConfig, VMKernelConfig};
+
+/// The kind of configuration entry a [`GpaRange`] comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GpaOwnerKind {
+    /// An entry of `kernel.memory_regions`.
+    MemoryRegion,
+    /// An entry of `devices.emu_devices`.
+    EmulatedDevice,
+    /// An entry of `devices.passthrough_devices`.
+    PassthroughDevice,
+}
+
+impl Display for GpaOwnerKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            GpaOwnerKind::MemoryRegion => write!(f, "memory region"),
+            GpaOwnerKind::EmulatedDevice => write!(f, "emulated device"),
+            GpaOwnerKind::PassthroughDevice => write!(f, "passthrough device"),
+        }
+    }
+}
+
+/// A range of guest physical addresses claimed by a configuration entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GpaRange {
+    /// What kind of entry claims the range.
+    pub kind: GpaOwnerKind,
+    /// The index of the owner within its list, e.g. `2` for `devices.emu_devices[2]`.
+    pub index: usize,
+    /// The name of the owner: the device name for devices, the key path for memory regions.
+    pub name: String,
+    /// The TOML key path of the owner, e.g. `devices.emu_devices[2]`.
+    pub path: String,
+    /// The first guest physical address of the range.
+    pub start: usize,
+    /// The size of the range in bytes.
+    pub size: usize,
+}
+
+impl GpaRange {
+    /// Returns the address right after the range, saturating at the end of the address space.
+    pub fn end(&self) -> usize {
+        self.start.saturating_add(self.size)
+    }
+
+    /// Returns true if the two ranges share at least one address.
+    pub fn overlaps(&self, other: &GpaRange) -> bool {
+        crate::validate::ranges_overlap(self.start, self.end(), other.start, other.end())
+    }
+}
+
+impl Display for GpaRange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self.kind {
+            GpaOwnerKind::MemoryRegion => write!(f, "{} {}", self.kind, self.name)?,
+            _ => write!(f, "{} '{}'", self.kind, self.name)?,
+        }
+        write!(f, " [{:#x}, {:#x})", self.start, self.end())
+    }
+}
+
+/// A pair of entries whose guest physical ranges overlap.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GpaOverlap {
+    /// The range with the lower start address.
+    pub first: GpaRange,
+    /// The range with the higher (or equal) start address.
+    pub second: GpaRange,
+}
+
+/// The guest physical address map of a VM, sorted by start address.
+#[derive(Debug, Default, Clone)]
+pub struct GpaMap {
+    ranges: Vec<GpaRange>,
+}
+
+impl GpaMap {
+    /// Creates an empty address map.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Builds the address map from the memory regions and devices of a VM.
+    ///
+    /// Entries with zero size are skipped, e.g. passthrough devices given only by name, whose
+    /// ranges are resolved from the device tree later.
+    pub fn build(kernel: &VMKernelConfig, devices: &VMDevicesConfig) -> Self {
+        let mut map = Self::new();
+        for (i, region) in kernel.memory_regions.iter().enumerate() {
+            let path = format!("kernel.memory_regions[{i}]");
+            map.insert(GpaRange {
+                kind: GpaOwnerKind::MemoryRegion,
+                index: i,
+                name: path.clone(),
+                path,
+                start: region.gpa,
+                size: region.size,
+            });
+        }
+        for (i, dev) in devices.emu_devices.iter().enumerate() {
+            map.insert(GpaRange {
+                kind: GpaOwnerKind::EmulatedDevice,
+                index: i,
+                name: dev.name.clone(),
+                path: format!("devices.emu_devices[{i}]"),
+                start: dev.base_gpa,
+                size: dev.length,
+            });
+        }
+        for (i, dev) in devices.passthrough_devices.iter().enumerate() {
+            map.insert(GpaRange {
+                kind: GpaOwnerKind::PassthroughDevice,
+                index: i,
+                name: dev.name.clone(),
+                path: format!("devices.passthrough_devices[{i}]"),
+                start: dev.base_gpa,
+                size: dev.length,
+            });
+        }
+        map
+    }
+
+    /// Adds a range to the map, keeping the map sorted. Zero-sized ranges are ignored.
+    pub fn insert(&mut self, range: GpaRange) {
+        if range.size == 0 {
+            return;
+        }
+        let pos = self.ranges.partition_point(|r| r.start <= range.start);
+        self.ranges.insert(pos, range);
+    }
+
+    /// Returns all ranges in the map, sorted by start address.
+    pub fn ranges(&self) -> &[GpaRange] {
+        &self.ranges
+    }
+
+    /// Returns every pair of overlapping ranges.
+    pub fn overlaps(&self) -> Vec<GpaOverlap> {
+        let mut overlaps = Vec::new();
+        for (i, first) in self.ranges.iter().enumerate() {
+            // Ranges are sorted by start, so only the following ranges that start before `first`
+            // ends can overlap it.
+            for second in self.ranges[i + 1..]
+                .iter()
+                .take_while(|r| r.start < first.end())
+            {
+                overlaps.push(GpaOverlap {
+                    first: first.clone(),
+                    second: second.clone(),
+                });
+            }
+        }
+        overlaps
+    }
+}
+
+impl AxVMCrateConfig {
+    /// Builds the guest physical address map of the VM, see [`GpaMap::build`].
+    pub fn gpa_map(&self) -> GpaMap {
+        GpaMap::build(&self.kernel, &self.devices)
+    }
+}
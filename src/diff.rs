@@ -0,0 +1,393 @@
+//! Semantic comparison of VM configurations.
+//!
+//! A textual diff of positional tuples cannot tell a renamed device from a moved one.
+//! [`AxVMCrateConfig::diff`] compares the configurations themselves instead: memory regions are
+//! matched by their guest physical address and devices by their name, so a change is reported
+//! on the field that actually changed.
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use alloc::{format, vec};
+use core::fmt::{Display, Formatter};
+
+use crate::writer::{format_hex, format_list, format_str, interrupt_mode_name, key_order};
+use crate::AxVMCrateConfig;
+
+/// What happened to a value between two configurations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    /// The value only exists in the new configuration.
+    Added,
+    /// The value only exists in the old configuration.
+    Removed,
+    /// The value exists in both configurations, with different contents.
+    Changed,
+    /// A device was renamed; everything but its name is the same.
+    Renamed,
+}
+
+impl Display for ChangeKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ChangeKind::Added => write!(f, "added"),
+            ChangeKind::Removed => write!(f, "removed"),
+            ChangeKind::Changed => write!(f, "changed"),
+            ChangeKind::Renamed => write!(f, "renamed"),
+        }
+    }
+}
+
+/// A difference between two VM configurations, found by [`AxVMCrateConfig::diff`].
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct ConfigChange {
+    /// What happened to the value.
+    pub kind: ChangeKind,
+    /// The TOML key path of the value. Memory regions are selected by their guest physical
+    /// address and devices by their name, e.g. `kernel.memory_regions[gpa=0x8000_0000].size`
+    /// or `devices.passthrough_devices[name=uart]`.
+    pub path: String,
+    /// The old value as written in TOML, `None` if the value was added. Serialized as the
+    /// value itself, e.g. `0x1000` as the number 4096.
+    #[serde(serialize_with = "serialize_toml_value")]
+    pub old: Option<String>,
+    /// The new value as written in TOML, `None` if the value was removed. Serialized like
+    /// `old`.
+    #[serde(serialize_with = "serialize_toml_value")]
+    pub new: Option<String>,
+}
+
+/// Serializes a value written in TOML as the value it denotes, so that e.g. JSON gets numbers,
+/// strings and arrays instead of TOML source text.
+fn serialize_toml_value<S: serde::Serializer>(
+    text: &Option<String>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    use serde::ser::{Error, Serialize};
+
+    let Some(text) = text else {
+        return serializer.serialize_none();
+    };
+    let mut table: toml::Table = toml::from_str(&format!("value = {text}"))
+        .map_err(|err| S::Error::custom(format!("invalid TOML value {text}: {}", err.message())))?;
+    table.remove("value").serialize(serializer)
+}
+
+impl Display for ConfigChange {
+    /// Formats the change as `<kind> <path>: <old> -> <new>`, with only the value that exists
+    /// for added and removed values.
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{} {}", self.kind, self.path)?;
+        match (&self.old, &self.new) {
+            (Some(old), Some(new)) => write!(f, ": {old} -> {new}"),
+            (Some(value), None) | (None, Some(value)) => write!(f, ": {value}"),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+/// Formats a selector of an array element by a field, e.g. `[name=uart]`. The value is quoted
+/// unless it only has characters that cannot be confused with the key path syntax.
+fn selector(key: &str, value: &str) -> String {
+    let bare = !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '@'));
+    if bare {
+        format!("[{key}={value}]")
+    } else {
+        format!("[{key}={}]", format_str(value))
+    }
+}
+
+/// Collects the changes found while comparing two configurations.
+#[derive(Default)]
+struct Changes(Vec<ConfigChange>);
+
+impl Changes {
+    fn push(&mut self, kind: ChangeKind, path: String, old: Option<String>, new: Option<String>) {
+        self.0.push(ConfigChange {
+            kind,
+            path,
+            old,
+            new,
+        });
+    }
+
+    /// Compares a value that exists in both configurations.
+    fn value(&mut self, path: impl Into<String>, old: String, new: String) {
+        if old != new {
+            self.push(ChangeKind::Changed, path.into(), Some(old), Some(new));
+        }
+    }
+
+    /// Compares an optional value.
+    fn option(&mut self, path: impl Into<String>, old: Option<String>, new: Option<String>) {
+        let kind = match (&old, &new) {
+            (Some(old), Some(new)) if old == new => return,
+            (None, None) => return,
+            (Some(_), Some(_)) => ChangeKind::Changed,
+            (None, Some(_)) => ChangeKind::Added,
+            (Some(_), None) => ChangeKind::Removed,
+        };
+        self.push(kind, path.into(), old, new);
+    }
+
+    /// Compares two lists of elements matched by a key, e.g. devices by name.
+    ///
+    /// Elements are given as their key and their fields as `(name, value)` pairs. Elements
+    /// with the same key are compared field by field; with `rename`, an unmatched old element
+    /// and an unmatched new element whose fields other than the key are all equal are reported
+    /// as renamed. Other unmatched elements are removed or added.
+    fn keyed(
+        &mut self,
+        path: &str,
+        key: &str,
+        old: Vec<(String, Vec<(&str, String)>)>,
+        new: Vec<(String, Vec<(&str, String)>)>,
+        rename: bool,
+    ) {
+        let tuple =
+            |fields: &[(&str, String)]| format_list(fields.iter().map(|(_, value)| value.clone()));
+        let mut matched = vec![false; new.len()];
+        let mut unmatched = Vec::new();
+        for (old_key, old_fields) in &old {
+            let found = (0..new.len()).find(|&i| !matched[i] && new[i].0 == *old_key);
+            let Some(i) = found else {
+                unmatched.push((old_key, old_fields));
+                continue;
+            };
+            matched[i] = true;
+            let element = format!("{path}{}", selector(key, old_key));
+            for ((field, old_value), (_, new_value)) in old_fields.iter().zip(&new[i].1) {
+                self.value(
+                    format!("{element}.{field}"),
+                    old_value.clone(),
+                    new_value.clone(),
+                );
+            }
+        }
+
+        let same_but_key = |a: &[(&str, String)], b: &[(&str, String)]| {
+            a.iter()
+                .zip(b)
+                .all(|((field, a), (_, b))| *field == key || a == b)
+        };
+        for (old_key, old_fields) in unmatched {
+            let element = format!("{path}{}", selector(key, old_key));
+            let renamed = (0..new.len())
+                .find(|&i| rename && !matched[i] && same_but_key(old_fields, &new[i].1));
+            match renamed {
+                Some(i) => {
+                    matched[i] = true;
+                    self.push(
+                        ChangeKind::Renamed,
+                        element,
+                        Some(format_str(old_key)),
+                        Some(format_str(&new[i].0)),
+                    );
+                }
+                None => self.push(ChangeKind::Removed, element, Some(tuple(old_fields)), None),
+            }
+        }
+        for (i, (new_key, new_fields)) in new.iter().enumerate() {
+            if !matched[i] {
+                let element = format!("{path}{}", selector(key, new_key));
+                self.push(ChangeKind::Added, element, None, Some(tuple(new_fields)));
+            }
+        }
+    }
+}
+
+impl AxVMCrateConfig {
+    /// Compares the configuration with a newer one and returns the differences, in the order
+    /// of the fields of the configuration.
+    ///
+    /// Fields are compared by value, so writing an address in hexadecimal or a size with a
+    /// unit makes no difference. Memory regions are matched by their guest physical address:
+    /// a resized region is reported as a changed `size`, a moved one as removed and added.
+    /// Emulated and passthrough devices are matched by name, and a device whose name changed
+    /// but nothing else is reported as renamed. Excluded device groups are compared as a set.
+    /// `config_version` is not compared, as both configurations are read in the current schema.
+    pub fn diff(&self, new: &Self) -> Vec<ConfigChange> {
+        let mut changes = Changes::default();
+        let hex_list = |list: &[usize]| format_list(list.iter().copied().map(format_hex));
+
+        let (old_base, new_base) = (&self.base, &new.base);
+        changes.value("base.id", old_base.id.to_string(), new_base.id.to_string());
+        changes.value(
+            "base.name",
+            format_str(&old_base.name),
+            format_str(&new_base.name),
+        );
+        changes.value(
+            "base.vm_type",
+            format_str(old_base.vm_type.name()),
+            format_str(new_base.vm_type.name()),
+        );
+        changes.value(
+            "base.cpu_num",
+            old_base.cpu_num.to_string(),
+            new_base.cpu_num.to_string(),
+        );
+        changes.option(
+            "base.phys_cpu_ids",
+            old_base.phys_cpu_ids.as_deref().map(hex_list),
+            new_base.phys_cpu_ids.as_deref().map(hex_list),
+        );
+        changes.option(
+            "base.phys_cpu_sets",
+            old_base.phys_cpu_sets.as_deref().map(hex_list),
+            new_base.phys_cpu_sets.as_deref().map(hex_list),
+        );
+
+        let (old_kernel, new_kernel) = (&self.kernel, &new.kernel);
+        changes.value(
+            "kernel.entry_point",
+            format_hex(old_kernel.entry_point),
+            format_hex(new_kernel.entry_point),
+        );
+        changes.value(
+            "kernel.kernel_path",
+            format_str(&old_kernel.kernel_path),
+            format_str(&new_kernel.kernel_path),
+        );
+        changes.value(
+            "kernel.kernel_load_addr",
+            format_hex(old_kernel.kernel_load_addr),
+            format_hex(new_kernel.kernel_load_addr),
+        );
+        let paths = [
+            ("bios_path", &old_kernel.bios_path, &new_kernel.bios_path),
+            ("dtb_path", &old_kernel.dtb_path, &new_kernel.dtb_path),
+            (
+                "ramdisk_path",
+                &old_kernel.ramdisk_path,
+                &new_kernel.ramdisk_path,
+            ),
+            ("disk_path", &old_kernel.disk_path, &new_kernel.disk_path),
+            ("cmdline", &old_kernel.cmdline, &new_kernel.cmdline),
+        ];
+        for (key, old, new) in paths {
+            changes.option(
+                format!("kernel.{key}"),
+                old.as_deref().map(format_str),
+                new.as_deref().map(format_str),
+            );
+        }
+        let load_addrs = [
+            (
+                "bios_load_addr",
+                old_kernel.bios_load_addr,
+                new_kernel.bios_load_addr,
+            ),
+            (
+                "dtb_load_addr",
+                old_kernel.dtb_load_addr,
+                new_kernel.dtb_load_addr,
+            ),
+            (
+                "ramdisk_load_addr",
+                old_kernel.ramdisk_load_addr,
+                new_kernel.ramdisk_load_addr,
+            ),
+        ];
+        for (key, old, new) in load_addrs {
+            changes.option(
+                format!("kernel.{key}"),
+                old.map(format_hex),
+                new.map(format_hex),
+            );
+        }
+        changes.value(
+            "kernel.image_location",
+            format_str(old_kernel.image_location.name()),
+            format_str(new_kernel.image_location.name()),
+        );
+        let regions = |config: &Self| {
+            config
+                .kernel
+                .memory_regions
+                .iter()
+                .map(|region| {
+                    let fields = key_order("kernel.memory_regions").iter().copied();
+                    (
+                        format_hex(region.gpa),
+                        fields.zip(region.tuple_cells()).collect(),
+                    )
+                })
+                .collect()
+        };
+        changes.keyed(
+            "kernel.memory_regions",
+            "gpa",
+            regions(self),
+            regions(new),
+            false,
+        );
+
+        let emu_devices = |config: &Self| {
+            config
+                .devices
+                .emu_devices
+                .iter()
+                .map(|dev| {
+                    let fields = key_order("devices.emu_devices").iter().copied();
+                    (dev.name.clone(), fields.zip(dev.tuple_cells()).collect())
+                })
+                .collect()
+        };
+        changes.keyed(
+            "devices.emu_devices",
+            "name",
+            emu_devices(self),
+            emu_devices(new),
+            true,
+        );
+        let passthrough_devices = |config: &Self| {
+            config
+                .devices
+                .passthrough_devices
+                .iter()
+                .map(|dev| {
+                    let fields = key_order("devices.passthrough_devices").iter().copied();
+                    (dev.name.clone(), fields.zip(dev.tuple_cells()).collect())
+                })
+                .collect()
+        };
+        changes.keyed(
+            "devices.passthrough_devices",
+            "name",
+            passthrough_devices(self),
+            passthrough_devices(new),
+            true,
+        );
+        changes.value(
+            "devices.interrupt_mode",
+            format_str(interrupt_mode_name(self.devices.interrupt_mode)),
+            format_str(interrupt_mode_name(new.devices.interrupt_mode)),
+        );
+        let groups = |config: &Self| -> Vec<String> {
+            config
+                .devices
+                .excluded_devices
+                .iter()
+                .map(|group| format_list(group.iter().map(|path| format_str(path))))
+                .collect()
+        };
+        let (old_groups, new_groups) = (groups(self), groups(new));
+        for group in old_groups
+            .iter()
+            .filter(|group| !new_groups.contains(group))
+        {
+            changes.option("devices.excluded_devices", Some(group.clone()), None);
+        }
+        for group in new_groups
+            .iter()
+            .filter(|group| !old_groups.contains(group))
+        {
+            changes.option("devices.excluded_devices", None, Some(group.clone()));
+        }
+
+        changes.0
+    }
+}
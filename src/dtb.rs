@@ -0,0 +1,904 @@
+//! Conversions between device trees and VM configurations.
+//!
+//! Host device trees describe the MMIO devices of a board with their `reg` ranges and
+//! `interrupts`. This module turns them into [`PassThroughDeviceConfig`] entries, so that
+//! `passthrough_devices` no longer has to be written by hand from `/proc/device-tree` dumps, and
+//! generates guest device trees from a VM configuration, so that they no longer have to be kept
+//! in sync with it by hand.
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::fdt::{cells_to_u64, Fdt, FdtNode, FdtProperty};
+use crate::{AxVMCrateConfig, EmulatedDeviceType, PassThroughDeviceConfig, VmMemFlags};
+
+/// The first SPI number on GIC interrupt controllers.
+const GIC_SPI_BASE: usize = 32;
+/// The first PPI number on GIC interrupt controllers.
+const GIC_PPI_BASE: usize = 16;
+/// The size of the GICv2 distributor and CPU interface ranges on the QEMU `virt` machine.
+const GICV2_FRAME_SIZE: usize = 0x1_0000;
+
+/// Returns true if `path` is one of the `excluded_devices` paths or a descendant of one.
+pub(crate) fn is_excluded(path: &str, excluded_devices: &[Vec<String>]) -> bool {
+    excluded_devices.iter().flatten().any(|excluded| {
+        let excluded = excluded.trim_end_matches('/');
+        path == excluded
+            || path
+                .strip_prefix(excluded)
+                .is_some_and(|rest| rest.starts_with('/'))
+    })
+}
+
+/// Translates a bus address of a child of `ancestors.last()` into a CPU physical address,
+/// following the `ranges` of every bus up to the root. Returns `None` if a bus has no `ranges`,
+/// i.e. its children are not memory-mapped, or no range covers the address.
+fn translate(mut addr: u64, ancestors: &[&FdtNode]) -> Option<u64> {
+    for level in (1..ancestors.len()).rev() {
+        let (bus, parent) = (ancestors[level], ancestors[level - 1]);
+        let ranges = bus.property("ranges")?.cells();
+        if ranges.is_empty() {
+            continue;
+        }
+        let (child_cells, parent_cells, size_cells) = (
+            bus.address_cells(),
+            parent.address_cells(),
+            bus.size_cells(),
+        );
+        let entry_cells = child_cells + parent_cells + size_cells;
+        if entry_cells == 0 {
+            return None;
+        }
+        addr = ranges.chunks_exact(entry_cells).find_map(|entry| {
+            let child = cells_to_u64(&entry[..child_cells]);
+            let parent_addr = cells_to_u64(&entry[child_cells..child_cells + parent_cells]);
+            let size = cells_to_u64(&entry[child_cells + parent_cells..]);
+            // Entries whose translated address overflows are malformed and skipped.
+            (child <= addr && addr - child < size)
+                .then(|| parent_addr.checked_add(addr - child))
+                .flatten()
+        })?;
+    }
+    Some(addr)
+}
+
+/// Returns the interrupt parent of a node, from its own or its nearest ancestor's
+/// `interrupt-parent` property.
+fn interrupt_parent<'a>(
+    fdt: &'a Fdt,
+    node: &FdtNode,
+    ancestors: &[&FdtNode],
+) -> Option<&'a FdtNode> {
+    let phandle = core::iter::once(node)
+        .chain(ancestors.iter().rev().copied())
+        .find_map(|node| node.prop_u32("interrupt-parent"))?;
+    fdt.find_phandle(phandle).map(|(_, node)| node)
+}
+
+/// Converts the first cells of an interrupt specifier into an interrupt number of the
+/// controller. GIC specifiers `<type number flags>` are translated to the GIC interrupt id, i.e.
+/// SPIs start at 32 and PPIs at 16; other controllers use the first cell as the number.
+fn interrupt_number(controller: Option<&FdtNode>, spec: &[u32]) -> Option<usize> {
+    let is_gic = controller.is_some_and(|intc| {
+        intc.is_compatible_with("arm,gic") || intc.is_compatible_with("arm,cortex-a")
+    });
+    match spec {
+        [kind, number, ..] if is_gic => match kind {
+            0 => Some(*number as usize + GIC_SPI_BASE),
+            1 => Some(*number as usize + GIC_PPI_BASE),
+            _ => None,
+        },
+        [number, ..] => Some(*number as usize),
+        [] => None,
+    }
+}
+
+/// Returns the first interrupt of a node, from `interrupts-extended` or `interrupts`.
+fn first_interrupt(fdt: &Fdt, node: &FdtNode, ancestors: &[&FdtNode]) -> Option<usize> {
+    if let Some(prop) = node.property("interrupts-extended") {
+        let cells = prop.cells();
+        let (_, controller) = fdt.find_phandle(*cells.first()?)?;
+        return interrupt_number(Some(controller), &cells[1..]);
+    }
+    let cells = node.property("interrupts")?.cells();
+    let controller = interrupt_parent(fdt, node, ancestors);
+    let spec_cells = controller
+        .and_then(|intc| intc.prop_u32("#interrupt-cells"))
+        .map_or(cells.len(), |n| n as usize);
+    interrupt_number(controller, cells.get(..spec_cells)?)
+}
+
+/// Returns true if the node is a subtree that has `reg` properties but no devices, i.e. CPUs,
+/// RAM and reserved memory.
+fn is_not_a_device(path: &str, node: &FdtNode) -> bool {
+    node.prop_str("device_type")
+        .is_some_and(|ty| ty == "memory" || ty == "cpu")
+        || path == "/cpus"
+        || path == "/reserved-memory"
+}
+
+/// Returns the CPU physical address of the first `reg` region of a node.
+fn first_reg_base(node: &FdtNode, ancestors: &[&FdtNode]) -> Option<u64> {
+    let address_cells = ancestors.last()?.address_cells();
+    let reg = node.property("reg")?.cells();
+    translate(cells_to_u64(reg.get(..address_cells)?), ancestors)
+}
+
+/// Finds the node of a host device tree a passthrough device was taken from: the node at the
+/// path the device is named after, as written by [`Fdt::passthrough_devices`], or else the
+/// enabled device node whose first `reg` region starts at the host address of the device.
+fn host_node<'a>(host: &'a Fdt, key: &str, base_hpa: usize) -> Option<(String, &'a FdtNode)> {
+    if let Some(node) = key.starts_with('/').then(|| host.find_node(key)).flatten() {
+        return Some((String::from(key), node));
+    }
+    let mut found = None;
+    host.walk(|path, node, ancestors| {
+        if found.is_none()
+            && node.is_enabled()
+            && !is_not_a_device(path, node)
+            && first_reg_base(node, ancestors) == Some(base_hpa as u64)
+        {
+            found = Some((String::from(path), node));
+        }
+    });
+    found
+}
+
+impl Fdt {
+    /// Returns a passthrough device entry for every enabled memory-mapped device node of the
+    /// device tree.
+    ///
+    /// Each `reg` region of a node becomes one entry, identity-mapped (`base_gpa` equals
+    /// `base_hpa`) and named after the node path; the second and later regions of a node get a
+    /// `#<index>` suffix. `irq_id` is the first interrupt of the node, or 0 if it has none.
+    ///
+    /// Nodes in `excluded_devices` (the same groups of node paths as
+    /// [`VMDevicesConfig::excluded_devices`](crate::VMDevicesConfig::excluded_devices)) and
+    /// their descendants are skipped, as are CPUs, memory and reserved memory, disabled nodes,
+    /// and nodes on buses that are not memory-mapped.
+    pub fn passthrough_devices(
+        &self,
+        excluded_devices: &[Vec<String>],
+    ) -> Vec<PassThroughDeviceConfig> {
+        let mut devices = Vec::new();
+        // Paths of subtrees that are skipped as a whole.
+        let mut skipped: Vec<String> = Vec::new();
+        self.walk(|path, node, ancestors| {
+            let Some(parent) = ancestors.last() else {
+                return;
+            };
+            if is_excluded(path, excluded_devices)
+                || is_excluded(path, core::slice::from_ref(&skipped))
+            {
+                return;
+            }
+            if !node.is_enabled() || is_not_a_device(path, node) {
+                skipped.push(String::from(path));
+                return;
+            }
+            let Some(reg) = node.property("reg") else {
+                return;
+            };
+
+            let (address_cells, size_cells) = (parent.address_cells(), parent.size_cells());
+            if size_cells == 0 {
+                return;
+            }
+            let irq_id = first_interrupt(self, node, ancestors).unwrap_or(0);
+            let reg = reg.cells();
+            for (index, entry) in reg.chunks_exact(address_cells + size_cells).enumerate() {
+                let size = cells_to_u64(&entry[address_cells..]) as usize;
+                let Some(base) = translate(cells_to_u64(&entry[..address_cells]), ancestors) else {
+                    continue;
+                };
+                if size == 0 {
+                    continue;
+                }
+                devices.push(PassThroughDeviceConfig {
+                    name: match index {
+                        0 => String::from(path),
+                        _ => format!("{path}#{index}"),
+                    },
+                    base_gpa: base as usize,
+                    base_hpa: base as usize,
+                    length: size,
+                    irq_id,
+                });
+            }
+        });
+        devices
+    }
+}
+
+/// Properties that hold a list of `<phandle specifier>` entries, and the property of the
+/// referenced node giving the number of specifier cells.
+const PHANDLE_LIST_PROPERTIES: &[(&str, &str)] = &[
+    ("interrupts-extended", "#interrupt-cells"),
+    ("clocks", "#clock-cells"),
+    ("assigned-clocks", "#clock-cells"),
+    ("resets", "#reset-cells"),
+    ("power-domains", "#power-domain-cells"),
+    ("dmas", "#dma-cells"),
+    ("phys", "#phy-cells"),
+    ("iommus", "#iommu-cells"),
+    ("mboxes", "#mbox-cells"),
+    ("msi-parent", "#msi-cells"),
+    ("pwms", "#pwm-cells"),
+    ("io-channels", "#io-channel-cells"),
+    ("thermal-sensors", "#thermal-sensor-cells"),
+];
+
+/// Returns the phandles a node refers to, including the interrupt parent inherited from its
+/// ancestors if the node has `interrupts`.
+fn phandle_references(fdt: &Fdt, node: &FdtNode, ancestors: &[&FdtNode]) -> Vec<u32> {
+    let specifier_cells = |phandle: u32, cells_name: &str| {
+        fdt.find_phandle(phandle)
+            .and_then(|(_, target)| target.prop_u32(cells_name))
+            .map_or(0, |n| n as usize)
+    };
+    let mut refs = Vec::new();
+    if node.property("interrupts").is_some() {
+        refs.extend(
+            core::iter::once(node)
+                .chain(ancestors.iter().rev().copied())
+                .find_map(|node| node.prop_u32("interrupt-parent")),
+        );
+    } else if let Some(phandle) = node.prop_u32("interrupt-parent") {
+        refs.push(phandle);
+    }
+    for prop in &node.properties {
+        let name = prop.name.as_str();
+        let cells_name = match PHANDLE_LIST_PROPERTIES.iter().find(|(n, _)| *n == name) {
+            Some((_, cells_name)) => Some(*cells_name),
+            None if name == "gpios" || name.ends_with("-gpios") => Some("#gpio-cells"),
+            None => None,
+        };
+        let cells = prop.cells();
+        match cells_name {
+            Some(cells_name) => {
+                let mut i = 0;
+                while let Some(&phandle) = cells.get(i) {
+                    // A zero phandle is an empty entry, without specifier.
+                    if phandle == 0 {
+                        i += 1;
+                        continue;
+                    }
+                    refs.push(phandle);
+                    i += 1 + specifier_cells(phandle, cells_name);
+                }
+            }
+            // Lists of bare phandles.
+            None if name.starts_with("pinctrl-") || name == "memory-region" => {
+                refs.extend(cells.into_iter().filter(|phandle| *phandle != 0));
+            }
+            None => {}
+        }
+    }
+    refs
+}
+
+/// Removes the nodes at `paths`, and their descendants, from the subtree of `node` at `path`.
+fn remove_nodes(node: &mut FdtNode, path: &str, paths: &[String]) {
+    node.children.retain_mut(|child| {
+        let child_path = match path {
+            "/" => format!("/{}", child.name),
+            _ => format!("{path}/{}", child.name),
+        };
+        if paths.contains(&child_path) {
+            return false;
+        }
+        remove_nodes(child, &child_path, paths);
+        true
+    });
+}
+
+impl Fdt {
+    /// Removes the devices in `excluded_devices` from the device tree, to turn a host device
+    /// tree into the device tree of a VM that gets every other host device, and returns the
+    /// paths of the removed nodes.
+    ///
+    /// `excluded_devices` are groups of node paths, like
+    /// [`VMDevicesConfig::excluded_devices`](crate::VMDevicesConfig::excluded_devices). Excluded
+    /// nodes are removed with their descendants. Nodes that refer to a removed node by phandle,
+    /// e.g. through `clocks`, `interrupt-parent` or `pinctrl-0`, cannot work without it and are
+    /// removed too, repeatedly, so dependents of dependents go as well. The root node is never
+    /// removed; its `interrupt-parent` is dropped if it refers to a removed node. `/aliases`
+    /// entries and the `/chosen` `stdout-path` naming a removed node are dropped as well.
+    pub fn remove_devices(&mut self, excluded_devices: &[Vec<String>]) -> Vec<String> {
+        // The topmost excluded nodes which exist in the tree.
+        let mut removed: Vec<String> = Vec::new();
+        self.walk(|path, _, _| {
+            if path != "/"
+                && is_excluded(path, excluded_devices)
+                && !is_excluded(path, core::slice::from_ref(&removed))
+            {
+                removed.push(String::from(path));
+            }
+        });
+
+        let mut phandle_paths = Vec::new();
+        self.walk(|path, node, _| {
+            if let Some(phandle) = node.phandle() {
+                phandle_paths.push((phandle, String::from(path)));
+            }
+        });
+        let is_removed = |removed: &Vec<String>, phandle: u32| {
+            phandle_paths
+                .iter()
+                .find(|(p, _)| *p == phandle)
+                .is_some_and(|(_, path)| is_excluded(path, core::slice::from_ref(removed)))
+        };
+
+        loop {
+            let mut dependents = Vec::new();
+            self.walk(|path, node, ancestors| {
+                if path == "/" || is_excluded(path, core::slice::from_ref(&removed)) {
+                    return;
+                }
+                if phandle_references(self, node, ancestors)
+                    .into_iter()
+                    .any(|phandle| is_removed(&removed, phandle))
+                {
+                    dependents.push(String::from(path));
+                }
+            });
+            if dependents.is_empty() {
+                break;
+            }
+            removed.extend(dependents);
+        }
+
+        let root_parent_removed = self
+            .root
+            .prop_u32("interrupt-parent")
+            .is_some_and(|phandle| is_removed(&removed, phandle));
+        remove_nodes(&mut self.root, "/", &removed);
+        if root_parent_removed {
+            self.root
+                .properties
+                .retain(|p| p.name != "interrupt-parent");
+        }
+        let names_removed_node = |prop: &FdtProperty| {
+            prop.as_str().is_some_and(|value| {
+                // `stdout-path` may have options after a colon, e.g. `/serial@0:115200n8`.
+                let path = value.split(':').next().unwrap_or(value);
+                path.starts_with('/') && is_excluded(path, core::slice::from_ref(&removed))
+            })
+        };
+        for node in self.root.children.iter_mut() {
+            match node.name.as_str() {
+                "aliases" => node.properties.retain(|prop| !names_removed_node(prop)),
+                "chosen" => node
+                    .properties
+                    .retain(|prop| prop.name != "stdout-path" || !names_removed_node(prop)),
+                _ => {}
+            }
+        }
+        removed
+    }
+}
+
+/// The guest architecture a device tree is generated for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuestArch {
+    /// 64-bit ARM, with a GIC and PSCI.
+    Aarch64,
+    /// 64-bit RISC-V, with a PLIC and per-hart interrupt controllers.
+    Riscv64,
+}
+
+/// The timebase frequency of riscv64 guests, matching the QEMU `virt` machine.
+const RISCV_TIMEBASE_FREQUENCY: u32 = 10_000_000;
+/// The `riscv,ndev` of the generated PLIC if no device IRQ is larger, matching QEMU `virt`.
+const RISCV_PLIC_NDEV: u32 = 0x5f;
+
+/// The kind of the guest interrupt controller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum IntcKind {
+    GicV2,
+    GicV3,
+    Plic,
+}
+
+impl IntcKind {
+    /// The kind of a host interrupt controller usable by guests of `arch`, from its
+    /// `compatible`.
+    fn of_node(node: &FdtNode, arch: GuestArch) -> Option<Self> {
+        match arch {
+            GuestArch::Aarch64 if node.is_compatible_with("arm,gic-v3") => Some(Self::GicV3),
+            GuestArch::Aarch64
+                if node.is_compatible_with("arm,gic")
+                    || node.is_compatible_with("arm,cortex-a") =>
+            {
+                Some(Self::GicV2)
+            }
+            GuestArch::Riscv64
+                if node.is_compatible_with("riscv,plic")
+                    || node.is_compatible_with("sifive,plic") =>
+            {
+                Some(Self::Plic)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The host device tree node of each passthrough device group, with its path, if known.
+type HostNodes<'a> = [Option<(String, &'a FdtNode)>];
+
+/// The guest interrupt controller, and the passthrough device and host device tree node it was
+/// derived from, if any.
+struct GuestIntc<'a> {
+    kind: IntcKind,
+    regs: Vec<(usize, usize)>,
+    passthrough_key: Option<&'a str>,
+    host_node: Option<&'a FdtNode>,
+}
+
+/// Splits a 64-bit value into two cells.
+fn u64_cells(value: usize) -> [u32; 2] {
+    [((value as u64) >> 32) as u32, value as u32]
+}
+
+/// Returns the `reg` cells of the regions, with two address and two size cells each.
+fn reg_cells(regs: &[(usize, usize)]) -> Vec<u32> {
+    regs.iter()
+        .flat_map(|(base, size)| u64_cells(*base).into_iter().chain(u64_cells(*size)))
+        .collect()
+}
+
+/// Returns the device a passthrough entry belongs to: entries named `<name>#<index>`, as
+/// written by [`Fdt::passthrough_devices`] for the extra `reg` regions of a node, belong to
+/// `<name>`.
+fn device_key(name: &str) -> &str {
+    match name.rsplit_once('#') {
+        Some((key, index)) if index.parse::<usize>().is_ok() => key,
+        _ => name,
+    }
+}
+
+/// Derives a node name (without unit address) from a device name: the last component of a node
+/// path, without its unit address, restricted to the characters allowed in node names.
+fn node_base_name(name: &str) -> String {
+    let name = name.rsplit('/').next().unwrap_or(name);
+    let name = name.split('@').next().unwrap_or(name);
+    let base: String = name
+        .chars()
+        .map(|c| c.to_ascii_lowercase())
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || ",._+-".contains(c) {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    match base.is_empty() {
+        true => String::from("device"),
+        false => base,
+    }
+}
+
+/// Groups passthrough regions by device, keeping the order of first appearance.
+fn passthrough_groups(
+    devices: &[PassThroughDeviceConfig],
+) -> Vec<(&str, Vec<&PassThroughDeviceConfig>)> {
+    let mut groups: Vec<(&str, Vec<&PassThroughDeviceConfig>)> = Vec::new();
+    for dev in devices.iter().filter(|dev| dev.length > 0) {
+        let key = device_key(&dev.name);
+        match groups.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, regions)) => regions.push(dev),
+            None => groups.push((key, alloc::vec![dev])),
+        }
+    }
+    groups
+}
+
+impl GuestIntc<'_> {
+    /// Finds the interrupt controller of the guest: an emulated interrupt controller, or a
+    /// passthrough device. With the host device tree, the passthrough device is the interrupt
+    /// parent of the root or else an `interrupt-controller` node of a known kind; otherwise it
+    /// is a device whose name says it is one (`gic`/`intc` on aarch64, `plic` on riscv64).
+    fn find<'a>(
+        config: &'a AxVMCrateConfig,
+        arch: GuestArch,
+        groups: &[(&'a str, Vec<&'a PassThroughDeviceConfig>)],
+        hosts: &HostNodes<'a>,
+        host: Option<&'a Fdt>,
+    ) -> Option<GuestIntc<'a>> {
+        let emu = &config.devices.emu_devices;
+        let emu_regs = |ty: EmulatedDeviceType| -> Vec<(usize, usize)> {
+            emu.iter()
+                .filter(|dev| dev.emu_type == ty)
+                .map(|dev| (dev.base_gpa, dev.length))
+                .collect()
+        };
+        let (emu_kind, names): (_, &[&str]) = match arch {
+            GuestArch::Aarch64 => (IntcKind::GicV2, &["gic", "intc"]),
+            GuestArch::Riscv64 => (IntcKind::Plic, &["plic"]),
+        };
+
+        let distributor = emu_regs(EmulatedDeviceType::GPPTDistributor);
+        if arch == GuestArch::Aarch64 && !distributor.is_empty() {
+            let mut regs = distributor;
+            regs.extend(emu_regs(EmulatedDeviceType::GPPTRedistributor));
+            return Some(GuestIntc {
+                kind: IntcKind::GicV3,
+                regs,
+                passthrough_key: None,
+                host_node: None,
+            });
+        }
+        let regs = emu_regs(EmulatedDeviceType::InterruptController);
+        if !regs.is_empty() {
+            return GuestIntc {
+                kind: emu_kind,
+                regs,
+                passthrough_key: None,
+                host_node: None,
+            }
+            .split_gicv2_regs();
+        }
+
+        let root_parent = host
+            .and_then(|host| host.find_phandle(host.root.prop_u32("interrupt-parent")?))
+            .map(|(path, _)| path);
+        let host_node = |index: usize| hosts.get(index)?.as_ref().map(|(_, node)| *node);
+        let by_host = root_parent
+            .and_then(|parent| {
+                hosts
+                    .iter()
+                    .position(|node| node.as_ref().is_some_and(|(path, _)| *path == parent))
+            })
+            .or_else(|| {
+                hosts.iter().position(|node| {
+                    node.as_ref().is_some_and(|(_, node)| {
+                        node.property("interrupt-controller").is_some()
+                            && IntcKind::of_node(node, arch).is_some()
+                    })
+                })
+            });
+        let (index, kind) = match by_host {
+            Some(index) => {
+                let kind = host_node(index).and_then(|node| IntcKind::of_node(node, arch));
+                (index, kind.unwrap_or(emu_kind))
+            }
+            None => {
+                let index = groups.iter().position(|(key, _)| {
+                    let key = key.to_ascii_lowercase();
+                    names.iter().any(|name| key.contains(name))
+                })?;
+                let kind = match emu_kind {
+                    IntcKind::GicV2 if groups[index].0.to_ascii_lowercase().contains("v3") => {
+                        IntcKind::GicV3
+                    }
+                    kind => kind,
+                };
+                (index, kind)
+            }
+        };
+        let (key, regions) = &groups[index];
+        GuestIntc {
+            kind,
+            regs: regions
+                .iter()
+                .map(|dev| (dev.base_gpa, dev.length))
+                .collect(),
+            passthrough_key: Some(key),
+            host_node: host_node(index),
+        }
+        .split_gicv2_regs()
+    }
+
+    /// Makes sure a GICv2 has both its distributor and CPU interface ranges. A single region
+    /// large enough for both is split like on the QEMU `virt` machine, i.e. the CPU interface
+    /// follows the 64K distributor; a smaller single region cannot describe a GICv2, so there is
+    /// no interrupt controller.
+    fn split_gicv2_regs(mut self) -> Option<Self> {
+        if self.kind != IntcKind::GicV2 || self.regs.len() != 1 {
+            return Some(self);
+        }
+        let (base, length) = self.regs[0];
+        if length < 2 * GICV2_FRAME_SIZE {
+            warn!(
+                "GICv2 region {base:#x}+{length:#x} is too small for the distributor and the CPU \
+                 interface, interrupts are not described"
+            );
+            return None;
+        }
+        self.regs = alloc::vec![
+            (base, GICV2_FRAME_SIZE),
+            (base + GICV2_FRAME_SIZE, GICV2_FRAME_SIZE),
+        ];
+        Some(self)
+    }
+
+    /// Returns the interrupt specifier of an interrupt, or `None` if the controller cannot
+    /// route it to a device (GIC SGIs).
+    fn specifier(&self, irq: usize) -> Option<Vec<u32>> {
+        const IRQ_TYPE_LEVEL_HIGH: u32 = 4;
+        match self.kind {
+            IntcKind::GicV2 | IntcKind::GicV3 if irq >= GIC_SPI_BASE => Some(alloc::vec![
+                0,
+                (irq - GIC_SPI_BASE) as u32,
+                IRQ_TYPE_LEVEL_HIGH
+            ]),
+            IntcKind::GicV2 | IntcKind::GicV3 if irq >= GIC_PPI_BASE => Some(alloc::vec![
+                1,
+                (irq - GIC_PPI_BASE) as u32,
+                IRQ_TYPE_LEVEL_HIGH
+            ]),
+            IntcKind::GicV2 | IntcKind::GicV3 => None,
+            IntcKind::Plic => Some(alloc::vec![irq as u32]),
+        }
+    }
+}
+
+impl AxVMCrateConfig {
+    /// Generates the device tree of the guest from the configuration.
+    ///
+    /// The tree has a node for every vCPU (with `reg` from `base.phys_cpu_ids`, or the vCPU id),
+    /// every guest RAM region, every passthrough device and every virtio emulated device, plus
+    /// `/chosen` with the kernel command line and the ramdisk. The ramdisk is only described if
+    /// `ramdisk_size` is given, since the image size is not part of the configuration.
+    ///
+    /// `host` is the device tree of the host the passthrough devices are taken from, if known.
+    /// Each passthrough device is matched to the host node at the path it is named after, or
+    /// else to the node at its host address, and gets the `compatible` of that node; without a
+    /// match it has no `compatible`, since the configuration does not know it.
+    ///
+    /// Interrupts are only described if the guest has an interrupt controller: an emulated
+    /// interrupt controller or GPPT distributor, or a passthrough device. The passthrough
+    /// interrupt controller is the host node that is the `interrupt-parent` of the root or else
+    /// has the `interrupt-controller` property, or without a host device tree, a device whose
+    /// name contains `gic`/`intc` (aarch64) or `plic` (riscv64). A GICv2 given as a single
+    /// region is split into the distributor and CPU interface ranges of the QEMU `virt`
+    /// machine, and left out if the region is too small for both.
+    pub fn guest_fdt(
+        &self,
+        arch: GuestArch,
+        ramdisk_size: Option<usize>,
+        host: Option<&Fdt>,
+    ) -> Fdt {
+        let mut next_phandle = 1;
+        let mut alloc_phandle = || {
+            next_phandle += 1;
+            next_phandle - 1
+        };
+
+        let mut root = FdtNode::new("");
+        root.set_cells("#address-cells", &[2])
+            .set_cells("#size-cells", &[2])
+            .set_strs(
+                "compatible",
+                &[match arch {
+                    GuestArch::Aarch64 => "linux,dummy-virt",
+                    GuestArch::Riscv64 => "riscv-virtio",
+                }],
+            )
+            .set_strs("model", &[&self.base.name]);
+
+        // vCPUs
+        let phys_ids: Vec<usize> = (0..self.base.cpu_num)
+            .map(|id| {
+                self.base
+                    .phys_cpu_ids
+                    .as_ref()
+                    .and_then(|ids| ids.get(id).copied())
+                    .unwrap_or(id)
+            })
+            .collect();
+        let mut cpus = FdtNode::new("cpus");
+        cpus.set_cells("#address-cells", &[1])
+            .set_cells("#size-cells", &[0]);
+        if arch == GuestArch::Riscv64 {
+            cpus.set_cells("timebase-frequency", &[RISCV_TIMEBASE_FREQUENCY]);
+        }
+        let mut cpu_intc_phandles = Vec::new();
+        for phys_id in &phys_ids {
+            let mut cpu = FdtNode::new(&format!("cpu@{phys_id:x}"));
+            cpu.set_strs("device_type", &["cpu"])
+                .set_cells("reg", &[*phys_id as u32]);
+            match arch {
+                GuestArch::Aarch64 => {
+                    cpu.set_strs("compatible", &["arm,armv8"])
+                        .set_strs("enable-method", &["psci"]);
+                }
+                GuestArch::Riscv64 => {
+                    cpu.set_strs("status", &["okay"])
+                        .set_strs("compatible", &["riscv"])
+                        .set_strs("riscv,isa", &["rv64imafdc"])
+                        .set_strs("mmu-type", &["riscv,sv39"]);
+                    let phandle = alloc_phandle();
+                    let mut intc = FdtNode::new("interrupt-controller");
+                    intc.set_cells("#interrupt-cells", &[1])
+                        .set_property(FdtProperty::empty("interrupt-controller"))
+                        .set_strs("compatible", &["riscv,cpu-intc"])
+                        .set_cells("phandle", &[phandle]);
+                    cpu.children.push(intc);
+                    cpu_intc_phandles.push(phandle);
+                }
+            }
+            cpus.children.push(cpu);
+        }
+
+        // Kernel command line and ramdisk
+        let mut chosen = FdtNode::new("chosen");
+        if let Some(cmdline) = &self.kernel.cmdline {
+            chosen.set_strs("bootargs", &[cmdline]);
+        }
+        if let (Some(_), Some(start), Some(size)) = (
+            &self.kernel.ramdisk_path,
+            self.kernel.ramdisk_load_addr,
+            ramdisk_size,
+        ) {
+            chosen
+                .set_cells("linux,initrd-start", &u64_cells(start))
+                .set_cells("linux,initrd-end", &u64_cells(start.saturating_add(size)));
+        }
+
+        root.children.push(chosen);
+        root.children.push(cpus);
+        if arch == GuestArch::Aarch64 {
+            let mut psci = FdtNode::new("psci");
+            psci.set_strs("compatible", &["arm,psci-1.0", "arm,psci-0.2", "arm,psci"])
+                .set_strs("method", &["hvc"]);
+            root.children.push(psci);
+        }
+
+        // Guest RAM
+        for region in &self.kernel.memory_regions {
+            if region.flags.contains(VmMemFlags::DEVICE) || region.size == 0 {
+                continue;
+            }
+            let mut memory = FdtNode::new(&format!("memory@{:x}", region.gpa));
+            memory
+                .set_strs("device_type", &["memory"])
+                .set_cells("reg", &reg_cells(&[(region.gpa, region.size)]));
+            root.children.push(memory);
+        }
+
+        // Interrupt controller
+        let groups = passthrough_groups(&self.devices.passthrough_devices);
+        let hosts: Vec<_> = groups
+            .iter()
+            .map(|(key, regions)| host.and_then(|host| host_node(host, key, regions[0].base_hpa)))
+            .collect();
+        let intc = GuestIntc::find(self, arch, &groups, &hosts, host);
+        if let Some(intc) = &intc {
+            let phandle = alloc_phandle();
+            let base = intc.regs.first().map_or(0, |(base, _)| *base);
+            let mut node = match intc.kind {
+                IntcKind::GicV2 => {
+                    let mut node = FdtNode::new(&format!("intc@{base:x}"));
+                    node.set_strs("compatible", &["arm,cortex-a15-gic"]);
+                    node
+                }
+                IntcKind::GicV3 => {
+                    let mut node = FdtNode::new(&format!("interrupt-controller@{base:x}"));
+                    node.set_strs("compatible", &["arm,gic-v3"]);
+                    node
+                }
+                IntcKind::Plic => {
+                    let mut node = FdtNode::new(&format!("plic@{base:x}"));
+                    let ndev = self
+                        .devices
+                        .passthrough_devices
+                        .iter()
+                        .map(|dev| dev.irq_id)
+                        .chain(self.devices.emu_devices.iter().map(|dev| dev.irq_id))
+                        .max()
+                        .map_or(RISCV_PLIC_NDEV, |max| RISCV_PLIC_NDEV.max(max as u32));
+                    let targets: Vec<u32> = cpu_intc_phandles
+                        .iter()
+                        .flat_map(|phandle| [*phandle, 11, *phandle, 9])
+                        .collect();
+                    node.set_strs("compatible", &["sifive,plic-1.0.0", "riscv,plic0"])
+                        .set_cells("#address-cells", &[0])
+                        .set_cells("riscv,ndev", &[ndev])
+                        .set_cells("interrupts-extended", &targets);
+                    node
+                }
+            };
+            let interrupt_cells = match intc.kind {
+                IntcKind::Plic => 1,
+                _ => 3,
+            };
+            if let Some(compatible) = intc.host_node.and_then(|node| node.property("compatible")) {
+                node.set_property(compatible.clone());
+            }
+            node.set_cells("#interrupt-cells", &[interrupt_cells])
+                .set_property(FdtProperty::empty("interrupt-controller"))
+                .set_cells("reg", &reg_cells(&intc.regs))
+                .set_cells("phandle", &[phandle]);
+            root.children.push(node);
+            root.set_cells("interrupt-parent", &[phandle]);
+
+            if arch == GuestArch::Aarch64 {
+                // Secure, non-secure, virtual and hypervisor timer PPIs.
+                let cpu_mask = match intc.kind {
+                    IntcKind::GicV2 => ((1u32 << self.base.cpu_num.min(8)) - 1) << 8,
+                    _ => 0,
+                };
+                let interrupts: Vec<u32> = [13, 14, 11, 10]
+                    .into_iter()
+                    .flat_map(|ppi| [1, ppi, cpu_mask | 4])
+                    .collect();
+                let mut timer = FdtNode::new("timer");
+                timer
+                    .set_strs("compatible", &["arm,armv8-timer"])
+                    .set_cells("interrupts", &interrupts);
+                root.children.push(timer);
+            }
+        }
+        let interrupts = |irq: usize| {
+            intc.as_ref()
+                .filter(|_| irq != 0)
+                .and_then(|intc| intc.specifier(irq))
+        };
+
+        // Passthrough devices
+        let mut stdout_path = None;
+        for ((key, regions), host_node) in groups.iter().zip(&hosts) {
+            if intc.as_ref().and_then(|intc| intc.passthrough_key) == Some(*key) {
+                continue;
+            }
+            let regs: Vec<(usize, usize)> = regions
+                .iter()
+                .map(|dev| (dev.base_gpa, dev.length))
+                .collect();
+            let name = format!("{}@{:x}", node_base_name(key), regs[0].0);
+            let mut node = FdtNode::new(&name);
+            if let Some((_, host_node)) = host_node {
+                if let Some(compatible) = host_node.property("compatible") {
+                    node.set_property(compatible.clone());
+                }
+            }
+            node.set_cells("reg", &reg_cells(&regs));
+            if let Some(spec) = interrupts(regions[0].irq_id) {
+                node.set_cells("interrupts", &spec);
+            }
+            let lower = key.to_ascii_lowercase();
+            if stdout_path.is_none()
+                && ["uart", "serial", "pl011"]
+                    .iter()
+                    .any(|s| lower.contains(s))
+            {
+                stdout_path = Some(format!("/{name}"));
+            }
+            root.children.push(node);
+        }
+
+        // Virtio devices
+        for dev in &self.devices.emu_devices {
+            if !matches!(
+                dev.emu_type,
+                EmulatedDeviceType::VirtioBlk
+                    | EmulatedDeviceType::VirtioNet
+                    | EmulatedDeviceType::VirtioConsole
+            ) {
+                continue;
+            }
+            let mut node = FdtNode::new(&format!("virtio_mmio@{:x}", dev.base_gpa));
+            node.set_strs("compatible", &["virtio,mmio"])
+                .set_cells("reg", &reg_cells(&[(dev.base_gpa, dev.length)]))
+                .set_property(FdtProperty::empty("dma-coherent"));
+            if let Some(spec) = interrupts(dev.irq_id) {
+                node.set_cells("interrupts", &spec);
+            }
+            root.children.push(node);
+        }
+
+        if let Some(stdout_path) = stdout_path {
+            if let Some(chosen) = root.children.iter_mut().find(|n| n.name == "chosen") {
+                chosen.set_strs("stdout-path", &[&stdout_path]);
+            }
+        }
+
+        Fdt {
+            boot_cpuid_phys: phys_ids.first().copied().unwrap_or(0) as u32,
+            mem_reserve: Vec::new(),
+            root,
+        }
+    }
+}
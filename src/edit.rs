@@ -0,0 +1,427 @@
+//! Reading and changing single values of configuration files by key path.
+//!
+//! Scripts patching configuration files with `sed` break as soon as the layout of a file
+//! changes. [`get_config_value`] and [`set_config_value`] address a value by its key path
+//! instead, e.g. `devices.passthrough_devices[name=uart].irq_id`, and edit the file with
+//! `toml_edit` like [`format_toml`](crate::format_toml), so the rest of the file, comments
+//! included, stays as written.
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt::{Display, Formatter};
+
+use toml_edit::{Array, ArrayOfTables, DocumentMut, InlineTable, Item, Table, Value};
+
+use crate::formatter::{bare, has_parents, is_tuple_array};
+use crate::writer::key_order;
+use crate::{parse_size, AxVMCrateConfig, ConfigParseError, ConfigParseErrorKind};
+
+/// Error returned when a value of a configuration file cannot be read or changed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigEditError {
+    /// The file is not valid TOML.
+    Parse(ConfigParseError),
+    /// The key path is malformed, or does not lead to something that can be set.
+    BadPath(String),
+    /// There is no value at the key path.
+    NotFound(String),
+    /// The file would not be a valid configuration with the new value.
+    Invalid(ConfigParseError),
+}
+
+impl Display for ConfigEditError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ConfigEditError::Parse(err) => write!(f, "{err}"),
+            ConfigEditError::BadPath(message) => write!(f, "invalid key path: {message}"),
+            ConfigEditError::NotFound(path) => write!(f, "no value at `{path}`"),
+            ConfigEditError::Invalid(err) => {
+                write!(f, "the new value makes the configuration invalid: {err}")
+            }
+        }
+    }
+}
+
+impl core::error::Error for ConfigEditError {}
+
+/// A step of a key path.
+#[derive(Debug)]
+enum Step {
+    /// A key of a table, or a field of a tuple.
+    Key(String),
+    /// An element of an array, by index.
+    Index(usize),
+    /// The first element of an array whose field has the given value, e.g. `[name=uart]`.
+    Select(String, String),
+}
+
+/// Parses a key path: keys separated by `.`, each followed by any number of `[index]` or
+/// `[field=value]` selectors. Values containing `.`, `]` or spaces are written as TOML strings,
+/// e.g. `[name="intc.gic"]`.
+fn parse_path(path: &str) -> Result<Vec<Step>, ConfigEditError> {
+    let bad = |message: &str| ConfigEditError::BadPath(format!("{message} in `{path}`"));
+    let mut steps = Vec::new();
+    let mut rest = path;
+    loop {
+        let end = rest.find(['.', '[', ']']).unwrap_or(rest.len());
+        if end == 0 {
+            return Err(bad("expected a key"));
+        }
+        steps.push(Step::Key(rest[..end].to_string()));
+        rest = &rest[end..];
+        while let Some(selector) = rest.strip_prefix('[') {
+            let (step, after) = parse_selector(selector).map_err(bad)?;
+            steps.push(step);
+            rest = after;
+        }
+        match rest.strip_prefix('.') {
+            Some(after) => rest = after,
+            None if rest.is_empty() => return Ok(steps),
+            None => return Err(bad("expected `.` or `[`")),
+        }
+    }
+}
+
+/// Parses a selector after its `[`, returning the step and the rest of the path after its `]`.
+fn parse_selector(selector: &str) -> Result<(Step, &str), &'static str> {
+    if selector.starts_with(|c: char| c.is_ascii_digit()) {
+        let (index, rest) = selector.split_once(']').ok_or("expected `]`")?;
+        let index = index.parse().map_err(|_| "expected an index")?;
+        return Ok((Step::Index(index), rest));
+    }
+
+    let (field, value) = selector
+        .split_once('=')
+        .ok_or("expected `=` in a selector")?;
+    if field.is_empty() || field.contains(']') {
+        return Err("expected a field name in a selector");
+    }
+    let (value, rest) = if value.starts_with('"') {
+        // Find the closing quote, skipping escaped ones
+        let mut escaped = false;
+        let end = value
+            .char_indices()
+            .skip(1)
+            .find(|&(_, c)| {
+                let end = c == '"' && !escaped;
+                escaped = c == '\\' && !escaped;
+                end
+            })
+            .map(|(i, _)| i + 1)
+            .ok_or("unterminated string in a selector")?;
+        let quoted = value[..end]
+            .parse::<Value>()
+            .ok()
+            .and_then(|quoted| quoted.as_str().map(str::to_string))
+            .ok_or("invalid string in a selector")?;
+        let rest = value[end..]
+            .strip_prefix(']')
+            .ok_or("expected `]` after a string")?;
+        (quoted, rest)
+    } else {
+        let (value, rest) = value.split_once(']').ok_or("expected `]`")?;
+        (value.to_string(), rest)
+    };
+    Ok((Step::Select(field.to_string(), value), rest))
+}
+
+/// What a key path leads to.
+enum Node<'a> {
+    Item(&'a mut Item),
+    Table(&'a mut Table),
+    Value(&'a mut Value),
+}
+
+/// Something with keys or elements.
+enum Container<'a> {
+    Table(&'a mut Table),
+    InlineTable(&'a mut InlineTable),
+    Array(&'a mut Array),
+    Tables(&'a mut ArrayOfTables),
+}
+
+impl<'a> Node<'a> {
+    fn into_container(self) -> Option<Container<'a>> {
+        let value = match self {
+            Node::Item(Item::Table(table)) | Node::Table(table) => {
+                return Some(Container::Table(table))
+            }
+            Node::Item(Item::ArrayOfTables(tables)) => return Some(Container::Tables(tables)),
+            Node::Item(Item::Value(value)) | Node::Value(value) => value,
+            Node::Item(Item::None) => return None,
+        };
+        match value {
+            Value::InlineTable(table) => Some(Container::InlineTable(table)),
+            Value::Array(array) => Some(Container::Array(array)),
+            _ => None,
+        }
+    }
+
+    /// The text of the value: strings without quotes, other values as written in TOML.
+    fn text(&self) -> String {
+        let value = match self {
+            Node::Item(Item::Value(value)) => value,
+            Node::Value(value) => value,
+            Node::Item(item) => return item.to_string().trim().to_string(),
+            Node::Table(table) => return table.to_string().trim().to_string(),
+        };
+        match value.as_str() {
+            Some(s) => s.to_string(),
+            None => bare(value),
+        }
+    }
+}
+
+/// Where a key path leads in the document, with what is needed to interpret the next step.
+struct Cursor<'a> {
+    node: Node<'a>,
+    /// The key path so far, for error messages.
+    path: String,
+    /// The key path so far without the selectors, e.g. `devices.passthrough_devices`.
+    keys: String,
+    /// Whether the node is an element of an array, whose fields are the keys of the array.
+    element: bool,
+}
+
+impl<'a> Cursor<'a> {
+    /// The names of the fields of the node if it is an element of a tuple array.
+    fn fields(&self) -> &'static [&'static str] {
+        if self.element && is_tuple_array(&self.keys) {
+            key_order(&self.keys)
+        } else {
+            &[]
+        }
+    }
+
+    /// Follows a step of a key path.
+    fn step(self, step: &Step) -> Result<Cursor<'a>, ConfigEditError> {
+        let fields = self.fields();
+        let (path, keys) = step_path(&self.path, &self.keys, step);
+        let not_found = || ConfigEditError::NotFound(path.clone());
+        let container = self.node.into_container().ok_or_else(not_found)?;
+        let node = match (step, container) {
+            (Step::Key(key), Container::Table(table)) => table.get_mut(key).map(Node::Item),
+            (Step::Key(key), Container::InlineTable(table)) => table.get_mut(key).map(Node::Value),
+            (Step::Key(key), Container::Array(array)) => fields
+                .iter()
+                .position(|field| field == key)
+                .and_then(|i| array.get_mut(i))
+                .map(Node::Value),
+            (Step::Index(i), Container::Array(array)) => array.get_mut(*i).map(Node::Value),
+            (Step::Index(i), Container::Tables(tables)) => tables.get_mut(*i).map(Node::Table),
+            (Step::Select(field, value), Container::Array(array)) => {
+                let fields = key_order(&self.keys);
+                let i = array
+                    .iter()
+                    .position(|element| element_field(element, fields, field, value));
+                i.and_then(|i| array.get_mut(i)).map(Node::Value)
+            }
+            (Step::Select(field, value), Container::Tables(tables)) => {
+                let i = tables.iter().position(|table| {
+                    table
+                        .get(field)
+                        .and_then(Item::as_value)
+                        .is_some_and(|v| matches(v, value))
+                });
+                i.and_then(|i| tables.get_mut(i)).map(Node::Table)
+            }
+            _ => None,
+        };
+        Ok(Cursor {
+            node: node.ok_or_else(not_found)?,
+            path,
+            keys,
+            element: !matches!(step, Step::Key(_)),
+        })
+    }
+
+    /// Sets the value at a last step of a key path, adding a key of a table if it is a known
+    /// field of the configuration and missing.
+    fn set(self, step: &Step, text: &str) -> Result<(), ConfigEditError> {
+        let fields = self.fields();
+        let known = key_order(&self.keys);
+        let (path, _) = step_path(&self.path, &self.keys, step);
+        let not_found = || ConfigEditError::NotFound(path.clone());
+        let container = self.node.into_container().ok_or_else(not_found)?;
+        match (step, container) {
+            (Step::Key(key), Container::Table(table)) => match table.get_mut(key) {
+                Some(Item::Value(old)) => replace(old, text),
+                Some(item @ (Item::Table(_) | Item::ArrayOfTables(_))) => {
+                    *item = Item::Value(parse_value(text, None))
+                }
+                _ if known.contains(&key.as_str()) => {
+                    table.insert(key, Item::Value(parse_value(text, None)));
+                }
+                _ => return Err(not_found()),
+            },
+            (Step::Key(key), Container::InlineTable(table)) => match table.get_mut(key) {
+                Some(old) => replace(old, text),
+                None if known.contains(&key.as_str()) => {
+                    table.insert(key, parse_value(text, None));
+                }
+                None => return Err(not_found()),
+            },
+            (Step::Key(key), Container::Array(array)) => {
+                let i = fields
+                    .iter()
+                    .position(|field| field == key)
+                    .ok_or_else(not_found)?;
+                // A missing optional field can be added after the last one
+                if i == array.len() {
+                    array.push(parse_value(text, None));
+                } else {
+                    replace(array.get_mut(i).ok_or_else(not_found)?, text);
+                }
+            }
+            (Step::Index(_) | Step::Select(..), Container::Array(array)) => {
+                let i = match step {
+                    Step::Index(i) => Some(*i).filter(|&i| i < array.len()),
+                    Step::Select(field, value) => array
+                        .iter()
+                        .position(|element| element_field(element, known, field, value)),
+                    Step::Key(_) => None,
+                };
+                let old = i.and_then(|i| array.get_mut(i)).ok_or_else(not_found)?;
+                replace(old, text);
+            }
+            (Step::Index(_) | Step::Select(..), Container::Tables(_)) => {
+                return Err(ConfigEditError::BadPath(format!(
+                    "`{path}` is a table, set its keys one by one"
+                )));
+            }
+            _ => return Err(not_found()),
+        }
+        Ok(())
+    }
+}
+
+/// The key path, and the key path without selectors, after a step.
+fn step_path(path: &str, keys: &str, step: &Step) -> (String, String) {
+    match step {
+        Step::Key(key) if path.is_empty() => (key.clone(), key.clone()),
+        Step::Key(key) => (format!("{path}.{key}"), format!("{keys}.{key}")),
+        Step::Index(i) => (format!("{path}[{i}]"), keys.to_string()),
+        Step::Select(field, value) => (format!("{path}[{field}={value}]"), keys.to_string()),
+    }
+}
+
+/// Whether the field `field` of an array element, a tuple with the fields `fields` or an inline
+/// table, matches `expected`.
+fn element_field(element: &Value, fields: &[&str], field: &str, expected: &str) -> bool {
+    let value = match element {
+        Value::Array(tuple) => fields
+            .iter()
+            .position(|f| *f == field)
+            .and_then(|i| tuple.get(i)),
+        Value::InlineTable(table) => table.get(field),
+        _ => None,
+    };
+    value.is_some_and(|value| matches(value, expected))
+}
+
+/// Whether a value matches the value of a selector. Numbers and sizes match by value, so
+/// `0x4000_0000`, `1073741824` and `"1G"` all match each other.
+fn matches(value: &Value, expected: &str) -> bool {
+    let size = match value {
+        Value::String(s) if s.value() == expected => return true,
+        Value::String(s) => parse_size(s.value()).ok(),
+        Value::Integer(n) => usize::try_from(*n.value()).ok(),
+        _ => return bare(value) == expected,
+    };
+    size.is_some() && parse_size(expected).ok() == size
+}
+
+/// Parses the text of a new value as TOML, e.g. `0x1000`, `["read", "write"]` or `"text"`.
+/// Text that is not valid TOML, or that would replace a string with another type, is taken as
+/// a string, so `console=ttyS0` or `1` can be given for a string without quotes.
+fn parse_value(text: &str, old: Option<&Value>) -> Value {
+    match text.parse::<Value>() {
+        Ok(mut value) if value.is_str() || !old.is_some_and(Value::is_str) => {
+            value.decor_mut().clear();
+            value
+        }
+        _ => Value::from(text),
+    }
+}
+
+/// Replaces a value, keeping the whitespace and comments around it.
+fn replace(old: &mut Value, text: &str) {
+    let mut value = parse_value(text, Some(old));
+    *value.decor_mut() = old.decor().clone();
+    *old = value;
+}
+
+/// Parses a document, reporting syntax errors like [`AxVMCrateConfig::from_toml_with_upgrade`].
+fn parse_document(source: &str) -> Result<DocumentMut, ConfigEditError> {
+    source.parse().map_err(|err: toml_edit::TomlError| {
+        ConfigEditError::Parse(ConfigParseError::new(
+            ConfigParseErrorKind::Syntax,
+            err.message(),
+            source,
+            None,
+            err.span(),
+        ))
+    })
+}
+
+/// Returns the value at `path` in a configuration file.
+///
+/// The key path is made of keys separated by `.`. An element of an array is selected by its
+/// index, e.g. `kernel.memory_regions[0]`, or by the value of one of its fields, e.g.
+/// `devices.passthrough_devices[name=uart]`; the first matching element is used. Selector
+/// values containing `.`, `]` or spaces are written as TOML strings, e.g. `[name="intc.gic"]`,
+/// and addresses and sizes match by value, e.g. `[gpa=0x4000_0000]`. The fields of memory
+/// regions and devices are addressed by name whether they are written as tuples or tables,
+/// e.g. `devices.passthrough_devices[name=uart].irq_id`.
+///
+/// Strings are returned without quotes, other values as written in the file without the
+/// comments around them, and tables as TOML.
+pub fn get_config_value(source: &str, path: &str) -> Result<String, ConfigEditError> {
+    let steps = parse_path(path)?;
+    let mut doc = parse_document(source)?;
+    let mut cursor = Cursor {
+        node: Node::Table(doc.as_table_mut()),
+        path: String::new(),
+        keys: String::new(),
+        element: false,
+    };
+    for step in &steps {
+        cursor = cursor.step(step)?;
+    }
+    Ok(cursor.node.text())
+}
+
+/// Sets the value at `path` in a configuration file, keeping the rest of the file as written,
+/// and returns the new file.
+///
+/// The key path is written as for [`get_config_value`]. A key missing from a table is added if
+/// it is a field of the configuration, and a missing optional field of a tuple if it follows the
+/// last one. The value is parsed as TOML, e.g. `0x1000`, `["read", "write"]` or `"text"`; text
+/// that is not valid TOML, or that would replace a string with another type, is taken as a
+/// string, so `console=ttyS0 root=/dev/vda` can be given without quotes.
+///
+/// Fails with [`ConfigEditError::Invalid`] if the configuration would not match the schema with
+/// the new value; cross-field rules are not checked, see [`AxVMCrateConfig::validate`]. A file
+/// with `extends` or `include` is not checked, as it only gives part of a configuration; check
+/// it merged with [`resolve_config`](crate::resolve_config).
+pub fn set_config_value(source: &str, path: &str, value: &str) -> Result<String, ConfigEditError> {
+    let mut steps = parse_path(path)?;
+    let last = steps.pop().expect("a key path has at least one key");
+    let mut doc = parse_document(source)?;
+    let mut cursor = Cursor {
+        node: Node::Table(doc.as_table_mut()),
+        path: String::new(),
+        keys: String::new(),
+        element: false,
+    };
+    for step in &steps {
+        cursor = cursor.step(step)?;
+    }
+    cursor.set(&last, value)?;
+
+    let edited = doc.to_string();
+    if !has_parents(&doc) {
+        AxVMCrateConfig::from_toml_with_upgrade(&edited).map_err(ConfigEditError::Invalid)?;
+    }
+    Ok(edited)
+}
@@ -0,0 +1,542 @@
+//! A minimal flattened device tree (DTB) parser and writer.
+//!
+//! The whole blob is parsed into an owned tree of [`FdtNode`]s, which is small enough for the
+//! device trees of real boards and keeps lookups and edits simple. Only structure version 17
+//! (and 16, which is layout-compatible) is supported, and blobs are written as version 17.
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt::{Display, Formatter};
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+const FDT_END: u32 = 0x9;
+
+/// Error returned when parsing a malformed device tree blob.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FdtError {
+    /// The blob does not start with the FDT magic number.
+    BadMagic(u32),
+    /// The blob uses a structure version that is not supported.
+    UnsupportedVersion(u32),
+    /// The blob ends before the data described by its header.
+    Truncated,
+    /// An unknown or misplaced token was found in the structure block.
+    BadToken {
+        /// The offset of the token in the blob.
+        offset: usize,
+        /// The token value.
+        token: u32,
+    },
+    /// A node or property name is not a valid NUL-terminated UTF-8 string.
+    BadString(usize),
+}
+
+impl Display for FdtError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            FdtError::BadMagic(magic) => write!(f, "bad magic {magic:#x}, not a device tree blob"),
+            FdtError::UnsupportedVersion(version) => {
+                write!(f, "unsupported device tree version {version}")
+            }
+            FdtError::Truncated => write!(f, "device tree blob is truncated"),
+            FdtError::BadToken { offset, token } => {
+                write!(f, "unexpected token {token:#x} at offset {offset:#x}")
+            }
+            FdtError::BadString(offset) => write!(f, "invalid string at offset {offset:#x}"),
+        }
+    }
+}
+
+impl core::error::Error for FdtError {}
+
+/// A property of a device tree node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FdtProperty {
+    /// The property name, e.g. `reg`.
+    pub name: String,
+    /// The raw property value.
+    pub value: Vec<u8>,
+}
+
+impl FdtProperty {
+    /// Creates a property without a value, e.g. `interrupt-controller`.
+    pub fn empty(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            value: Vec::new(),
+        }
+    }
+
+    /// Creates a property holding big-endian 32-bit cells.
+    pub fn from_cells(name: &str, cells: &[u32]) -> Self {
+        Self {
+            name: name.to_string(),
+            value: cells.iter().flat_map(|cell| cell.to_be_bytes()).collect(),
+        }
+    }
+
+    /// Creates a property holding a list of NUL-terminated strings.
+    pub fn from_strs(name: &str, strings: &[&str]) -> Self {
+        let mut value = Vec::new();
+        for s in strings {
+            value.extend_from_slice(s.as_bytes());
+            value.push(0);
+        }
+        Self {
+            name: name.to_string(),
+            value,
+        }
+    }
+
+    /// Returns the value as big-endian 32-bit cells. Trailing bytes that do not form a full
+    /// cell are ignored.
+    pub fn cells(&self) -> Vec<u32> {
+        self.value
+            .chunks_exact(4)
+            .map(|cell| u32::from_be_bytes([cell[0], cell[1], cell[2], cell[3]]))
+            .collect()
+    }
+
+    /// Returns the value as a single 32-bit cell, or `None` if it is not 4 bytes long.
+    pub fn as_u32(&self) -> Option<u32> {
+        let bytes: [u8; 4] = self.value.as_slice().try_into().ok()?;
+        Some(u32::from_be_bytes(bytes))
+    }
+
+    /// Returns the value as a list of NUL-terminated strings, e.g. for `compatible`.
+    pub fn strings(&self) -> impl Iterator<Item = &str> {
+        self.value
+            .split(|b| *b == 0)
+            .filter(|s| !s.is_empty())
+            .filter_map(|s| core::str::from_utf8(s).ok())
+    }
+
+    /// Returns the value as a single string, or `None` if it is not a NUL-terminated string.
+    pub fn as_str(&self) -> Option<&str> {
+        let (last, bytes) = self.value.split_last()?;
+        if *last != 0 {
+            return None;
+        }
+        core::str::from_utf8(bytes).ok()
+    }
+}
+
+/// A node of a device tree.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FdtNode {
+    /// The node name including the unit address, e.g. `serial@10000000`. Empty for the root.
+    pub name: String,
+    /// The properties of the node, in blob order.
+    pub properties: Vec<FdtProperty>,
+    /// The child nodes, in blob order.
+    pub children: Vec<FdtNode>,
+}
+
+impl FdtNode {
+    /// Creates a node without properties and children.
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Adds a property holding big-endian 32-bit cells.
+    pub fn set_cells(&mut self, name: &str, cells: &[u32]) -> &mut Self {
+        self.set_property(FdtProperty::from_cells(name, cells))
+    }
+
+    /// Adds a property holding a list of strings.
+    pub fn set_strs(&mut self, name: &str, strings: &[&str]) -> &mut Self {
+        self.set_property(FdtProperty::from_strs(name, strings))
+    }
+
+    /// Adds a property, replacing any existing property with the same name.
+    pub fn set_property(&mut self, prop: FdtProperty) -> &mut Self {
+        match self.properties.iter_mut().find(|p| p.name == prop.name) {
+            Some(existing) => *existing = prop,
+            None => self.properties.push(prop),
+        }
+        self
+    }
+
+    /// Returns the property with the given name.
+    pub fn property(&self, name: &str) -> Option<&FdtProperty> {
+        self.properties.iter().find(|prop| prop.name == name)
+    }
+
+    /// Returns the value of a single-cell property.
+    pub fn prop_u32(&self, name: &str) -> Option<u32> {
+        self.property(name)?.as_u32()
+    }
+
+    /// Returns the value of a string property.
+    pub fn prop_str(&self, name: &str) -> Option<&str> {
+        self.property(name)?.as_str()
+    }
+
+    /// Returns the child with the given name.
+    pub fn child(&self, name: &str) -> Option<&FdtNode> {
+        self.children.iter().find(|child| child.name == name)
+    }
+
+    /// Returns the phandle of the node, from `phandle` or the older `linux,phandle`.
+    pub fn phandle(&self) -> Option<u32> {
+        self.prop_u32("phandle")
+            .or_else(|| self.prop_u32("linux,phandle"))
+    }
+
+    /// Returns true if any `compatible` string of the node starts with `prefix`.
+    pub fn is_compatible_with(&self, prefix: &str) -> bool {
+        self.property("compatible")
+            .is_some_and(|prop| prop.strings().any(|s| s.starts_with(prefix)))
+    }
+
+    /// Returns false if the node has a `status` other than `okay`/`ok`.
+    pub fn is_enabled(&self) -> bool {
+        self.prop_str("status")
+            .is_none_or(|status| status == "okay" || status == "ok")
+    }
+
+    /// The `#address-cells` of the children of this node, defaulting to 2.
+    pub fn address_cells(&self) -> usize {
+        self.prop_u32("#address-cells").unwrap_or(2) as usize
+    }
+
+    /// The `#size-cells` of the children of this node, defaulting to 1.
+    pub fn size_cells(&self) -> usize {
+        self.prop_u32("#size-cells").unwrap_or(1) as usize
+    }
+}
+
+/// A parsed device tree blob.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Fdt {
+    /// The physical id of the boot CPU.
+    pub boot_cpuid_phys: u32,
+    /// The memory reservation block, as `(address, size)` pairs.
+    pub mem_reserve: Vec<(u64, u64)>,
+    /// The root node.
+    pub root: FdtNode,
+}
+
+/// A big-endian reader over the blob that reports truncation as [`FdtError::Truncated`].
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl Reader<'_> {
+    fn u32(&mut self) -> Result<u32, FdtError> {
+        let bytes = self
+            .data
+            .get(self.offset..self.offset + 4)
+            .ok_or(FdtError::Truncated)?;
+        self.offset += 4;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn u64(&mut self) -> Result<u64, FdtError> {
+        Ok((u64::from(self.u32()?) << 32) | u64::from(self.u32()?))
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&[u8], FdtError> {
+        let end = self.offset.checked_add(len).ok_or(FdtError::Truncated)?;
+        let bytes = self.data.get(self.offset..end).ok_or(FdtError::Truncated)?;
+        self.offset = align4(end);
+        Ok(bytes)
+    }
+
+    /// Reads a NUL-terminated string and skips the padding after it.
+    fn cstr(&mut self) -> Result<String, FdtError> {
+        let start = self.offset;
+        let s = read_cstr(self.data, start)?;
+        self.offset = align4(start + s.len() + 1);
+        Ok(s.to_string())
+    }
+}
+
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+fn read_cstr(data: &[u8], offset: usize) -> Result<&str, FdtError> {
+    let rest = data.get(offset..).ok_or(FdtError::Truncated)?;
+    let len = rest
+        .iter()
+        .position(|b| *b == 0)
+        .ok_or(FdtError::Truncated)?;
+    core::str::from_utf8(&rest[..len]).map_err(|_| FdtError::BadString(offset))
+}
+
+impl Fdt {
+    /// Parses a device tree blob.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, FdtError> {
+        let mut header = Reader { data, offset: 0 };
+        let magic = header.u32()?;
+        if magic != FDT_MAGIC {
+            return Err(FdtError::BadMagic(magic));
+        }
+        let total_size = header.u32()? as usize;
+        let off_dt_struct = header.u32()? as usize;
+        let off_dt_strings = header.u32()? as usize;
+        let off_mem_rsvmap = header.u32()? as usize;
+        let version = header.u32()?;
+        let _last_comp_version = header.u32()?;
+        let boot_cpuid_phys = header.u32()?;
+        if version < 16 {
+            return Err(FdtError::UnsupportedVersion(version));
+        }
+        let data = data.get(..total_size).ok_or(FdtError::Truncated)?;
+        let strings = data.get(off_dt_strings..).ok_or(FdtError::Truncated)?;
+
+        let mut mem_reserve = Vec::new();
+        let mut rsv = Reader {
+            data,
+            offset: off_mem_rsvmap,
+        };
+        loop {
+            let (address, size) = (rsv.u64()?, rsv.u64()?);
+            if address == 0 && size == 0 {
+                break;
+            }
+            mem_reserve.push((address, size));
+        }
+
+        // Nodes under construction, the innermost last.
+        let mut stack: Vec<FdtNode> = Vec::new();
+        let mut root = None;
+        let mut reader = Reader {
+            data,
+            offset: off_dt_struct,
+        };
+        loop {
+            let offset = reader.offset;
+            let token = reader.u32()?;
+            match token {
+                FDT_BEGIN_NODE if root.is_none() => stack.push(FdtNode {
+                    name: reader.cstr()?,
+                    ..Default::default()
+                }),
+                FDT_END_NODE => {
+                    let node = stack.pop().ok_or(FdtError::BadToken { offset, token })?;
+                    match stack.last_mut() {
+                        Some(parent) => parent.children.push(node),
+                        None => root = Some(node),
+                    }
+                }
+                FDT_PROP => {
+                    let len = reader.u32()? as usize;
+                    let name_offset = reader.u32()? as usize;
+                    let value = reader.bytes(len)?.to_vec();
+                    let name = read_cstr(strings, name_offset)
+                        .map_err(|_| FdtError::BadString(off_dt_strings + name_offset))?
+                        .to_string();
+                    stack
+                        .last_mut()
+                        .ok_or(FdtError::BadToken { offset, token })?
+                        .properties
+                        .push(FdtProperty { name, value });
+                }
+                FDT_NOP => {}
+                FDT_END if stack.is_empty() => break,
+                _ => return Err(FdtError::BadToken { offset, token }),
+            }
+        }
+
+        Ok(Fdt {
+            boot_cpuid_phys,
+            mem_reserve,
+            root: root.ok_or(FdtError::Truncated)?,
+        })
+    }
+
+    /// Returns the node at `path`, e.g. `/soc/serial@10000000`.
+    pub fn find_node(&self, path: &str) -> Option<&FdtNode> {
+        path.split('/')
+            .filter(|name| !name.is_empty())
+            .try_fold(&self.root, |node, name| node.child(name))
+    }
+
+    /// Returns the path of the node with the given phandle, and the node itself.
+    pub fn find_phandle(&self, phandle: u32) -> Option<(String, &FdtNode)> {
+        let mut found = None;
+        self.walk(|path, node, _| {
+            if found.is_none() && node.phandle() == Some(phandle) {
+                found = Some((path.to_string(), node));
+            }
+        });
+        found
+    }
+
+    /// Calls `f` for every node in depth-first order, with the node path and the ancestors of
+    /// the node, the root first.
+    pub fn walk<'a>(&'a self, mut f: impl FnMut(&str, &'a FdtNode, &[&'a FdtNode])) {
+        fn visit<'a>(
+            node: &'a FdtNode,
+            path: &str,
+            ancestors: &mut Vec<&'a FdtNode>,
+            f: &mut impl FnMut(&str, &'a FdtNode, &[&'a FdtNode]),
+        ) {
+            f(path, node, ancestors);
+            ancestors.push(node);
+            for child in &node.children {
+                let child_path = match path {
+                    "/" => format!("/{}", child.name),
+                    _ => format!("{path}/{}", child.name),
+                };
+                visit(child, &child_path, ancestors, f);
+            }
+            ancestors.pop();
+        }
+        visit(&self.root, "/", &mut Vec::new(), &mut f);
+    }
+}
+
+impl Fdt {
+    /// Serializes the device tree into a blob (structure version 17).
+    pub fn to_bytes(&self) -> Vec<u8> {
+        fn push_u32(buf: &mut Vec<u8>, value: u32) {
+            buf.extend_from_slice(&value.to_be_bytes());
+        }
+        fn pad(buf: &mut Vec<u8>) {
+            buf.resize(align4(buf.len()), 0);
+        }
+        fn write_node(node: &FdtNode, structure: &mut Vec<u8>, strings: &mut Vec<u8>) {
+            push_u32(structure, FDT_BEGIN_NODE);
+            structure.extend_from_slice(node.name.as_bytes());
+            structure.push(0);
+            pad(structure);
+            for prop in &node.properties {
+                let name_offset = find_string(strings, &prop.name).unwrap_or_else(|| {
+                    let offset = strings.len();
+                    strings.extend_from_slice(prop.name.as_bytes());
+                    strings.push(0);
+                    offset
+                });
+                push_u32(structure, FDT_PROP);
+                push_u32(structure, prop.value.len() as u32);
+                push_u32(structure, name_offset as u32);
+                structure.extend_from_slice(&prop.value);
+                pad(structure);
+            }
+            for child in &node.children {
+                write_node(child, structure, strings);
+            }
+            push_u32(structure, FDT_END_NODE);
+        }
+        /// Returns the offset of `name` in the strings block, if it is already there.
+        fn find_string(strings: &[u8], name: &str) -> Option<usize> {
+            let mut offset = 0;
+            for s in strings.split(|b| *b == 0) {
+                if s == name.as_bytes() {
+                    return Some(offset);
+                }
+                offset += s.len() + 1;
+            }
+            None
+        }
+
+        const HEADER_SIZE: usize = 40;
+        let mut rsvmap = Vec::new();
+        for (address, size) in self.mem_reserve.iter().chain([(0, 0)].iter()) {
+            rsvmap.extend_from_slice(&address.to_be_bytes());
+            rsvmap.extend_from_slice(&size.to_be_bytes());
+        }
+        let (mut structure, mut strings) = (Vec::new(), Vec::new());
+        write_node(&self.root, &mut structure, &mut strings);
+        push_u32(&mut structure, FDT_END);
+
+        // The memory reservation block must be 8-byte aligned, which the header size is.
+        let off_mem_rsvmap = HEADER_SIZE;
+        let off_dt_struct = off_mem_rsvmap + rsvmap.len();
+        let off_dt_strings = off_dt_struct + structure.len();
+        let total_size = off_dt_strings + strings.len();
+
+        let mut blob = Vec::with_capacity(total_size);
+        for field in [
+            FDT_MAGIC,
+            total_size as u32,
+            off_dt_struct as u32,
+            off_dt_strings as u32,
+            off_mem_rsvmap as u32,
+            17,
+            16,
+            self.boot_cpuid_phys,
+            strings.len() as u32,
+            structure.len() as u32,
+        ] {
+            push_u32(&mut blob, field);
+        }
+        blob.extend_from_slice(&rsvmap);
+        blob.extend_from_slice(&structure);
+        blob.extend_from_slice(&strings);
+        blob
+    }
+
+    /// Formats the device tree as device tree source (DTS), which `dtc` compiles back into an
+    /// equivalent blob.
+    pub fn to_dts(&self) -> String {
+        fn write_value(out: &mut String, prop: &FdtProperty) {
+            let value = &prop.value;
+            let printable =
+                |s: &[u8]| !s.is_empty() && s.iter().all(|b| b.is_ascii_graphic() || *b == b' ');
+            if value.is_empty() {
+                return;
+            }
+            out.push_str(" = ");
+            if value.last() == Some(&0)
+                && value[..value.len() - 1].split(|b| *b == 0).all(printable)
+            {
+                let strings: Vec<String> = prop.strings().map(|s| format!("{s:?}")).collect();
+                out.push_str(&strings.join(", "));
+            } else if value.len().is_multiple_of(4) {
+                let cells: Vec<String> = prop.cells().iter().map(|c| format!("{c:#x}")).collect();
+                out.push_str(&format!("<{}>", cells.join(" ")));
+            } else {
+                let bytes: Vec<String> = value.iter().map(|b| format!("{b:02x}")).collect();
+                out.push_str(&format!("[{}]", bytes.join(" ")));
+            }
+        }
+        fn write_node(out: &mut String, node: &FdtNode, depth: usize) {
+            let indent = "\t".repeat(depth);
+            let name = if depth == 0 { "/" } else { node.name.as_str() };
+            out.push_str(&format!("{indent}{name} {{\n"));
+            for prop in &node.properties {
+                out.push_str(&format!("{indent}\t{}", prop.name));
+                write_value(out, prop);
+                out.push_str(";\n");
+            }
+            for child in &node.children {
+                out.push('\n');
+                write_node(out, child, depth + 1);
+            }
+            out.push_str(&format!("{indent}}};\n"));
+        }
+
+        let mut out = String::from("/dts-v1/;\n\n");
+        for (address, size) in &self.mem_reserve {
+            out.push_str(&format!("/memreserve/ {address:#x} {size:#x};\n"));
+        }
+        if !self.mem_reserve.is_empty() {
+            out.push('\n');
+        }
+        write_node(&mut out, &self.root, 0);
+        out
+    }
+}
+
+/// Combines big-endian cells into a number. Only the last two cells are used, so the flags
+/// cell of 3-cell PCI addresses is dropped.
+pub(crate) fn cells_to_u64(cells: &[u32]) -> u64 {
+    cells
+        .iter()
+        .rev()
+        .take(2)
+        .rev()
+        .fold(0, |value, cell| (value << 32) | u64::from(*cell))
+}
@@ -0,0 +1,116 @@
+//! Typed mapping flags of guest memory regions.
+use alloc::string::String;
+use core::fmt::{Display, Formatter};
+
+bitflags::bitflags! {
+    /// The mapping flags of a guest memory region, mirroring `MappingFlags` provided by
+    /// `axaddrspace`.
+    ///
+    /// In TOML it is written either as an integer, e.g. `0x7`, or as a list of flag names, e.g.
+    /// `["read", "write", "exec"]`. It is always serialized as an integer.
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct VmMemFlags: usize {
+        /// The memory is readable.
+        const READ = 1 << 0;
+        /// The memory is writable.
+        const WRITE = 1 << 1;
+        /// The memory is executable.
+        const EXECUTE = 1 << 2;
+        /// The memory is user accessible.
+        const USER = 1 << 3;
+        /// The memory is device memory.
+        const DEVICE = 1 << 4;
+        /// The memory is uncached.
+        const UNCACHED = 1 << 5;
+    }
+}
+
+impl VmMemFlags {
+    /// Returns the flag with the given name, case-insensitively. Besides the flag names, the
+    /// short forms `r`, `w`, `x`/`exec` and `u` are accepted.
+    pub fn from_flag_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "read" | "r" => Some(Self::READ),
+            "write" | "w" => Some(Self::WRITE),
+            "execute" | "exec" | "x" => Some(Self::EXECUTE),
+            "user" | "u" => Some(Self::USER),
+            "device" => Some(Self::DEVICE),
+            "uncached" => Some(Self::UNCACHED),
+            _ => None,
+        }
+    }
+}
+
+impl Display for VmMemFlags {
+    /// Formats the flags symbolically, e.g. `READ | WRITE | EXECUTE`.
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        if self.is_empty() {
+            return write!(f, "NONE");
+        }
+        for (i, (name, _)) in self.iter_names().enumerate() {
+            if i > 0 {
+                write!(f, " | ")?;
+            }
+            write!(f, "{name}")?;
+        }
+        Ok(())
+    }
+}
+
+impl serde::Serialize for VmMemFlags {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(self.bits() as u64)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for VmMemFlags {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct FlagsVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for FlagsVisitor {
+            type Value = VmMemFlags;
+
+            fn expecting(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+                write!(
+                    f,
+                    "an integer or a list of mapping flags like [\"read\", \"write\", \"exec\"]"
+                )
+            }
+
+            fn visit_u64<E: serde::de::Error>(self, v: u64) -> Result<VmMemFlags, E> {
+                usize::try_from(v)
+                    .ok()
+                    .and_then(VmMemFlags::from_bits)
+                    .ok_or_else(|| {
+                        E::custom(alloc::format!(
+                            "unknown mapping flag bits {:#x}",
+                            v & !(VmMemFlags::all().bits() as u64)
+                        ))
+                    })
+            }
+
+            fn visit_i64<E: serde::de::Error>(self, v: i64) -> Result<VmMemFlags, E> {
+                let v = u64::try_from(v)
+                    .map_err(|_| E::invalid_value(serde::de::Unexpected::Signed(v), &self))?;
+                self.visit_u64(v)
+            }
+
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(
+                self,
+                mut seq: A,
+            ) -> Result<VmMemFlags, A::Error> {
+                let mut flags = VmMemFlags::empty();
+                while let Some(name) = seq.next_element::<String>()? {
+                    flags |= VmMemFlags::from_flag_name(&name).ok_or_else(|| {
+                        serde::de::Error::custom(alloc::format!(
+                            "unknown mapping flag '{name}', expected one of read, write, exec, user, device, uncached"
+                        ))
+                    })?;
+                }
+                Ok(flags)
+            }
+        }
+
+        deserializer.deserialize_any(FlagsVisitor)
+    }
+}
@@ -0,0 +1,362 @@
+//! Canonical layout of configuration files, keeping their comments.
+//!
+//! [`format_toml`] edits a file with `toml_edit` instead of writing it from scratch like
+//! [`AxVMCrateConfig::to_toml`], so comments stay next to the values they describe. Only the
+//! layout changes, the configuration read from the file stays the same.
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use alloc::{format, vec};
+
+use toml_edit::{Array, DocumentMut, Item, Table, Value};
+
+use crate::resolve::{EXTENDS_KEY, INCLUDE_KEY};
+use crate::writer::{format_hex, key_order};
+use crate::{AxVMCrateConfig, ConfigParseError, ConfigParseErrorKind};
+
+/// Arrays written as tuples with aligned columns, with the columns holding addresses or sizes.
+const TUPLE_ARRAYS: &[(&str, &[usize])] = &[
+    ("kernel.memory_regions", &[0, 1]),
+    ("devices.emu_devices", &[1, 2]),
+    ("devices.passthrough_devices", &[1, 2, 3]),
+];
+
+/// Keys holding addresses or sizes, written in hexadecimal.
+const ADDRESS_KEYS: &[&str] = &[
+    "entry_point",
+    "kernel_load_addr",
+    "bios_load_addr",
+    "dtb_load_addr",
+    "ramdisk_load_addr",
+    "gpa",
+    "size",
+    "base_gpa",
+    "base_hpa",
+    "length",
+];
+
+/// Rewrites a VM configuration file into the canonical layout, keeping its comments.
+///
+/// The canonical layout is:
+/// - `config_version` first, then the `[base]`, `[kernel]` and `[devices]` tables, and the keys
+///   of each table in the order of the fields of the configuration structures;
+/// - addresses and sizes, and every integer already written in hexadecimal, as hexadecimal
+///   grouped by `_`, e.g. `0x8000_0000`;
+/// - memory regions and devices written as tuples one per line, with aligned columns and
+///   aligned trailing comments;
+/// - one space around `=`.
+///
+/// Fails if the file is not a valid configuration, or if formatting would change the
+/// configuration it describes. A file with `extends` or `include` (see
+/// [`resolve_config`](crate::resolve_config)) only gives part of a configuration, so it only
+/// has to be valid TOML. Formatting a formatted file changes nothing.
+pub fn format_toml(source: &str) -> Result<String, ConfigParseError> {
+    let mut doc: DocumentMut = source.parse().map_err(|err: toml_edit::TomlError| {
+        ConfigParseError::new(
+            ConfigParseErrorKind::Syntax,
+            err.message(),
+            source,
+            None,
+            err.span(),
+        )
+    })?;
+    let config = if has_parents(&doc) {
+        None
+    } else {
+        Some(AxVMCrateConfig::from_toml_with_upgrade(source)?.0)
+    };
+
+    order_tables(doc.as_table_mut());
+    format_table(doc.as_table_mut(), "");
+    if doc
+        .trailing()
+        .as_str()
+        .is_some_and(|trailing| trailing.trim().is_empty())
+    {
+        doc.set_trailing("");
+    }
+    let formatted = doc.to_string();
+    // Formatting must not change what the file means.
+    if let Some(config) = config {
+        let reparsed =
+            AxVMCrateConfig::from_toml_with_upgrade(&formatted).map(|(config, _)| config);
+        if reparsed.as_ref() != Ok(&config) {
+            return Err(ConfigParseError::new(
+                ConfigParseErrorKind::Schema,
+                "formatting would change the meaning of the configuration",
+                source,
+                Some(""),
+                None,
+            ));
+        }
+    }
+    Ok(formatted)
+}
+
+/// Whether a file builds on other files with `extends` or `include`.
+pub(crate) fn has_parents(doc: &DocumentMut) -> bool {
+    doc.contains_key(EXTENDS_KEY) || doc.contains_key(INCLUDE_KEY)
+}
+
+/// Joins a key to the path of its table.
+fn join(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{path}.{key}")
+    }
+}
+
+/// Whether the elements of the array at `path` may be written as tuples.
+pub(crate) fn is_tuple_array(path: &str) -> bool {
+    TUPLE_ARRAYS.iter().any(|(p, _)| *p == path)
+}
+
+/// The rank of `key` in `order`; keys not in `order` come last.
+fn rank(order: &[&str], key: &str) -> usize {
+    order.iter().position(|k| *k == key).unwrap_or(order.len())
+}
+
+/// Calls `f` on every table below `table` in document order, with the rank of the top-level
+/// section the table belongs to.
+fn visit_tables(table: &mut Table, section: Option<usize>, f: &mut impl FnMut(usize, &mut Table)) {
+    for (key, item) in table.iter_mut() {
+        let section = section.unwrap_or_else(|| rank(key_order(""), key.get()));
+        match item {
+            Item::Table(table) => {
+                f(section, table);
+                visit_tables(table, Some(section), f);
+            }
+            Item::ArrayOfTables(tables) => {
+                for table in tables.iter_mut() {
+                    f(section, table);
+                    visit_tables(table, Some(section), f);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Moves the tables into the section order. Tables of the same section, e.g. `[kernel]` and
+/// `[[kernel.memory_regions]]`, keep their relative order.
+fn order_tables(root: &mut Table) {
+    let mut tables = Vec::new();
+    visit_tables(root, None, &mut |section, table| {
+        tables.push((section, table.position()))
+    });
+    let mut order: Vec<usize> = (0..tables.len())
+        .filter(|&i| tables[i].1.is_some())
+        .collect();
+    order.sort_by_key(|&i| tables[i]);
+    let mut positions = vec![None; tables.len()];
+    for (position, &i) in order.iter().enumerate() {
+        positions[i] = Some(position as isize + 1);
+    }
+
+    let mut index = 0;
+    let root_is_empty = root.iter().all(|(_, item)| !item.is_value());
+    visit_tables(root, None, &mut |_, table| {
+        if let Some(position) = positions[index] {
+            table.set_position(Some(position));
+            // Separate every table header from what comes before it by a blank line, except
+            // at the start of the file
+            let prefix = table
+                .decor()
+                .prefix()
+                .and_then(|prefix| prefix.as_str())
+                .unwrap_or_default()
+                .trim_start_matches('\n');
+            let prefix = if position == 1 && root_is_empty {
+                prefix.to_string()
+            } else {
+                format!("\n{prefix}")
+            };
+            table.decor_mut().set_prefix(prefix);
+        }
+        index += 1;
+    });
+}
+
+/// Orders the keys of a table and formats its values and subtables.
+fn format_table(table: &mut Table, path: &str) {
+    let order = key_order(path);
+    table.sort_values_by(|a, _, b, _| rank(order, a.get()).cmp(&rank(order, b.get())));
+
+    for (mut key, item) in table.iter_mut() {
+        let child = join(path, key.get());
+        match item {
+            Item::Value(value) => {
+                key.leaf_decor_mut().set_suffix(" ");
+                value.decor_mut().set_prefix(" ");
+                format_value(value, &child, ADDRESS_KEYS.contains(&key.get()));
+            }
+            Item::Table(table) => format_table(table, &child),
+            Item::ArrayOfTables(tables) => {
+                for table in tables.iter_mut() {
+                    format_table(table, &child);
+                }
+            }
+            Item::None => {}
+        }
+    }
+}
+
+/// Formats a value at `path`, writing integers in hexadecimal if `address` or if they already
+/// are.
+fn format_value(value: &mut Value, path: &str, address: bool) {
+    match value {
+        Value::Integer(integer) => {
+            let is_hex = integer
+                .as_repr()
+                .and_then(|repr| repr.as_raw().as_str())
+                .is_some_and(|raw| raw.starts_with("0x"));
+            let Ok(number) = usize::try_from(*integer.value()) else {
+                return;
+            };
+            if address || is_hex {
+                let mut hex: Value = format_hex(number).parse().unwrap();
+                *hex.decor_mut() = value.decor().clone();
+                *value = hex;
+            }
+        }
+        Value::Array(array) => match TUPLE_ARRAYS.iter().find(|(p, _)| *p == path) {
+            Some((_, columns)) => format_tuples(array, path, columns),
+            None => {
+                for value in array.iter_mut() {
+                    format_value(value, path, false);
+                }
+            }
+        },
+        Value::InlineTable(table) => {
+            let order = key_order(path);
+            table.sort_values_by(|a, _, b, _| rank(order, a.get()).cmp(&rank(order, b.get())));
+            for (key, value) in table.iter_mut() {
+                let address = ADDRESS_KEYS.contains(&key.get());
+                format_value(value, &join(path, key.get()), address);
+            }
+            table.fmt();
+        }
+        _ => {}
+    }
+}
+
+/// The text of a value without its surrounding whitespace and comments.
+pub(crate) fn bare(value: &Value) -> String {
+    let mut value = value.clone();
+    value.decor_mut().clear();
+    value.to_string()
+}
+
+/// The comments in the whitespace between two values of an array: the one ending the line of
+/// the first value, and those on lines of their own.
+fn comments(gap: &str) -> (Option<String>, Vec<String>) {
+    let mut lines = gap.split('\n');
+    let trailing = lines
+        .next()
+        .and_then(|line| line.find('#').map(|i| line[i..].trim_end().to_string()));
+    let own_lines = lines
+        .map(str::trim)
+        .filter(|line| line.starts_with('#'))
+        .map(str::to_string)
+        .collect();
+    (trailing, own_lines)
+}
+
+/// Writes an array as one tuple per line with aligned columns, keeping the comments between
+/// the tuples. `columns` are the columns holding addresses or sizes.
+fn format_tuples(array: &mut Array, path: &str, columns: &[usize]) {
+    if array.is_empty() {
+        return;
+    }
+
+    // Collect the comments before each tuple, and after the last one
+    let raw = |raw: Option<&toml_edit::RawString>| {
+        raw.and_then(|raw| raw.as_str())
+            .unwrap_or_default()
+            .to_string()
+    };
+    let mut gaps = Vec::with_capacity(array.len() + 1);
+    let mut gap = String::new();
+    for row in array.iter() {
+        gap.push_str(&raw(row.decor().prefix()));
+        gaps.push(comments(&gap));
+        gap = raw(row.decor().suffix());
+    }
+    gap.push_str(array.trailing().as_str().unwrap_or_default());
+    gaps.push(comments(&gap));
+
+    // Format the cells, and align the columns of the tuples
+    let mut widths = Vec::new();
+    for row in array.iter_mut() {
+        if let Value::Array(cells) = row {
+            for (i, cell) in cells.iter_mut().enumerate() {
+                format_value(cell, path, columns.contains(&i));
+                if let Value::Array(list) = cell {
+                    list.fmt();
+                }
+                let width = bare(cell).chars().count();
+                if widths.len() <= i {
+                    widths.push(width);
+                } else {
+                    widths[i] = widths[i].max(width);
+                }
+            }
+        } else {
+            format_value(row, path, false);
+        }
+    }
+    for row in array.iter_mut() {
+        if let Value::Array(cells) = row {
+            let mut padding = 0;
+            for (i, cell) in cells.iter_mut().enumerate() {
+                let prefix = if i == 0 {
+                    String::new()
+                } else {
+                    " ".repeat(1 + padding)
+                };
+                cell.decor_mut().set_prefix(prefix);
+                cell.decor_mut().set_suffix("");
+                padding = widths[i] - bare(cell).chars().count();
+            }
+            cells.set_trailing("");
+            cells.set_trailing_comma(false);
+        }
+    }
+
+    // Put each tuple on a line of its own, with the trailing comments aligned
+    let lines: Vec<usize> = array
+        .iter()
+        .map(|row| 4 + bare(row).chars().count() + 1)
+        .collect();
+    let width = lines.iter().copied().max().unwrap_or(0);
+    let tail = |trailing: &Option<String>, line: usize| match trailing {
+        Some(comment) => format!("{} {comment}", " ".repeat(width - line)),
+        None => String::new(),
+    };
+    let own_lines = |comments: &[String]| {
+        comments
+            .iter()
+            .map(|comment| format!("    {comment}\n"))
+            .collect::<String>()
+    };
+    let mut previous: Option<usize> = None;
+    for (i, row) in array.iter_mut().enumerate() {
+        let (trailing, comments) = &gaps[i];
+        let tail = match previous {
+            Some(line) => tail(trailing, line),
+            // Comments after the opening bracket stay there
+            None => trailing
+                .as_ref()
+                .map(|comment| format!(" {comment}"))
+                .unwrap_or_default(),
+        };
+        row.decor_mut()
+            .set_prefix(format!("{tail}\n{}    ", own_lines(comments)));
+        row.decor_mut().set_suffix("");
+        previous = Some(lines[i]);
+    }
+    let (trailing, comments) = &gaps[array.len()];
+    let last = lines[array.len() - 1];
+    array.set_trailing(format!("{}\n{}", tail(trailing, last), own_lines(comments)));
+    array.set_trailing_comma(true);
+}
@@ -0,0 +1,81 @@
+//! Parsing and (de)serialization of [`ImageLocation`].
+//!
+//! Image locations are written as strings, matched case-insensitively against the names and
+//! aliases of each location. Unknown values are rejected instead of falling back to `"fs"`.
+use alloc::string::{String, ToString};
+use core::fmt::{Display, Formatter};
+use core::str::FromStr;
+
+use crate::ImageLocation;
+
+/// Error returned when parsing an [`ImageLocation`] from a string that names no location.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseImageLocationError(String);
+
+impl Display for ParseImageLocationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "unknown image location '{}', expected one of \"fs\", \"memory\"",
+            self.0
+        )
+    }
+}
+
+impl core::error::Error for ParseImageLocationError {}
+
+impl ImageLocation {
+    /// Returns the name of the image location, as written in configuration files.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Fs => "fs",
+            Self::Memory => "memory",
+        }
+    }
+}
+
+impl Display for ImageLocation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for ImageLocation {
+    type Err = ParseImageLocationError;
+
+    /// Parses an image location case-insensitively. `filesystem` and `rootfs` are accepted for
+    /// `fs`, and `ram` for `memory`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "fs" | "filesystem" | "rootfs" => Ok(Self::Fs),
+            "memory" | "ram" => Ok(Self::Memory),
+            _ => Err(ParseImageLocationError(s.to_string())),
+        }
+    }
+}
+
+impl serde::Serialize for ImageLocation {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for ImageLocation {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ImageLocationVisitor;
+
+        impl serde::de::Visitor<'_> for ImageLocationVisitor {
+            type Value = ImageLocation;
+
+            fn expecting(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+                write!(f, "one of \"fs\", \"memory\"")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<ImageLocation, E> {
+                v.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_str(ImageLocationVisitor)
+    }
+}
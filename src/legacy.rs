@@ -0,0 +1,159 @@
+//! Reader for the legacy flat configuration format.
+//!
+//! Before the configuration was split into `[base]`, `[kernel]` and `[devices]` tables, all
+//! fields lived at the top level of the file, and memory regions were written as
+//! `[gpa, size, flags]` without a mapping type. This module maps such files into
+//! [`AxVMCrateConfig`].
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use axerrno::AxResult;
+
+use crate::{
+    AxVMCrateConfig, EmulatedDeviceConfig, ImageLocation, PassThroughDeviceConfig, VMBaseConfig,
+    VMDevicesConfig, VMInterruptMode, VMKernelConfig, VMType, VmMemConfig, VmMemFlags,
+    VmMemMappingType,
+};
+
+/// A memory region in the legacy format, where the mapping type is optional.
+#[derive(Debug, serde::Deserialize)]
+struct LegacyMemConfig {
+    gpa: usize,
+    #[serde(deserialize_with = "crate::units::deserialize_size")]
+    size: usize,
+    flags: VmMemFlags,
+    #[serde(default)]
+    map_type: Option<VmMemMappingType>,
+}
+
+impl From<LegacyMemConfig> for VmMemConfig {
+    fn from(region: LegacyMemConfig) -> Self {
+        // Legacy hypervisors identity-mapped device memory and allocated everything else.
+        let map_type = region
+            .map_type
+            .unwrap_or(if region.flags.contains(VmMemFlags::DEVICE) {
+                VmMemMappingType::MapIdentical
+            } else {
+                VmMemMappingType::MapAlloc
+            });
+        VmMemConfig {
+            gpa: region.gpa,
+            size: region.size,
+            flags: region.flags,
+            map_type,
+        }
+    }
+}
+
+/// The legacy flat configuration, with all fields at the top level.
+#[derive(Debug, serde::Deserialize)]
+struct LegacyVMConfig {
+    id: usize,
+    name: String,
+    vm_type: VMType,
+    cpu_num: usize,
+    phys_cpu_ids: Option<Vec<usize>>,
+    phys_cpu_sets: Option<Vec<usize>>,
+    entry_point: usize,
+    kernel_path: String,
+    kernel_load_addr: usize,
+    bios_path: Option<String>,
+    bios_load_addr: Option<usize>,
+    dtb_path: Option<String>,
+    dtb_load_addr: Option<usize>,
+    ramdisk_path: Option<String>,
+    ramdisk_load_addr: Option<usize>,
+    #[serde(default)]
+    image_location: ImageLocation,
+    cmdline: Option<String>,
+    disk_path: Option<String>,
+    memory_regions: Vec<LegacyMemConfig>,
+    #[serde(default)]
+    emu_devices: Vec<EmulatedDeviceConfig>,
+    #[serde(default)]
+    passthrough_devices: Vec<PassThroughDeviceConfig>,
+    #[serde(default)]
+    interrupt_mode: VMInterruptMode,
+    #[serde(default)]
+    excluded_devices: Vec<Vec<String>>,
+}
+
+impl From<LegacyVMConfig> for AxVMCrateConfig {
+    fn from(legacy: LegacyVMConfig) -> Self {
+        AxVMCrateConfig {
+            config_version: None,
+            base: VMBaseConfig {
+                id: legacy.id,
+                name: legacy.name,
+                vm_type: legacy.vm_type,
+                cpu_num: legacy.cpu_num,
+                phys_cpu_ids: legacy.phys_cpu_ids,
+                phys_cpu_sets: legacy.phys_cpu_sets,
+            },
+            kernel: VMKernelConfig {
+                entry_point: legacy.entry_point,
+                kernel_path: legacy.kernel_path,
+                kernel_load_addr: legacy.kernel_load_addr,
+                bios_path: legacy.bios_path,
+                bios_load_addr: legacy.bios_load_addr,
+                dtb_path: legacy.dtb_path,
+                dtb_load_addr: legacy.dtb_load_addr,
+                ramdisk_path: legacy.ramdisk_path,
+                ramdisk_load_addr: legacy.ramdisk_load_addr,
+                image_location: legacy.image_location,
+                cmdline: legacy.cmdline,
+                disk_path: legacy.disk_path,
+                memory_regions: legacy.memory_regions.into_iter().map(Into::into).collect(),
+            },
+            devices: VMDevicesConfig {
+                emu_devices: legacy.emu_devices,
+                passthrough_devices: legacy.passthrough_devices,
+                interrupt_mode: legacy.interrupt_mode,
+                excluded_devices: legacy.excluded_devices,
+            },
+        }
+    }
+}
+
+/// Returns true if the configuration table is in the legacy flat format, i.e. it has top-level
+/// VM fields such as `id` or `kernel_path` and none of the `[base]`, `[kernel]` and `[devices]`
+/// tables.
+pub(crate) fn is_legacy_table(table: &toml::Table) -> bool {
+    let sectioned = ["base", "kernel", "devices"]
+        .iter()
+        .any(|key| table.contains_key(*key));
+    let flat = ["id", "name", "cpu_num", "kernel_path", "memory_regions"]
+        .iter()
+        .any(|key| table.contains_key(*key));
+    flat && !sectioned
+}
+
+/// Converts a configuration table in the legacy flat format into the sectioned format.
+pub(crate) fn upgrade_legacy_table(table: &toml::Table) -> Result<toml::Table, toml::de::Error> {
+    let legacy: LegacyVMConfig = toml::Value::Table(table.clone()).try_into()?;
+    match toml::Value::try_from(AxVMCrateConfig::from(legacy)) {
+        Ok(toml::Value::Table(table)) => Ok(table),
+        _ => Err(serde::de::Error::custom("failed to convert legacy config")),
+    }
+}
+
+impl AxVMCrateConfig {
+    /// Returns true if the toml string is a configuration in the legacy flat format, i.e. it has
+    /// top-level VM fields such as `id` or `kernel_path` and none of the `[base]`, `[kernel]` and
+    /// `[devices]` tables.
+    pub fn is_legacy_toml(raw_cfg_str: &str) -> bool {
+        toml::from_str::<toml::Table>(raw_cfg_str).is_ok_and(|table| is_legacy_table(&table))
+    }
+
+    /// Deserialize a toml string in the legacy flat format to `AxVMCrateConfig`.
+    ///
+    /// Memory regions without a mapping type are identity-mapped if they are device memory, and
+    /// allocated by the VM monitor otherwise.
+    pub fn from_legacy_toml(raw_cfg_str: &str) -> AxResult<Self> {
+        let legacy: LegacyVMConfig = toml::from_str(raw_cfg_str).map_err(|err| {
+            warn!("Legacy config TOML parse error {:?}", err.message());
+            axerrno::ax_err_type!(InvalidInput, alloc::format!("Error details {err:?}"))
+        })?;
+        Ok(legacy.into())
+    }
+}
@@ -1,7 +1,7 @@
 //! [ArceOS-Hypervisor](https://github.com/arceos-hypervisor/arceos-umhv)
 //! [VM](https://github.com/arceos-hypervisor/axvm) config module.
 //! [`AxVMCrateConfig`]: the configuration structure for the VM.
-//! It is generated from toml file, and then converted to `AxVMConfig` for the VM creation.
+//! It is generated from toml file, and then converted to [`AxVMConfig`] for the VM creation.
 #![cfg_attr(not(feature = "std"), no_std)]
 
 extern crate alloc;
@@ -16,8 +16,60 @@ use serde_repr::{Deserialize_repr, Serialize_repr};
 
 use axerrno::AxResult;
 
+mod addr_map;
+mod diff;
+mod dtb;
+#[cfg(feature = "std")]
+mod edit;
+mod fdt;
+mod flags;
+#[cfg(feature = "std")]
+mod formatter;
+mod image_location;
+mod legacy;
+mod location;
+#[cfg(feature = "std")]
+mod migrate;
+mod parse_error;
+mod platform;
+#[cfg(feature = "std")]
+mod resolve;
+mod runtime;
+mod system;
+mod units;
+mod validate;
+mod version;
+mod vm_type;
+mod writer;
+
+pub use addr_map::{GpaMap, GpaOverlap, GpaOwnerKind, GpaRange};
+pub use diff::{ChangeKind, ConfigChange};
+pub use dtb::GuestArch;
+#[cfg(feature = "std")]
+pub use edit::{get_config_value, set_config_value, ConfigEditError};
+pub use fdt::{Fdt, FdtError, FdtNode, FdtProperty};
+pub use flags::VmMemFlags;
+#[cfg(feature = "std")]
+pub use formatter::format_toml;
+pub use image_location::ParseImageLocationError;
+pub use location::{locate_key, SourceLocation};
+#[cfg(feature = "std")]
+pub use migrate::migrate_toml;
+pub use parse_error::{ConfigParseError, ConfigParseErrorKind, SourceExcerpt};
+pub use platform::{AxPlatformConfig, PlatformDevice, PlatformMemRange};
+#[cfg(feature = "std")]
+pub use resolve::{resolve_config, ConfigResolveError};
+pub use runtime::{AxVCpuConfig, AxVMConfig, AxVMConfigError, AxVMImage, AxVMImageConfig};
+pub use system::AxSystemConfig;
+pub use units::{parse_size, ParseSizeError};
+pub use validate::{Diagnostic, Severity};
+pub use version::{ConfigUpgrade, CURRENT_CONFIG_VERSION};
+pub use vm_type::ParseVMTypeError;
+
 /// A part of `AxVMConfig`, which represents guest VM type.
-#[derive(Default, Clone, Copy, PartialEq, Eq, Debug, serde::Serialize, serde::Deserialize)]
+///
+/// In TOML it is written either as a number or as a name: `"host"`, `"rtos"` or `"linux"`.
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug)]
 pub enum VMType {
     /// Host VM, used for boot from Linux like Jailhouse do, named "type1.5".
     VMTHostVM = 0,
@@ -73,15 +125,22 @@ impl Default for VmMemMappingType {
 /// Represents a contiguous memory region within the guest's physical address space.
 /// Each region has specific properties including address, size, access permissions,
 /// and mapping type that determine how it's handled by the hypervisor.
-#[derive(Debug, Default, Clone, serde::Serialize, serde::Deserialize)]
+///
+/// It can be written either as a positional array `[gpa, size, flags, map_type]`, or as a table
+/// with the field names as keys, e.g. `{ gpa = 0x8000_0000, size = 0x100_0000, flags = 0x7,
+/// map_type = 1 }` or a `[[kernel.memory_regions]]` table. `flags` may also be a list of flag
+/// names, see [`VmMemFlags`].
+#[derive(Debug, Default, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct VmMemConfig {
     /// The start address of the memory region in GPA (Guest Physical Address).
     pub gpa: usize,
-    /// The size of the memory region in bytes.
+    /// The size of the memory region in bytes, also accepts strings like `"16MiB"`.
+    #[serde(deserialize_with = "units::deserialize_size")]
     pub size: usize,
-    /// The mappings flags of the memory region, refers to `MappingFlags` provided by `axaddrspace`.
+    /// The mappings flags of the memory region, mirroring `MappingFlags` provided by `axaddrspace`.
     /// Defines access permissions (read, write, execute) and caching behavior.
-    pub flags: usize,
+    pub flags: VmMemFlags,
     /// The type of memory mapping.
     /// Determines whether memory is allocated dynamically or mapped identically.
     pub map_type: VmMemMappingType,
@@ -91,19 +150,22 @@ pub struct VmMemConfig {
 ///
 /// Allocation scheme:
 /// - 0x00 - 0x1F: Special devices, and abstract device types that does not specify a concrete
-/// interface or implementation. The device objects created from these types depend on the target
-/// architecture and the specific implementation of the hypervisor.
+///   interface or implementation. The device objects created from these types depend on the target
+///   architecture and the specific implementation of the hypervisor.
 /// - 0x20 - 0x7F: Concrete emulated device types.
 ///   - 0x20 - 0x2F: Interrupt controller devices.
 ///   - 0x30 - 0x3F: Reserved for future use.
 /// - 0x80 - 0xDF: Reserved for future use.
 /// - 0xE0 - 0xEF: Virtio devices.
 /// - 0xF0 - 0xFF: Reserved for future use.
-#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize_repr, Deserialize_repr, Enumerable)]
+#[derive(
+    Debug, Default, Copy, Clone, PartialEq, Eq, Serialize_repr, Deserialize_repr, Enumerable,
+)]
 #[repr(u8)]
 pub enum EmulatedDeviceType {
     // Special devices and abstract device types.
     /// Dummy device type.
+    #[default]
     Dummy = 0x0,
     /// Interrupt controller device, e.g. vGICv2 in aarch64, vLAPIC in x86.
     InterruptController = 0x1,
@@ -143,12 +205,6 @@ pub enum EmulatedDeviceType {
     // GICR = 0x9,
 }
 
-impl Default for EmulatedDeviceType {
-    fn default() -> Self {
-        Self::Dummy
-    }
-}
-
 impl Display for EmulatedDeviceType {
     // Implementation of the Display trait for EmulatedDeviceType.
     fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
@@ -216,24 +272,36 @@ impl EmulatedDeviceType {
 }
 
 /// A part of `AxVMConfig`, which represents the configuration of an emulated device for a virtual machine.
-#[derive(Debug, Default, Clone, serde::Serialize, serde::Deserialize)]
+///
+/// It can be written either as a positional array
+/// `[name, base_gpa, length, irq_id, emu_type, cfg_list]`, or as a table with the field names as
+/// keys, e.g. a `[[devices.emu_devices]]` table. `cfg_list` is optional in both forms.
+#[derive(Debug, Default, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct EmulatedDeviceConfig {
     /// The name of the device.
     pub name: String,
     /// The base GPA (Guest Physical Address) of the device.
     pub base_gpa: usize,
-    /// The address length of the device.
+    /// The address length of the device, also accepts strings like `"4K"`.
+    #[serde(deserialize_with = "units::deserialize_size")]
     pub length: usize,
     /// The IRQ (Interrupt Request) ID of the device.
     pub irq_id: usize,
     /// The type of emulated device.
     pub emu_type: EmulatedDeviceType,
     /// The config_list of the device
+    #[serde(default)]
     pub cfg_list: Vec<usize>,
 }
 
 /// A part of `AxVMConfig`, which represents the configuration of a pass-through device for a virtual machine.
-#[derive(Debug, Default, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+///
+/// It can be written either as a positional array `[name, base_gpa, base_hpa, length, irq_id]`,
+/// or as a table with the field names as keys, e.g. a `[[devices.passthrough_devices]]` table.
+/// All fields except `name` are optional in both forms.
+#[derive(Debug, Default, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct PassThroughDeviceConfig {
     /// The name of the device.
     pub name: String,
@@ -243,8 +311,8 @@ pub struct PassThroughDeviceConfig {
     /// The base HPA (Host Physical Address) of the device.
     #[serde(default)]
     pub base_hpa: usize,
-    /// The address length of the device.
-    #[serde(default)]
+    /// The address length of the device, also accepts strings like `"4K"`.
+    #[serde(default, deserialize_with = "units::deserialize_size")]
     pub length: usize,
     /// The IRQ (Interrupt Request) ID of the device.
     #[serde(default)]
@@ -252,14 +320,14 @@ pub struct PassThroughDeviceConfig {
 }
 
 /// The configuration structure for the guest VM base info.
-#[derive(Debug, Default, Clone, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub struct VMBaseConfig {
     /// VM ID.
     pub id: usize,
     /// VM name.
     pub name: String,
-    /// VM type.
-    pub vm_type: usize,
+    /// VM type, written as a number or a name like `"rtos"`.
+    pub vm_type: VMType,
     // Resources.
     /// The number of virtual CPUs.
     pub cpu_num: usize,
@@ -275,15 +343,30 @@ pub struct VMBaseConfig {
     /// - If `None`, vcpu will be scheduled on available physical CPUs randomly.
     /// - If set, each vcpu will be scheduled on the specified physical CPUs.
     ///      
-    ///     For example, [0x0101, 0x0010] means:
-    ///          - vCpu0 can be scheduled at pCpu0 and pCpu2;
-    ///          - vCpu1 will only be scheduled at pCpu1;
-    ///      It will phrase an error if the number of vCpus is not equal to the length of `phys_cpu_sets` array.
+    ///   For example, [0x0101, 0x0010] means:
+    ///   - vCpu0 can be scheduled at pCpu0 and pCpu2;
+    ///   - vCpu1 will only be scheduled at pCpu1;
+    ///
+    ///   It will phrase an error if the number of vCpus is not equal to the length of `phys_cpu_sets` array.
     pub phys_cpu_sets: Option<Vec<usize>>,
 }
 
+/// The default `VMBaseConfig` describes the host VM, with no vCPUs.
+impl Default for VMBaseConfig {
+    fn default() -> Self {
+        Self {
+            id: 0,
+            name: String::new(),
+            vm_type: VMType::VMTHostVM,
+            cpu_num: 0,
+            phys_cpu_ids: None,
+            phys_cpu_sets: None,
+        }
+    }
+}
+
 /// The configuration structure for the guest VM kernel.
-#[derive(Debug, Default, Clone, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Default, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub struct VMKernelConfig {
     /// The entry point of the kernel image.
     pub entry_point: usize,
@@ -303,8 +386,9 @@ pub struct VMKernelConfig {
     pub ramdisk_path: Option<String>,
     /// The load address of the ramdisk image, `None` if not used.
     pub ramdisk_load_addr: Option<usize>,
-    /// The location of the image, default is 'fs'.
-    pub image_location: Option<String>,
+    /// The location of the images, default is [`ImageLocation::Fs`].
+    #[serde(default)]
+    pub image_location: ImageLocation,
     /// The command line of the kernel.
     pub cmdline: Option<String>,
     /// The path of the disk image.
@@ -313,11 +397,26 @@ pub struct VMKernelConfig {
     pub memory_regions: Vec<VmMemConfig>,
 }
 
+/// Where the hypervisor reads the kernel, BIOS, DTB and ramdisk images from.
+///
+/// In TOML it is written as a case-insensitive string: `"fs"` (or `"filesystem"`, `"rootfs"`)
+/// and `"memory"` (or `"ram"`). Other values are rejected. More sources may be added later.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ImageLocation {
+    /// The images are files inside the ArceOS's rootfs, loaded when the VM is created.
+    #[default]
+    Fs,
+    /// The images are read from the host when the hypervisor is built and embedded into it.
+    Memory,
+}
+
 /// Specifies how the VM should handle interrupts and interrupt controllers.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub enum VMInterruptMode {
     /// The VM will not handle interrupts, and the guest OS should not use interrupts.
     #[serde(rename = "no_irq", alias = "no", alias = "none")]
+    #[default]
     NoIrq,
     /// The VM will use the emulated interrupt controller to handle interrupts.
     #[serde(rename = "emu", alias = "emulated")]
@@ -327,14 +426,8 @@ pub enum VMInterruptMode {
     Passthrough,
 }
 
-impl Default for VMInterruptMode {
-    fn default() -> Self {
-        Self::NoIrq
-    }
-}
-
 /// The configuration structure for the guest VM devices.
-#[derive(Debug, Default, Clone, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Default, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub struct VMDevicesConfig {
     /// Emu device Information
     pub emu_devices: Vec<EmulatedDeviceConfig>,
@@ -343,15 +436,25 @@ pub struct VMDevicesConfig {
     /// How the VM should handle interrupts and interrupt controllers.
     #[serde(default)]
     pub interrupt_mode: VMInterruptMode,
-    ///we would not like to pass through devices
+    /// Host devices that are not given to the VM, as groups of device tree node paths, e.g.
+    /// `[["/soc/ethernet@ff0e0000", "/soc/phy@ff0f0000"]]`. Each group lists the nodes of one
+    /// device; an excluded node excludes its descendants too.
+    ///
+    /// They are skipped when importing passthrough devices from a host device tree
+    /// ([`Fdt::passthrough_devices`]), and removed, with the nodes that depend on them, when
+    /// deriving the VM's device tree from the host's ([`Fdt::remove_devices`]).
     #[serde(default)]
     pub excluded_devices: Vec<Vec<String>>,
 }
 
 /// The configuration structure for the guest VM serialized from a toml file provided by user,
-/// and then converted to `AxVMConfig` for the VM creation.
-#[derive(Debug, Default, Clone, serde::Serialize, serde::Deserialize)]
+/// and then converted to [`AxVMConfig`] for the VM creation.
+#[derive(Debug, Default, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub struct AxVMCrateConfig {
+    /// The version of the configuration schema the file is written for, see
+    /// [`CURRENT_CONFIG_VERSION`]. If `None`, the version is inferred from the layout of the file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config_version: Option<u32>,
     /// The base configuration for the VM.
     pub base: VMBaseConfig,
     /// The kernel configuration for the VM.
@@ -362,14 +465,23 @@ pub struct AxVMCrateConfig {
 
 impl AxVMCrateConfig {
     /// Deserialize the toml string to `AxVMCrateConfig`.
+    ///
+    /// Configurations written for older schema versions, including the legacy flat format, are
+    /// upgraded to the current version, see [`AxVMCrateConfig::from_toml_with_upgrade`], which
+    /// also returns the [`ConfigParseError`] with the location of a problem instead of an
+    /// [`AxError`](axerrno::AxError). The location is logged here.
     pub fn from_toml(raw_cfg_str: &str) -> AxResult<Self> {
-        let config: AxVMCrateConfig = toml::from_str(raw_cfg_str).map_err(|err| {
-            warn!("Config TOML parse error {:?}", err.message());
-            axerrno::ax_err_type!(InvalidInput, alloc::format!("Error details {err:?}"))
-        })?;
+        let (config, upgrade) = Self::from_toml_with_upgrade(raw_cfg_str)?;
+        for warning in &upgrade.warnings {
+            warn!(
+                "Config upgraded from version {}: {}",
+                upgrade.source_version, warning
+            );
+        }
         Ok(config)
     }
 }
 
 #[cfg(test)]
+#[allow(clippy::format_in_format_args)]
 mod test;
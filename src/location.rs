@@ -0,0 +1,163 @@
+//! Locating configuration fields in TOML source text.
+//!
+//! Diagnostics and errors refer to fields by their TOML key path, e.g.
+//! `devices.passthrough_devices[2]`. Tools that show problems in an editor or a CI report also
+//! need to know where that field is written in the file; this module maps key paths and byte
+//! offsets back to the source.
+use alloc::format;
+use alloc::string::{String, ToString};
+use core::ops::Range;
+
+use toml::de::{DeTable, DeValue};
+use toml::Spanned;
+
+/// A position in a source text, as 1-based line and column numbers. Columns count characters,
+/// not bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SourceLocation {
+    /// The line number, starting at 1.
+    pub line: usize,
+    /// The column number, starting at 1.
+    pub column: usize,
+}
+
+impl SourceLocation {
+    /// Returns the location of the byte at `offset` in `source`. Offsets past the end of the
+    /// source or inside a multi-byte character are moved back to the previous character.
+    pub fn from_offset(source: &str, offset: usize) -> Self {
+        let mut offset = offset.min(source.len());
+        while !source.is_char_boundary(offset) {
+            offset -= 1;
+        }
+        let before = &source[..offset];
+        let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+        Self {
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+        }
+    }
+}
+
+/// Splits a key path component like `memory_regions[1]` into the key and the array indices.
+fn parse_component(component: &str) -> Option<(&str, impl Iterator<Item = Option<usize>> + '_)> {
+    let (key, indices) = component
+        .find('[')
+        .map_or((component, ""), |bracket| component.split_at(bracket));
+    let indices = indices
+        .split_terminator(']')
+        .map(|index| index.strip_prefix('[')?.parse().ok());
+    Some((key, indices))
+}
+
+/// Returns the byte range of the value at a TOML key path, e.g. `base.cpu_num`,
+/// `kernel.memory_regions[1]` or `vm[0].devices.passthrough_devices[2]`, in `source`.
+///
+/// If the last keys of the path are missing, e.g. a diagnostic about a field that should have
+/// been set, the range of the deepest table or array that exists is returned instead. Returns
+/// `None` if the source cannot be parsed, the first key is missing, or an array index is out of
+/// range.
+pub fn locate_key(source: &str, path: &str) -> Option<Range<usize>> {
+    let root = DeTable::parse(source).ok()?;
+    let mut current: Option<&Spanned<DeValue>> = None;
+    for component in path.split('.') {
+        let (key, indices) = parse_component(component)?;
+        let table = match current {
+            None => root.get_ref(),
+            Some(value) => value.get_ref().as_table()?,
+        };
+        let Some((_, value)) = table.iter().find(|(name, _)| name.get_ref() == key) else {
+            return current.map(Spanned::span);
+        };
+        current = Some(value);
+        for index in indices {
+            current = Some(value_at(current?, index?)?);
+        }
+    }
+    current.map(Spanned::span)
+}
+
+/// Returns the element at `index` of an array value.
+fn value_at<'a, 'i>(
+    value: &'a Spanned<DeValue<'i>>,
+    index: usize,
+) -> Option<&'a Spanned<DeValue<'i>>> {
+    value.get_ref().as_array()?.as_ref().get(index)
+}
+
+/// Returns the key path of the innermost value or key at byte `offset` of `source`, e.g.
+/// `devices.passthrough_devices[2]` for an offset inside the third passthrough device. Returns
+/// `None` if the source cannot be parsed or the offset is not inside any value.
+pub(crate) fn key_path_at(source: &str, offset: usize) -> Option<String> {
+    let root = DeTable::parse(source).ok()?;
+    let mut path = String::new();
+    table_path_at(root.get_ref(), offset, &mut path).then_some(path)
+}
+
+fn table_path_at(table: &DeTable, offset: usize, path: &mut String) -> bool {
+    for (key, value) in table.iter() {
+        let len = path.len();
+        if !path.is_empty() {
+            path.push('.');
+        }
+        path.push_str(key.get_ref());
+        if key.span().contains(&offset) || value_path_at(value, offset, path) {
+            return true;
+        }
+        path.truncate(len);
+    }
+    false
+}
+
+/// Finds `offset` inside a value. The span of a table written with a `[header]` only covers
+/// the header, so the keys and elements of tables and arrays are searched whatever their span.
+fn value_path_at(value: &Spanned<DeValue>, offset: usize, path: &mut String) -> bool {
+    if let Some(table) = value.get_ref().as_table() {
+        if table_path_at(table, offset, path) {
+            return true;
+        }
+    } else if let Some(array) = value.get_ref().as_array() {
+        for (index, element) in array.as_ref().iter().enumerate() {
+            let len = path.len();
+            path.push_str(&format!("[{index}]"));
+            if value_path_at(element, offset, path) {
+                return true;
+            }
+            path.truncate(len);
+        }
+    }
+    value.span().contains(&offset)
+}
+
+/// Renders the source line of `span` with its line number and a caret under the span, like
+/// compiler messages:
+///
+/// ```text
+///    |
+/// 12 |     ["UART", 0x1000_0000, "x", 0x1000, 0x1],
+///    |                           ^^^
+/// ```
+///
+/// Only the first line of spans covering several lines is shown.
+pub(crate) fn render_snippet(source: &str, span: &Range<usize>) -> String {
+    let start = SourceLocation::from_offset(source, span.start);
+    let line_start = source[..span.start.min(source.len())]
+        .rfind('\n')
+        .map_or(0, |newline| newline + 1);
+    let line = source[line_start..].lines().next().unwrap_or("");
+    // Keep tabs in the padding so that the caret lines up with the source line.
+    let padding: String = line
+        .chars()
+        .take(start.column - 1)
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    let width = source
+        .get(span.clone())
+        .map_or(0, |text| text.lines().next().unwrap_or("").chars().count())
+        .max(1);
+    let gutter = " ".repeat(start.line.to_string().len());
+    format!(
+        "{gutter} |\n{} | {line}\n{gutter} | {padding}{}",
+        start.line,
+        "^".repeat(width)
+    )
+}
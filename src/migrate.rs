@@ -0,0 +1,170 @@
+//! Migrating configuration files to the current schema, keeping their comments.
+//!
+//! [`migrate_toml`] applies the upgrade done by
+//! [`AxVMCrateConfig::from_toml_with_upgrade`] to the file itself with `toml_edit`, like
+//! [`format_toml`], instead of writing the upgraded configuration from scratch, so comments and
+//! the values the upgrade does not touch stay as written.
+use alloc::format;
+use alloc::string::{String, ToString};
+
+use toml_edit::{DocumentMut, Item, Key, Table, Value};
+
+use crate::writer::key_order;
+use crate::{
+    format_toml, AxVMCrateConfig, ConfigParseError, ConfigParseErrorKind, ConfigUpgrade,
+    CURRENT_CONFIG_VERSION,
+};
+
+/// The tables the fields of the legacy flat format are moved into.
+const SECTIONS: &[&str] = &["base", "kernel", "devices"];
+
+/// Rewrites a VM configuration file for [`CURRENT_CONFIG_VERSION`], keeping its comments.
+///
+/// Fields of the legacy flat format are moved into the `[base]`, `[kernel]` and `[devices]`
+/// tables, memory regions without a mapping type get the one they were loaded with, and
+/// `config_version` is set. The result is written in the layout of [`format_toml`]. Returns the
+/// migrated file together with a description of the upgrade; a file already written for the
+/// current version is returned formatted.
+///
+/// Fails if the file is not a valid configuration, or if the migrated file would describe a
+/// different configuration than the one loaded from the original.
+pub fn migrate_toml(source: &str) -> Result<(String, ConfigUpgrade), ConfigParseError> {
+    let (config, upgrade) = AxVMCrateConfig::from_toml_with_upgrade(source)?;
+    let mut doc: DocumentMut = source.parse().map_err(|err: toml_edit::TomlError| {
+        ConfigParseError::new(
+            ConfigParseErrorKind::Syntax,
+            err.message(),
+            source,
+            None,
+            err.span(),
+        )
+    })?;
+
+    let root = doc.as_table_mut();
+    let header = if upgrade.source_version == 0 {
+        let header = take_header(root);
+        split_sections(root);
+        fill_map_types(root, &config);
+        header
+    } else {
+        String::new()
+    };
+    set_version(root, header);
+
+    let migrated = format_toml(&doc.to_string())?;
+    // The migrated file must describe the VM the original file was loaded as.
+    let reparsed = AxVMCrateConfig::from_toml_with_upgrade(&migrated).map(|(config, _)| config);
+    if reparsed.as_ref() != Ok(&config) {
+        return Err(ConfigParseError::new(
+            ConfigParseErrorKind::Version,
+            format!(
+                "cannot migrate from config_version {}: the migrated file would describe a \
+                 different configuration",
+                upgrade.source_version
+            ),
+            source,
+            Some(""),
+            None,
+        ));
+    }
+    Ok((migrated, upgrade))
+}
+
+/// Takes the comments heading the file, i.e. those before the first key up to the last blank
+/// line, so that they stay at the top when the key is moved into a table. Comments right above
+/// the key stay with it.
+fn take_header(root: &mut Table) -> String {
+    let Some((mut key, Item::Value(_))) = root.iter_mut().next() else {
+        return String::new();
+    };
+    if key.get() == "config_version" {
+        return String::new();
+    }
+    let decor = key.leaf_decor_mut();
+    let prefix = decor
+        .prefix()
+        .and_then(|prefix| prefix.as_str())
+        .unwrap_or_default()
+        .to_string();
+    let Some(end) = prefix.rfind("\n\n") else {
+        return String::new();
+    };
+    decor.set_prefix(&prefix[end + 2..]);
+    prefix[..end + 2].to_string()
+}
+
+/// Moves the top-level fields of the legacy flat format into the `[base]`, `[kernel]` and
+/// `[devices]` tables, with their comments.
+fn split_sections(root: &mut Table) {
+    for section in SECTIONS {
+        let mut table = Table::new();
+        for key in key_order(section) {
+            if let Some((mut key, item)) = root.remove_entry(key) {
+                // The table header takes the place of blank lines before its first key
+                if table.is_empty() {
+                    let decor = key.leaf_decor_mut();
+                    let prefix = decor.prefix().and_then(|prefix| prefix.as_str());
+                    let prefix = prefix.unwrap_or_default().trim_start_matches('\n');
+                    decor.set_prefix(prefix.to_string());
+                }
+                table.insert_formatted(&key, item);
+            }
+        }
+        root.insert(section, Item::Table(table));
+    }
+}
+
+/// Adds the mapping type each memory region was loaded with to the regions written without one.
+fn fill_map_types(root: &mut Table, config: &AxVMCrateConfig) {
+    let Some(regions) = root
+        .get_mut("kernel")
+        .and_then(|kernel| kernel.get_mut("memory_regions"))
+    else {
+        return;
+    };
+    let map_types = config
+        .kernel
+        .memory_regions
+        .iter()
+        .map(|region| region.map_type.clone() as u8 as i64);
+
+    match regions {
+        Item::Value(Value::Array(rows)) => {
+            for (row, map_type) in rows.iter_mut().zip(map_types) {
+                match row {
+                    Value::Array(cells) if cells.len() == 3 => cells.push(map_type),
+                    Value::InlineTable(table) if !table.contains_key("map_type") => {
+                        table.insert("map_type", map_type.into());
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Item::ArrayOfTables(tables) => {
+            for (table, map_type) in tables.iter_mut().zip(map_types) {
+                if !table.contains_key("map_type") {
+                    table.insert("map_type", toml_edit::value(map_type));
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Sets `config_version` to [`CURRENT_CONFIG_VERSION`], keeping the comments around an existing
+/// key. A new key is preceded by `header`.
+fn set_version(root: &mut Table, header: String) {
+    let version = i64::from(CURRENT_CONFIG_VERSION);
+    match root.get_mut("config_version") {
+        Some(Item::Value(value)) => {
+            let decor = value.decor().clone();
+            *value = version.into();
+            *value.decor_mut() = decor;
+        }
+        _ => {
+            let mut key = Key::new("config_version");
+            key.leaf_decor_mut().set_prefix(header);
+            root.insert_formatted(&key, toml_edit::value(version));
+        }
+    }
+}
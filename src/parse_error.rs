@@ -0,0 +1,144 @@
+//! Errors of parsing configuration files, located in the source.
+//!
+//! `toml` reports where a file is wrong as a byte range. [`ConfigParseError`] keeps that range
+//! and adds what a user needs to fix the file: the line and column, the key path of the
+//! offending value and the source line with a caret under it. It converts into
+//! [`AxError`](axerrno::AxError) for callers that only deal in `AxResult`.
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use core::fmt::{Display, Formatter};
+use core::ops::Range;
+
+use crate::location::{key_path_at, render_snippet};
+use crate::{Diagnostic, SourceLocation};
+
+/// What kind of problem made a configuration unparsable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigParseErrorKind {
+    /// The file is not valid TOML.
+    Syntax,
+    /// The file is valid TOML, but a field is missing, unknown or has the wrong type or value.
+    Schema,
+    /// The `config_version` is not supported, or the configuration cannot be upgraded to the
+    /// current schema version.
+    Version,
+}
+
+/// Error returned when a configuration file cannot be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigParseError {
+    /// What kind of problem it is.
+    pub kind: ConfigParseErrorKind,
+    /// A description of the problem, as reported by the TOML parser or the schema.
+    pub message: String,
+    /// The TOML key path of the offending value, e.g. `devices.passthrough_devices[2]`. Empty if
+    /// the problem is not about a single value, e.g. a syntax error between tables.
+    pub path: String,
+    /// The line and column where the offending text starts, if known.
+    pub location: Option<SourceLocation>,
+    /// The offending text, if known. Boxed to keep `Result`s carrying the error small.
+    pub excerpt: Option<Box<SourceExcerpt>>,
+}
+
+/// The offending text of a [`ConfigParseError`] in the source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceExcerpt {
+    /// The byte range of the offending text in the source.
+    pub span: Range<usize>,
+    /// The offending source line with a caret under the offending text.
+    pub snippet: String,
+}
+
+impl ConfigParseError {
+    /// Creates an error about the text at `span` of `source`, deriving the location, the
+    /// snippet and, if `path` is `None`, the key path from the span.
+    pub(crate) fn new(
+        kind: ConfigParseErrorKind,
+        message: impl Into<String>,
+        source: &str,
+        path: Option<&str>,
+        span: Option<Range<usize>>,
+    ) -> Self {
+        let path = match (path, &span) {
+            (Some(path), _) => path.to_string(),
+            (None, Some(span)) => key_path_at(source, span.start).unwrap_or_default(),
+            (None, None) => String::new(),
+        };
+        Self {
+            kind,
+            message: message.into(),
+            path,
+            location: span
+                .as_ref()
+                .map(|span| SourceLocation::from_offset(source, span.start)),
+            excerpt: span.map(|span| {
+                Box::new(SourceExcerpt {
+                    snippet: render_snippet(source, &span),
+                    span,
+                })
+            }),
+        }
+    }
+
+    /// The byte range of the offending text in the source, if known.
+    pub fn span(&self) -> Option<Range<usize>> {
+        self.excerpt.as_ref().map(|excerpt| excerpt.span.clone())
+    }
+
+    /// Creates an error from an error of the `toml` parser or deserializer.
+    pub(crate) fn from_toml(
+        kind: ConfigParseErrorKind,
+        err: &toml::de::Error,
+        source: &str,
+    ) -> Self {
+        Self::new(kind, err.message(), source, None, err.span())
+    }
+
+    /// A stable code for the kind of error, matching the codes of [`Diagnostic`]s: `C001` for
+    /// syntax errors, `C002` for schema errors and `C003` for version errors.
+    pub fn code(&self) -> &'static str {
+        match self.kind {
+            ConfigParseErrorKind::Syntax => "C001",
+            ConfigParseErrorKind::Schema => "C002",
+            ConfigParseErrorKind::Version => "C003",
+        }
+    }
+
+    /// Converts the error into an error [`Diagnostic`], for tools that report parse and
+    /// validation problems together.
+    pub fn to_diagnostic(&self) -> Diagnostic {
+        Diagnostic::error(self.code(), self.path.clone(), self.message.clone())
+    }
+}
+
+impl Display for ConfigParseError {
+    /// Formats the error as `<key path> at line <l>, column <c>: <message>`, followed by the
+    /// snippet on the next lines if the location is known.
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        if !self.path.is_empty() {
+            write!(f, "{}", self.path)?;
+        }
+        if let Some(location) = &self.location {
+            if !self.path.is_empty() {
+                write!(f, " at ")?;
+            }
+            write!(f, "line {}, column {}", location.line, location.column)?;
+        }
+        if !self.path.is_empty() || self.location.is_some() {
+            write!(f, ": ")?;
+        }
+        write!(f, "{}", self.message)?;
+        if let Some(excerpt) = &self.excerpt {
+            write!(f, "\n{}", excerpt.snippet)?;
+        }
+        Ok(())
+    }
+}
+
+impl core::error::Error for ConfigParseError {}
+
+impl From<ConfigParseError> for axerrno::AxError {
+    fn from(err: ConfigParseError) -> Self {
+        axerrno::ax_err_type!(InvalidInput, err)
+    }
+}
@@ -0,0 +1,315 @@
+//! Description of the host platform, to check VM configurations against real hardware.
+//!
+//! A VM configuration refers to host resources: identity-mapped RAM, the host physical ranges
+//! and IRQs of passthrough devices, and physical CPUs. A platform description lists what the
+//! board actually has, so that [`AxVMCrateConfig::validate_against_platform`] can flag
+//! resources the host does not provide or reserves for the hypervisor.
+//!
+//! Memory ranges and devices are written as arrays or as tables with named keys, like memory
+//! regions and devices of a VM configuration:
+//!
+//! ```toml
+//! name = "qemu-virt-riscv64"
+//! cpu_num = 4
+//! cpu_ids = [0, 1, 2, 3]
+//! # [base, size, name]
+//! memory = [[0x8000_0000, "2G"]]
+//! reserved = [[0x8000_0000, "2M", "opensbi"]]
+//! # [name, base, size, irqs]
+//! devices = [["uart0", 0x1000_0000, 0x100, [10]]]
+//! ```
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::validate::{covered_by, ranges_overlap};
+use crate::{
+    locate_key, AxSystemConfig, AxVMCrateConfig, ConfigParseError, ConfigParseErrorKind,
+    Diagnostic, VmMemFlags, VmMemMappingType,
+};
+
+/// A range of host physical memory.
+#[derive(Debug, Default, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PlatformMemRange {
+    /// The start address of the range.
+    pub base: usize,
+    /// The size of the range.
+    #[serde(deserialize_with = "crate::units::deserialize_size")]
+    pub size: usize,
+    /// What the range is used for, e.g. `"opensbi"`. Optional.
+    #[serde(default)]
+    pub name: String,
+}
+
+impl PlatformMemRange {
+    /// The end address (exclusive) of the range.
+    pub fn end(&self) -> usize {
+        self.base.saturating_add(self.size)
+    }
+}
+
+/// An MMIO device of the host platform.
+#[derive(Debug, Default, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PlatformDevice {
+    /// The name of the device.
+    pub name: String,
+    /// The host physical base address of the MMIO range.
+    pub base: usize,
+    /// The size of the MMIO range.
+    #[serde(deserialize_with = "crate::units::deserialize_size")]
+    pub size: usize,
+    /// The IRQ numbers of the device. Empty if unknown, in which case IRQs are not checked.
+    #[serde(default)]
+    pub irqs: Vec<usize>,
+}
+
+impl PlatformDevice {
+    /// The end address (exclusive) of the MMIO range.
+    pub fn end(&self) -> usize {
+        self.base.saturating_add(self.size)
+    }
+}
+
+/// The description of a host platform.
+#[derive(Debug, Default, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AxPlatformConfig {
+    /// The name of the platform.
+    #[serde(default)]
+    pub name: String,
+    /// The number of physical CPUs.
+    pub cpu_num: usize,
+    /// The hardware ids of the physical CPUs, e.g. `MPIDR_EL1` values on aarch64 or hart ids on
+    /// riscv64. If `None`, the CPUs are numbered from `0` to `cpu_num - 1`.
+    #[serde(default)]
+    pub cpu_ids: Option<Vec<usize>>,
+    /// The physical RAM ranges.
+    pub memory: Vec<PlatformMemRange>,
+    /// Ranges of physical memory or MMIO reserved for the hypervisor or firmware, which must not
+    /// be given to VMs.
+    #[serde(default)]
+    pub reserved: Vec<PlatformMemRange>,
+    /// The MMIO devices.
+    #[serde(default)]
+    pub devices: Vec<PlatformDevice>,
+}
+
+impl AxPlatformConfig {
+    /// Deserialize the toml string to `AxPlatformConfig`.
+    ///
+    /// Errors tell where the file is wrong, like those of [`AxVMCrateConfig::from_toml_with_upgrade`].
+    pub fn from_toml(raw_cfg_str: &str) -> Result<Self, ConfigParseError> {
+        toml::from_str::<toml::Table>(raw_cfg_str).map_err(|err| {
+            ConfigParseError::from_toml(ConfigParseErrorKind::Syntax, &err, raw_cfg_str)
+        })?;
+        let platform: Self = toml::from_str(raw_cfg_str).map_err(|err| {
+            ConfigParseError::from_toml(ConfigParseErrorKind::Schema, &err, raw_cfg_str)
+        })?;
+        if let Some(ids) = &platform.cpu_ids {
+            if ids.len() != platform.cpu_num {
+                return Err(ConfigParseError::new(
+                    ConfigParseErrorKind::Schema,
+                    format!(
+                        "cpu_ids has {} entries but cpu_num is {}",
+                        ids.len(),
+                        platform.cpu_num
+                    ),
+                    raw_cfg_str,
+                    Some("cpu_ids"),
+                    locate_key(raw_cfg_str, "cpu_ids"),
+                ));
+            }
+        }
+        Ok(platform)
+    }
+
+    /// Returns true if `id` is the hardware id of a physical CPU.
+    fn has_cpu_id(&self, id: usize) -> bool {
+        match &self.cpu_ids {
+            Some(ids) => ids.contains(&id),
+            None => id < self.cpu_num,
+        }
+    }
+
+    /// The mask of all physical CPUs, where bit `i` is the `i`-th CPU.
+    fn cpu_mask(&self) -> usize {
+        match self.cpu_num {
+            n if n >= usize::BITS as usize => usize::MAX,
+            n => (1 << n) - 1,
+        }
+    }
+
+    /// The physical RAM ranges as sorted `(start, end)` pairs.
+    fn ram(&self) -> Vec<(usize, usize)> {
+        let mut ram: Vec<_> = self.memory.iter().map(|r| (r.base, r.end())).collect();
+        ram.sort_unstable();
+        ram
+    }
+
+    /// Reports a reserved range overlapping `[start, end)`.
+    fn check_reserved(&self, path: &str, start: usize, end: usize, diags: &mut Vec<Diagnostic>) {
+        for range in &self.reserved {
+            if ranges_overlap(start, end, range.base, range.end()) {
+                let message = format!(
+                    "host range [{start:#x}, {end:#x}) overlaps reserved range '{}' [{:#x}, {:#x})",
+                    range.name,
+                    range.base,
+                    range.end()
+                );
+                diags.push(Diagnostic::error("C701", path, message));
+            }
+        }
+    }
+}
+
+impl AxVMCrateConfig {
+    /// Checks that the host resources used by the VM exist on `platform` and are not reserved.
+    ///
+    /// The checks cover physical CPU ids and masks, identity-mapped memory regions, which must
+    /// be host RAM (or a device's MMIO range if mapped as device memory), and passthrough
+    /// devices, whose host range and IRQ must belong to a platform device.
+    pub fn validate_against_platform(&self, platform: &AxPlatformConfig) -> Vec<Diagnostic> {
+        let mut diags = Vec::new();
+        validate_cpus(self, platform, &mut diags);
+        validate_identical_memory(self, platform, &mut diags);
+        validate_passthrough_devices(self, platform, &mut diags);
+        diags
+    }
+}
+
+impl AxSystemConfig {
+    /// Checks every VM with [`AxVMCrateConfig::validate_against_platform`], prefixing the paths
+    /// of the diagnostics with `vm[i].`.
+    pub fn validate_against_platform(&self, platform: &AxPlatformConfig) -> Vec<Diagnostic> {
+        let mut diags = Vec::new();
+        for (i, vm) in self.vms.iter().enumerate() {
+            diags.extend(
+                vm.validate_against_platform(platform)
+                    .into_iter()
+                    .map(|diag| Diagnostic {
+                        path: format!("vm[{i}].{}", diag.path),
+                        ..diag
+                    }),
+            );
+        }
+        diags
+    }
+}
+
+fn validate_cpus(
+    config: &AxVMCrateConfig,
+    platform: &AxPlatformConfig,
+    diags: &mut Vec<Diagnostic>,
+) {
+    let base = &config.base;
+    if base.cpu_num > platform.cpu_num {
+        diags.push(Diagnostic::warning(
+            "C702",
+            "base.cpu_num",
+            format!(
+                "VM has {} vCPUs but the platform only has {} physical CPUs",
+                base.cpu_num, platform.cpu_num
+            ),
+        ));
+    }
+    for (i, id) in base.phys_cpu_ids.iter().flatten().enumerate() {
+        if !platform.has_cpu_id(*id) {
+            diags.push(Diagnostic::error(
+                "C703",
+                format!("base.phys_cpu_ids[{i}]"),
+                format!("physical CPU id {id:#x} does not exist on the platform"),
+            ));
+        }
+    }
+    for (i, mask) in base.phys_cpu_sets.iter().flatten().enumerate() {
+        let missing = mask & !platform.cpu_mask();
+        if missing != 0 {
+            diags.push(Diagnostic::error(
+                "C704",
+                format!("base.phys_cpu_sets[{i}]"),
+                format!(
+                    "selects physical CPUs {missing:#x} but the platform only has {} CPUs",
+                    platform.cpu_num
+                ),
+            ));
+        }
+    }
+}
+
+fn validate_identical_memory(
+    config: &AxVMCrateConfig,
+    platform: &AxPlatformConfig,
+    diags: &mut Vec<Diagnostic>,
+) {
+    let ram = platform.ram();
+    for (i, region) in config.kernel.memory_regions.iter().enumerate() {
+        if region.map_type != VmMemMappingType::MapIdentical || region.size == 0 {
+            continue;
+        }
+        let path = format!("kernel.memory_regions[{i}]");
+        let (start, end) = (region.gpa, region.gpa.saturating_add(region.size));
+        let backed = covered_by(&ram, start, end)
+            || region.flags.contains(VmMemFlags::DEVICE)
+                && platform
+                    .devices
+                    .iter()
+                    .any(|dev| dev.base <= start && end <= dev.end());
+        if !backed {
+            diags.push(Diagnostic::error(
+                "C705",
+                &path,
+                format!(
+                    "identity-mapped range [{start:#x}, {end:#x}) is not {} of the platform",
+                    if region.flags.contains(VmMemFlags::DEVICE) {
+                        "RAM or a device MMIO range"
+                    } else {
+                        "RAM"
+                    }
+                ),
+            ));
+        }
+        platform.check_reserved(&path, start, end, diags);
+    }
+}
+
+fn validate_passthrough_devices(
+    config: &AxVMCrateConfig,
+    platform: &AxPlatformConfig,
+    diags: &mut Vec<Diagnostic>,
+) {
+    for (i, dev) in config.devices.passthrough_devices.iter().enumerate() {
+        if dev.length == 0 {
+            continue;
+        }
+        let path = format!("devices.passthrough_devices[{i}]");
+        let (start, end) = (dev.base_hpa, dev.base_hpa.saturating_add(dev.length));
+        match platform
+            .devices
+            .iter()
+            .find(|host| host.base <= start && end <= host.end())
+        {
+            None => {
+                let message = format!(
+                    "host range [{start:#x}, {end:#x}) of '{}' is not inside the MMIO range of \
+                     any platform device",
+                    dev.name
+                );
+                diags.push(Diagnostic::error("C706", &path, message));
+            }
+            Some(host) if !host.irqs.is_empty() && !host.irqs.contains(&dev.irq_id) => {
+                let irqs: Vec<_> = host.irqs.iter().map(|irq| format!("{irq:#x}")).collect();
+                let message = format!(
+                    "IRQ {:#x} is not an interrupt of platform device '{}', which has IRQs {}",
+                    dev.irq_id,
+                    host.name,
+                    irqs.join(", ")
+                );
+                diags.push(Diagnostic::error("C707", &path, message));
+            }
+            Some(_) => {}
+        }
+        platform.check_reserved(&path, start, end, diags);
+    }
+}
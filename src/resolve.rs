@@ -0,0 +1,381 @@
+//! Configurations built on other configuration files.
+//!
+//! Per-board configurations usually share most of their content. A file can name a
+//! configuration it builds on with `extends = "base.toml"` and fragments to merge into it with
+//! `include = ["devices.toml"]`, and then only give what differs. [`resolve_config`] merges
+//! such a file with the files it names into a flat configuration, and
+//! [`AxVMCrateConfig::from_file`] reads a configuration file that way.
+//! [`AxSystemConfig::from_file`] does the same for the VMs of a system configuration.
+//!
+//! The files are merged in order: the file named by `extends`, then each file of `include`,
+//! then the file itself, each over the result of the previous ones. Merging a file:
+//! - merges tables key by key, recursively;
+//! - merges `devices.emu_devices` and `devices.passthrough_devices` by device name: a device
+//!   with the name of an inherited one replaces it in place, or, if written as a table, only
+//!   replaces the fields it gives, and other devices are appended;
+//! - replaces every other value, including other arrays such as `kernel.memory_regions`.
+use std::cell::Cell;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+
+use toml::{Table, Value};
+
+use crate::system::inline_vm_error;
+use crate::writer::key_order;
+use crate::{
+    locate_key, AxSystemConfig, AxVMCrateConfig, ConfigParseError, ConfigParseErrorKind,
+    ConfigUpgrade,
+};
+
+/// The key naming the configuration a file builds on.
+pub(crate) const EXTENDS_KEY: &str = "extends";
+/// The key listing the fragments merged into a file.
+pub(crate) const INCLUDE_KEY: &str = "include";
+
+/// Arrays merged by the name of their elements instead of replaced.
+const KEYED_ARRAYS: &[&str] = &["devices.emu_devices", "devices.passthrough_devices"];
+
+/// Error returned when a configuration file and the files it builds on cannot be merged.
+#[derive(Debug)]
+pub enum ConfigResolveError {
+    /// A file cannot be read.
+    Io {
+        /// The path of the file.
+        path: PathBuf,
+        /// The error reading it.
+        error: io::Error,
+    },
+    /// A file is not valid TOML, its `extends` or `include` is malformed, or the merged
+    /// configuration is not valid. Errors of a merged configuration have no location, as it is
+    /// not written in any file.
+    Parse {
+        /// The path of the file.
+        path: PathBuf,
+        /// The error parsing it.
+        error: ConfigParseError,
+    },
+    /// Files extend or include each other in a cycle, listed from the first file of the cycle
+    /// back to it.
+    Cycle(Vec<PathBuf>),
+}
+
+impl Display for ConfigResolveError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigResolveError::Io { path, error } => {
+                write!(f, "failed to read file '{}': {error}", path.display())
+            }
+            ConfigResolveError::Parse { path, error } => write!(f, "'{}': {error}", path.display()),
+            ConfigResolveError::Cycle(paths) => {
+                let paths: Vec<String> = paths
+                    .iter()
+                    .map(|path| format!("'{}'", path.display()))
+                    .collect();
+                write!(
+                    f,
+                    "files extend or include each other in a cycle: {}",
+                    paths.join(" -> ")
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigResolveError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigResolveError::Io { error, .. } => Some(error),
+            ConfigResolveError::Parse { error, .. } => Some(error),
+            ConfigResolveError::Cycle(_) => None,
+        }
+    }
+}
+
+/// Merges a configuration file with the files named by its `extends` and `include`, and
+/// returns the merged configuration as TOML.
+///
+/// `source` is the content of the file at `path`; the paths it names are relative to the
+/// directory of `path`, and may extend and include other files in turn. A file without
+/// `extends` and `include` is returned as it is.
+pub fn resolve_config(source: &str, path: &Path) -> Result<String, ConfigResolveError> {
+    let table = parse_table(source, path)?;
+    if !table.contains_key(EXTENDS_KEY) && !table.contains_key(INCLUDE_KEY) {
+        return Ok(source.to_string());
+    }
+    let mut stack: Vec<PathBuf> = path.canonicalize().into_iter().collect();
+    let merged = resolve_table(table, source, path, &mut stack)?;
+    Ok(toml::to_string(&merged).expect("a TOML table can be written back"))
+}
+
+impl AxVMCrateConfig {
+    /// Reads a configuration file, merged with the files named by its `extends` and
+    /// `include`, see [`resolve_config`].
+    ///
+    /// Like [`AxVMCrateConfig::from_toml`], configurations written for older schema versions
+    /// are upgraded to the current version and the upgrade is logged.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, ConfigResolveError> {
+        let (config, upgrade) = Self::from_file_with_upgrade(path)?;
+        for warning in &upgrade.warnings {
+            warn!(
+                "Config upgraded from version {}: {}",
+                upgrade.source_version, warning
+            );
+        }
+        Ok(config)
+    }
+
+    /// Reads a configuration file like [`AxVMCrateConfig::from_file`], and also returns what
+    /// was done to upgrade it, see [`AxVMCrateConfig::from_toml_with_upgrade`].
+    pub fn from_file_with_upgrade(
+        path: impl AsRef<Path>,
+    ) -> Result<(Self, ConfigUpgrade), ConfigResolveError> {
+        let path = path.as_ref();
+        let source = fs::read_to_string(path).map_err(|error| ConfigResolveError::Io {
+            path: path.to_path_buf(),
+            error,
+        })?;
+        let resolved = resolve_config(&source, path)?;
+        Self::from_resolved_with_upgrade(&resolved, &source, path)
+    }
+
+    /// Parses the configuration `resolved` from the content `source` of the file at `path`.
+    /// Errors are only located if `resolved` is the file as written.
+    fn from_resolved_with_upgrade(
+        resolved: &str,
+        source: &str,
+        path: &Path,
+    ) -> Result<(Self, ConfigUpgrade), ConfigResolveError> {
+        Self::from_toml_with_upgrade(resolved).map_err(|mut error| {
+            if resolved != source {
+                error.location = None;
+                error.excerpt = None;
+            }
+            ConfigResolveError::Parse {
+                path: path.to_path_buf(),
+                error,
+            }
+        })
+    }
+}
+
+impl AxSystemConfig {
+    /// Reads a system configuration file. Its inline `[[vm]]` tables and the VM configuration
+    /// files listed in `vm_configs` are merged with the files they extend or include, see
+    /// [`resolve_config`]; paths are relative to the directory of the file naming them.
+    ///
+    /// Errors of the system configuration file and its inline VMs are located in it like those
+    /// of [`AxSystemConfig::from_toml`], and errors of a VM configuration file are reported for
+    /// that file.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, ConfigResolveError> {
+        let path = path.as_ref();
+        let source = fs::read_to_string(path).map_err(|error| ConfigResolveError::Io {
+            path: path.to_path_buf(),
+            error,
+        })?;
+        let dir = path.parent().unwrap_or(Path::new(""));
+
+        // Errors of other files are kept aside, as the system config only takes errors located
+        // in it.
+        let failure = Cell::new(None);
+        let fail = |err: ConfigResolveError| {
+            let error = ConfigParseError::new(
+                ConfigParseErrorKind::Schema,
+                err.to_string(),
+                "",
+                Some(""),
+                None,
+            );
+            failure.set(Some(err));
+            error
+        };
+        let system = Self::from_toml_with(
+            &source,
+            |index, table| {
+                let mut stack: Vec<PathBuf> = path.canonicalize().into_iter().collect();
+                resolve_table(table, "", path, &mut stack).map_err(|err| match err {
+                    ConfigResolveError::Parse { path: file, error } if file == path => {
+                        inline_vm_error(&source, index, error)
+                    }
+                    err => fail(err),
+                })
+            },
+            |vm_path| {
+                let vm_path = dir.join(vm_path);
+                fs::read_to_string(&vm_path)
+                    .map_err(|error| ConfigResolveError::Io {
+                        path: vm_path.clone(),
+                        error,
+                    })
+                    .and_then(|vm_source| {
+                        let resolved = resolve_config(&vm_source, &vm_path)?;
+                        AxVMCrateConfig::from_resolved_with_upgrade(
+                            &resolved, &vm_source, &vm_path,
+                        )?;
+                        Ok(resolved)
+                    })
+                    .map_err(|err| {
+                        fail(err);
+                        axerrno::AxError::InvalidData
+                    })
+            },
+        );
+        match (system, failure.into_inner()) {
+            (Ok(system), _) => Ok(system),
+            (Err(_), Some(err)) => Err(err),
+            (Err(error), None) => Err(ConfigResolveError::Parse {
+                path: path.to_path_buf(),
+                error,
+            }),
+        }
+    }
+}
+
+/// Parses a file as a TOML table.
+fn parse_table(source: &str, path: &Path) -> Result<Table, ConfigResolveError> {
+    toml::from_str(source).map_err(|err| ConfigResolveError::Parse {
+        path: path.to_path_buf(),
+        error: ConfigParseError::from_toml(ConfigParseErrorKind::Syntax, &err, source),
+    })
+}
+
+/// Removes `extends` and `include` from the table of a file, and returns the paths they name
+/// in merge order.
+fn take_parents(table: &mut Table, source: &str) -> Result<Vec<String>, ConfigParseError> {
+    let error = |key: &str, message: &str| {
+        ConfigParseError::new(
+            ConfigParseErrorKind::Schema,
+            message,
+            source,
+            Some(key),
+            locate_key(source, key),
+        )
+    };
+    let mut parents = Vec::new();
+    match table.remove(EXTENDS_KEY) {
+        Some(Value::String(path)) => parents.push(path),
+        Some(_) => {
+            return Err(error(
+                EXTENDS_KEY,
+                "expected the path of a configuration file",
+            ))
+        }
+        None => {}
+    }
+    match table.remove(INCLUDE_KEY) {
+        Some(Value::Array(paths)) => {
+            for path in paths {
+                let Value::String(path) = path else {
+                    return Err(error(INCLUDE_KEY, "expected paths of configuration files"));
+                };
+                parents.push(path);
+            }
+        }
+        Some(_) => return Err(error(INCLUDE_KEY, "expected a list of paths")),
+        None => {}
+    }
+    Ok(parents)
+}
+
+/// Merges the table of the file at `path` over the files it names. `stack` holds the
+/// canonical paths of the files being merged, to detect cycles.
+fn resolve_table(
+    mut table: Table,
+    source: &str,
+    path: &Path,
+    stack: &mut Vec<PathBuf>,
+) -> Result<Table, ConfigResolveError> {
+    let parents = take_parents(&mut table, source).map_err(|error| ConfigResolveError::Parse {
+        path: path.to_path_buf(),
+        error,
+    })?;
+    let dir = path.parent().unwrap_or(Path::new(""));
+
+    let mut merged = Table::new();
+    for parent in parents {
+        let parent = dir.join(parent);
+        let io_error = |error| ConfigResolveError::Io {
+            path: parent.clone(),
+            error,
+        };
+        let canonical = parent.canonicalize().map_err(io_error)?;
+        if let Some(start) = stack.iter().position(|path| *path == canonical) {
+            let mut cycle = stack[start..].to_vec();
+            cycle.push(canonical);
+            return Err(ConfigResolveError::Cycle(cycle));
+        }
+        let parent_source = fs::read_to_string(&parent).map_err(io_error)?;
+        let parent_table = parse_table(&parent_source, &parent)?;
+        stack.push(canonical);
+        let parent_table = resolve_table(parent_table, &parent_source, &parent, stack)?;
+        stack.pop();
+        merge(&mut merged, parent_table, "");
+    }
+    merge(&mut merged, table, "");
+    Ok(merged)
+}
+
+/// Merges `overlay` over `base`, the table at `path`.
+fn merge(base: &mut Table, overlay: Table, path: &str) {
+    for (key, value) in overlay {
+        let child = if path.is_empty() {
+            key.clone()
+        } else {
+            format!("{path}.{key}")
+        };
+        let value = match (base.get_mut(&key), value) {
+            (Some(Value::Table(base)), Value::Table(overlay)) => {
+                merge(base, overlay, &child);
+                continue;
+            }
+            (Some(Value::Array(base)), Value::Array(overlay))
+                if KEYED_ARRAYS.contains(&child.as_str()) =>
+            {
+                merge_by_name(base, overlay, key_order(&child));
+                continue;
+            }
+            (_, value) => value,
+        };
+        base.insert(key, value);
+    }
+}
+
+/// The name of a device, written as a tuple or a table.
+fn device_name(device: &Value) -> Option<&str> {
+    match device {
+        Value::Array(tuple) => tuple.first().and_then(Value::as_str),
+        Value::Table(table) => table.get("name").and_then(Value::as_str),
+        _ => None,
+    }
+}
+
+/// Merges the devices of `overlay` over those of `base` by name. `fields` are the names of the
+/// fields of a device in the tuple form.
+fn merge_by_name(base: &mut Vec<Value>, overlay: Vec<Value>, fields: &[&str]) {
+    for device in overlay {
+        let found = device_name(&device)
+            .and_then(|name| base.iter().position(|old| device_name(old) == Some(name)));
+        let Some(i) = found else {
+            base.push(device);
+            continue;
+        };
+        match (&mut base[i], device) {
+            (old, Value::Table(fields_given)) => {
+                // Only the fields given are replaced, so turn a tuple into a table first
+                if let Value::Array(tuple) = old {
+                    let table = fields
+                        .iter()
+                        .map(|field| field.to_string())
+                        .zip(tuple.drain(..))
+                        .collect();
+                    *old = Value::Table(table);
+                }
+                match old {
+                    Value::Table(table) => merge(table, fields_given, ""),
+                    old => *old = Value::Table(fields_given),
+                }
+            }
+            (old, device) => *old = device,
+        }
+    }
+}
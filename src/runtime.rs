@@ -0,0 +1,190 @@
+//! The runtime VM configuration, resolved from [`AxVMCrateConfig`].
+//!
+//! [`AxVMCrateConfig`] mirrors the toml file, so most of its fields are optional and may be
+//! inconsistent. [`AxVMConfig`] is what a VM monitor actually needs: the configuration has been
+//! validated, and every default has been filled in, so consumers do not have to re-implement the
+//! defaulting rules.
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{Display, Formatter};
+
+use axerrno::AxResult;
+
+use crate::{
+    AxVMCrateConfig, Diagnostic, EmulatedDeviceConfig, ImageLocation, PassThroughDeviceConfig,
+    VMInterruptMode, VMType, VmMemConfig,
+};
+
+/// A virtual CPU of the VM and the physical CPU(s) it is bound to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AxVCpuConfig {
+    /// The id of the vCPU, from `0` to `cpu_num - 1`.
+    pub id: usize,
+    /// The physical id of the vCPU, taken from `base.phys_cpu_ids`, or the vCPU id if not given.
+    pub phys_id: usize,
+    /// The mask of physical CPUs the vCPU may run on, taken from `base.phys_cpu_sets`. `None`
+    /// if the vCPU is not pinned.
+    pub phys_cpu_set: Option<usize>,
+}
+
+/// An image and the guest physical address it is loaded at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AxVMImage {
+    /// The path of the image, inside the rootfs or on the host depending on
+    /// [`AxVMImageConfig::location`].
+    pub path: String,
+    /// The guest physical address the image is loaded at.
+    pub load_addr: usize,
+}
+
+/// The images of the VM.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AxVMImageConfig {
+    /// Where the images are read from.
+    pub location: ImageLocation,
+    /// The kernel image.
+    pub kernel: AxVMImage,
+    /// The BIOS image, if any.
+    pub bios: Option<AxVMImage>,
+    /// The device tree blob, if any.
+    pub dtb: Option<AxVMImage>,
+    /// The ramdisk image, if any.
+    pub ramdisk: Option<AxVMImage>,
+}
+
+/// The validated runtime configuration of a VM, with all defaults resolved.
+///
+/// Created from an [`AxVMCrateConfig`] with [`TryFrom`], which fails if the configuration has
+/// validation errors.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AxVMConfig {
+    /// VM ID.
+    pub id: usize,
+    /// VM name.
+    pub name: String,
+    /// VM type.
+    pub vm_type: VMType,
+    /// The vCPUs of the VM, ordered by id.
+    pub vcpus: Vec<AxVCpuConfig>,
+    /// The entry point of the VM.
+    pub entry_point: usize,
+    /// The images of the VM.
+    pub images: AxVMImageConfig,
+    /// The command line of the kernel, if any.
+    pub cmdline: Option<String>,
+    /// The path of the disk image, if any.
+    pub disk_path: Option<String>,
+    /// The guest memory regions.
+    pub memory_regions: Vec<VmMemConfig>,
+    /// The emulated devices.
+    pub emu_devices: Vec<EmulatedDeviceConfig>,
+    /// The passthrough devices.
+    pub passthrough_devices: Vec<PassThroughDeviceConfig>,
+    /// How the VM handles interrupts.
+    pub interrupt_mode: VMInterruptMode,
+    /// Groups of host devices that are not passed through.
+    pub excluded_devices: Vec<Vec<String>>,
+}
+
+/// Error returned when converting an [`AxVMCrateConfig`] with validation errors into an
+/// [`AxVMConfig`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AxVMConfigError {
+    /// The diagnostics of the configuration, containing at least one error.
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl AxVMConfigError {
+    /// Returns the diagnostics with error severity.
+    pub fn errors(&self) -> impl Iterator<Item = &Diagnostic> {
+        self.diagnostics.iter().filter(|diag| diag.is_error())
+    }
+}
+
+impl Display for AxVMConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "invalid VM config: ")?;
+        for (i, diag) in self.errors().enumerate() {
+            if i > 0 {
+                write!(f, "; ")?;
+            }
+            write!(f, "{}: {}", diag.path, diag.message)?;
+        }
+        Ok(())
+    }
+}
+
+impl core::error::Error for AxVMConfigError {}
+
+impl From<AxVMConfigError> for axerrno::AxError {
+    fn from(err: AxVMConfigError) -> Self {
+        axerrno::ax_err_type!(InvalidInput, err)
+    }
+}
+
+/// Pairs an optional image path with its load address. Validation guarantees that a path
+/// always has a load address, an address without a path is ignored.
+fn image(path: Option<String>, load_addr: Option<usize>) -> Option<AxVMImage> {
+    Some(AxVMImage {
+        path: path?,
+        load_addr: load_addr?,
+    })
+}
+
+impl TryFrom<AxVMCrateConfig> for AxVMConfig {
+    type Error = AxVMConfigError;
+
+    fn try_from(config: AxVMCrateConfig) -> Result<Self, Self::Error> {
+        let diagnostics = config.validate();
+        if diagnostics.iter().any(Diagnostic::is_error) {
+            return Err(AxVMConfigError { diagnostics });
+        }
+
+        let AxVMCrateConfig {
+            base,
+            kernel,
+            devices,
+            ..
+        } = config;
+
+        let vcpus = (0..base.cpu_num)
+            .map(|id| AxVCpuConfig {
+                id,
+                phys_id: base.phys_cpu_ids.as_ref().map_or(id, |ids| ids[id]),
+                phys_cpu_set: base.phys_cpu_sets.as_ref().map(|sets| sets[id]),
+            })
+            .collect();
+
+        Ok(AxVMConfig {
+            id: base.id,
+            name: base.name,
+            vm_type: base.vm_type,
+            vcpus,
+            entry_point: kernel.entry_point,
+            images: AxVMImageConfig {
+                location: kernel.image_location,
+                kernel: AxVMImage {
+                    path: kernel.kernel_path,
+                    load_addr: kernel.kernel_load_addr,
+                },
+                bios: image(kernel.bios_path, kernel.bios_load_addr),
+                dtb: image(kernel.dtb_path, kernel.dtb_load_addr),
+                ramdisk: image(kernel.ramdisk_path, kernel.ramdisk_load_addr),
+            },
+            cmdline: kernel.cmdline,
+            disk_path: kernel.disk_path,
+            memory_regions: kernel.memory_regions,
+            emu_devices: devices.emu_devices,
+            passthrough_devices: devices.passthrough_devices,
+            interrupt_mode: devices.interrupt_mode,
+            excluded_devices: devices.excluded_devices,
+        })
+    }
+}
+
+impl AxVMConfig {
+    /// Deserialize the toml string to `AxVMCrateConfig` and convert it to `AxVMConfig`.
+    pub fn from_toml(raw_cfg_str: &str) -> AxResult<Self> {
+        Ok(AxVMCrateConfig::from_toml(raw_cfg_str)?.try_into()?)
+    }
+}
@@ -0,0 +1,372 @@
+//! System configuration: all VMs that run together on one board.
+//!
+//! Each [`AxVMCrateConfig`] is valid on its own, but VMs sharing a board also share its
+//! resources. A system configuration lists the VMs, either inline as `[[vm]]` tables or as
+//! paths of per-VM files in `vm_configs`, and [`AxSystemConfig::validate`] checks that they do
+//! not claim the same ids, names, host memory, passthrough devices or physical CPUs.
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use axerrno::AxResult;
+
+use crate::validate::ranges_overlap;
+use crate::{
+    locate_key, AxVMCrateConfig, ConfigParseError, ConfigParseErrorKind, Diagnostic, VmMemFlags,
+    VmMemMappingType,
+};
+
+/// The layout of a system configuration file.
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SystemConfigFile {
+    /// VM configurations written inline as `[[vm]]` tables.
+    #[serde(default)]
+    vm: Vec<toml::Table>,
+    /// Paths of per-VM configuration files.
+    #[serde(default)]
+    vm_configs: Vec<String>,
+}
+
+/// Moves an error of the inline VM at `vm[index]` into the system configuration `source`,
+/// prefixing its key path and locating it there.
+pub(crate) fn inline_vm_error(
+    source: &str,
+    index: usize,
+    err: ConfigParseError,
+) -> ConfigParseError {
+    let path = if err.path.is_empty() {
+        format!("vm[{index}]")
+    } else {
+        format!("vm[{index}].{}", err.path)
+    };
+    ConfigParseError::new(
+        err.kind,
+        err.message,
+        source,
+        Some(&path),
+        locate_key(source, &path),
+    )
+}
+
+/// Rejects an inline VM that extends or includes other files, which needs
+/// `AxSystemConfig::from_file` to read them.
+fn without_parents(
+    source: &str,
+    index: usize,
+    table: toml::Table,
+) -> Result<toml::Table, ConfigParseError> {
+    match ["extends", "include"]
+        .into_iter()
+        .find(|key| table.contains_key(*key))
+    {
+        Some(key) => {
+            let message =
+                format!("{key} is only supported when reading the system config from a file");
+            let err =
+                ConfigParseError::new(ConfigParseErrorKind::Schema, message, "", Some(key), None);
+            Err(inline_vm_error(source, index, err))
+        }
+        None => Ok(table),
+    }
+}
+
+/// The configurations of all VMs running on one board.
+#[derive(Debug, Default, Clone)]
+pub struct AxSystemConfig {
+    /// The VM configurations. Inline `[[vm]]` tables come first, followed by the files listed in
+    /// `vm_configs`, each in the order they are written.
+    pub vms: Vec<AxVMCrateConfig>,
+}
+
+impl AxSystemConfig {
+    /// Deserialize a system configuration from a toml string.
+    ///
+    /// `load` is called with each path listed in `vm_configs` and returns the content of that
+    /// VM configuration file. Inline `[[vm]]` tables and files are upgraded to the current
+    /// schema version like single VM configurations.
+    ///
+    /// Errors are located in the system configuration: errors of an inline VM point at the
+    /// offending value of its `[[vm]]` table, and errors of a VM configuration file point at its
+    /// entry in `vm_configs`, with the location in that file in the message.
+    pub fn from_toml(
+        raw_cfg_str: &str,
+        load: impl FnMut(&str) -> AxResult<String>,
+    ) -> Result<Self, ConfigParseError> {
+        Self::from_toml_with(
+            raw_cfg_str,
+            |index, table| without_parents(raw_cfg_str, index, table),
+            load,
+        )
+    }
+
+    /// Deserialize a system configuration like [`AxSystemConfig::from_toml`], with `resolve`
+    /// called with the index and the table of each inline `[[vm]]` and returning the table to
+    /// read the VM from.
+    pub(crate) fn from_toml_with(
+        raw_cfg_str: &str,
+        mut resolve: impl FnMut(usize, toml::Table) -> Result<toml::Table, ConfigParseError>,
+        mut load: impl FnMut(&str) -> AxResult<String>,
+    ) -> Result<Self, ConfigParseError> {
+        toml::from_str::<toml::Table>(raw_cfg_str).map_err(|err| {
+            ConfigParseError::from_toml(ConfigParseErrorKind::Syntax, &err, raw_cfg_str)
+        })?;
+        let file: SystemConfigFile = toml::from_str(raw_cfg_str).map_err(|err| {
+            ConfigParseError::from_toml(ConfigParseErrorKind::Schema, &err, raw_cfg_str)
+        })?;
+
+        let mut vms = Vec::with_capacity(file.vm.len() + file.vm_configs.len());
+        for (index, table) in file.vm.into_iter().enumerate() {
+            let raw_vm = toml::to_string(&resolve(index, table)?).map_err(|err| {
+                ConfigParseError::new(
+                    ConfigParseErrorKind::Schema,
+                    err.to_string(),
+                    raw_cfg_str,
+                    Some(&format!("vm[{index}]")),
+                    None,
+                )
+            })?;
+            match AxVMCrateConfig::from_toml_with_upgrade(&raw_vm) {
+                Ok((vm, _)) => vms.push(vm),
+                Err(err) => return Err(inline_vm_error(raw_cfg_str, index, err)),
+            }
+        }
+        for (index, path) in file.vm_configs.iter().enumerate() {
+            let entry = format!("vm_configs[{index}]");
+            let raw_vm = load(path).map_err(|err| {
+                ConfigParseError::new(
+                    ConfigParseErrorKind::Schema,
+                    format!("failed to load '{path}': {err}"),
+                    raw_cfg_str,
+                    Some(&entry),
+                    locate_key(raw_cfg_str, &entry),
+                )
+            })?;
+            match AxVMCrateConfig::from_toml_with_upgrade(&raw_vm) {
+                Ok((vm, _)) => vms.push(vm),
+                Err(mut err) => {
+                    // The location in the VM file is only described, the snippet would be
+                    // taken for one of the system configuration.
+                    err.excerpt = None;
+                    return Err(ConfigParseError::new(
+                        err.kind,
+                        format!("in '{path}': {err}"),
+                        raw_cfg_str,
+                        Some(&entry),
+                        locate_key(raw_cfg_str, &entry),
+                    ));
+                }
+            }
+        }
+        Ok(Self { vms })
+    }
+
+    /// Checks each VM with [`AxVMCrateConfig::validate`], and the VMs against each other.
+    ///
+    /// Diagnostics of a single VM have their path prefixed with `vm[i].`, where `i` is the index
+    /// of the VM in [`AxSystemConfig::vms`].
+    pub fn validate(&self) -> Vec<Diagnostic> {
+        let mut diags = Vec::new();
+        if self.vms.is_empty() {
+            diags.push(Diagnostic::error("C601", "vm", "system config has no VMs"));
+        }
+        for (i, vm) in self.vms.iter().enumerate() {
+            diags.extend(vm.validate().into_iter().map(|diag| Diagnostic {
+                path: format!("vm[{i}].{}", diag.path),
+                ..diag
+            }));
+        }
+        diags.extend(self.validate_between_vms());
+        diags
+    }
+
+    /// Checks the VMs against each other only: unique ids and names, and no host memory,
+    /// passthrough device, pinned physical CPU or physical CPU id used by two VMs.
+    ///
+    /// Each diagnostic is reported on the later of the two VMs, with its path prefixed with
+    /// `vm[i].`.
+    pub fn validate_between_vms(&self) -> Vec<Diagnostic> {
+        let mut diags = Vec::new();
+        self.validate_identities(&mut diags);
+        self.validate_identical_memory(&mut diags);
+        self.validate_passthrough_devices(&mut diags);
+        self.validate_cpu_sets(&mut diags);
+        self.validate_cpu_ids(&mut diags);
+        diags
+    }
+
+    /// Describes the VM at `index` for diagnostics, e.g. `vm[0] 'linux'`.
+    fn describe(&self, index: usize) -> String {
+        format!("vm[{index}] '{}'", self.vms[index].base.name)
+    }
+
+    /// Every pair of VMs, as `(i, j)` with `i < j`.
+    fn pairs(&self) -> impl Iterator<Item = (usize, usize)> {
+        let n = self.vms.len();
+        (0..n).flat_map(move |i| (i + 1..n).map(move |j| (i, j)))
+    }
+
+    /// VM ids and names must be unique across the system.
+    fn validate_identities(&self, diags: &mut Vec<Diagnostic>) {
+        for j in 0..self.vms.len() {
+            let vm = &self.vms[j].base;
+            if let Some(i) = (0..j).find(|&i| self.vms[i].base.id == vm.id) {
+                diags.push(Diagnostic::error(
+                    "C602",
+                    format!("vm[{j}].base.id"),
+                    format!("VM id {} is already used by {}", vm.id, self.describe(i)),
+                ));
+            }
+            if !vm.name.is_empty() {
+                if let Some(i) = (0..j).find(|&i| self.vms[i].base.name == vm.name) {
+                    diags.push(Diagnostic::error(
+                        "C603",
+                        format!("vm[{j}].base.name"),
+                        format!(
+                            "VM name '{}' is already used by vm[{i}] (id {})",
+                            vm.name, self.vms[i].base.id
+                        ),
+                    ));
+                }
+            }
+        }
+    }
+
+    /// Identity-mapped memory is host memory owned by the VM, so no two VMs may map the same
+    /// host range. Shared device memory is reported as a warning, since the device is usually
+    /// meant to be owned by one VM only.
+    fn validate_identical_memory(&self, diags: &mut Vec<Diagnostic>) {
+        for (i, j) in self.pairs() {
+            let identical = |vm: usize| {
+                self.vms[vm]
+                    .kernel
+                    .memory_regions
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, region)| {
+                        region.map_type == VmMemMappingType::MapIdentical && region.size > 0
+                    })
+            };
+            for (a_index, a) in identical(i) {
+                for (b_index, b) in identical(j) {
+                    let (a_end, b_end) =
+                        (a.gpa.saturating_add(a.size), b.gpa.saturating_add(b.size));
+                    if !ranges_overlap(a.gpa, a_end, b.gpa, b_end) {
+                        continue;
+                    }
+                    let path = format!("vm[{j}].kernel.memory_regions[{b_index}]");
+                    let message = format!(
+                        "host memory [{:#x}, {:#x}) is also identity-mapped by {} at kernel.memory_regions[{a_index}] [{:#x}, {:#x})",
+                        b.gpa,
+                        b_end,
+                        self.describe(i),
+                        a.gpa,
+                        a_end
+                    );
+                    if a.flags.contains(VmMemFlags::DEVICE) && b.flags.contains(VmMemFlags::DEVICE)
+                    {
+                        diags.push(Diagnostic::warning("C604", path, message));
+                    } else {
+                        diags.push(Diagnostic::error("C604", path, message));
+                    }
+                }
+            }
+        }
+    }
+
+    /// A host device can only be passed through to one VM.
+    fn validate_passthrough_devices(&self, diags: &mut Vec<Diagnostic>) {
+        for (i, j) in self.pairs() {
+            let devices = |vm: usize| {
+                self.vms[vm]
+                    .devices
+                    .passthrough_devices
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, dev)| dev.length > 0)
+            };
+            for (a_index, a) in devices(i) {
+                for (b_index, b) in devices(j) {
+                    let (a_end, b_end) = (
+                        a.base_hpa.saturating_add(a.length),
+                        b.base_hpa.saturating_add(b.length),
+                    );
+                    if ranges_overlap(a.base_hpa, a_end, b.base_hpa, b_end) {
+                        let message = format!(
+                            "passthrough device '{}' at host [{:#x}, {:#x}) is also passed \
+                             through to {} as '{}' at devices.passthrough_devices[{a_index}]",
+                            b.name,
+                            b.base_hpa,
+                            b_end,
+                            self.describe(i),
+                            a.name
+                        );
+                        diags.push(Diagnostic::error(
+                            "C605",
+                            format!("vm[{j}].devices.passthrough_devices[{b_index}]"),
+                            message,
+                        ));
+                    }
+                }
+            }
+        }
+    }
+
+    /// VMs that pin their vCPUs with `phys_cpu_sets` get those physical CPUs exclusively, so
+    /// the pinned CPUs of two VMs must not intersect. VMs without `phys_cpu_sets` are not
+    /// pinned and are not checked.
+    fn validate_cpu_sets(&self, diags: &mut Vec<Diagnostic>) {
+        let pinned = |vm: usize| {
+            self.vms[vm]
+                .base
+                .phys_cpu_sets
+                .as_ref()
+                .map(|sets| sets.iter().fold(0, |mask, set| mask | set))
+        };
+        for (i, j) in self.pairs() {
+            if let (Some(a), Some(b)) = (pinned(i), pinned(j)) {
+                if a & b != 0 {
+                    diags.push(Diagnostic::error(
+                        "C606",
+                        format!("vm[{j}].base.phys_cpu_sets"),
+                        format!(
+                            "physical CPUs {:#x} are also assigned to {}",
+                            a & b,
+                            self.describe(i)
+                        ),
+                    ));
+                }
+            }
+        }
+    }
+
+    /// vCPUs given physical CPU ids with `phys_cpu_ids` run on those physical CPUs, so no
+    /// physical CPU may be given to the vCPUs of two VMs.
+    fn validate_cpu_ids(&self, diags: &mut Vec<Diagnostic>) {
+        let ids = |vm: usize| {
+            self.vms[vm]
+                .base
+                .phys_cpu_ids
+                .as_deref()
+                .unwrap_or_default()
+        };
+        for (i, j) in self.pairs() {
+            let shared: Vec<String> = ids(j)
+                .iter()
+                .filter(|id| ids(i).contains(id))
+                .map(|id| format!("{id:#x}"))
+                .collect();
+            if !shared.is_empty() {
+                diags.push(Diagnostic::error(
+                    "C607",
+                    format!("vm[{j}].base.phys_cpu_ids"),
+                    format!(
+                        "physical CPU ids {} are also assigned to {}",
+                        shared.join(", "),
+                        self.describe(i)
+                    ),
+                ));
+            }
+        }
+    }
+}
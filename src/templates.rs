@@ -2,39 +2,158 @@
 //!
 //! This module provides functionality to generate VM configuration templates
 //! with sensible defaults based on user-provided parameters.
-use crate::{AxVMCrateConfig, VMBaseConfig, VMDevicesConfig, VMKernelConfig};
+use std::fmt::{Display, Formatter};
+
+use crate::{
+    AxVMCrateConfig, ImageLocation, PassThroughDeviceConfig, VMBaseConfig, VMDevicesConfig,
+    VMKernelConfig, VMType, VmMemConfig, VmMemFlags, VmMemMappingType, CURRENT_CONFIG_VERSION,
+};
+
+/// Target architectures supported by the template generator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Arch {
+    /// RISC-V 64-bit, laid out like the QEMU `virt` machine.
+    #[value(name = "riscv64")]
+    Riscv64,
+    /// ARM64, laid out like the QEMU `virt` machine.
+    #[value(name = "aarch64")]
+    Aarch64,
+    /// x86_64, booted through a BIOS image.
+    #[value(name = "x86_64")]
+    X86_64,
+}
+
+impl Display for Arch {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Arch::Riscv64 => write!(f, "riscv64"),
+            Arch::Aarch64 => write!(f, "aarch64"),
+            Arch::X86_64 => write!(f, "x86_64"),
+        }
+    }
+}
+
+impl Arch {
+    /// Guest memory regions of the architecture, as described in `templates/<arch>.toml`.
+    fn memory_regions(&self) -> Vec<VmMemConfig> {
+        const RAM: VmMemFlags = VmMemFlags::READ
+            .union(VmMemFlags::WRITE)
+            .union(VmMemFlags::EXECUTE);
+        const MMIO: VmMemFlags = RAM.union(VmMemFlags::DEVICE);
+
+        // Format: (`gpa`, `size`, `flags`, `map_type`).
+        let regions: &[(usize, usize, VmMemFlags, VmMemMappingType)] = match self {
+            Arch::Riscv64 => &[
+                (0x8000_0000, 0x100_0000, RAM, VmMemMappingType::MapAlloc), // Low RAM 16M
+            ],
+            Arch::Aarch64 => &[
+                (
+                    0x8000_0000,
+                    0x4000_0000,
+                    RAM,
+                    VmMemMappingType::MapIdentical,
+                ), // System RAM 1G
+            ],
+            Arch::X86_64 => &[
+                (0x0000_0000, 0x100_0000, RAM, VmMemMappingType::MapAlloc), // Low RAM 16M
+                (0xfec0_0000, 0x1000, MMIO, VmMemMappingType::MapIdentical), // IO APIC 4K
+                (0xfee0_0000, 0x1000, MMIO, VmMemMappingType::MapIdentical), // Local APIC 4K
+                (0xfed0_0000, 0x1000, MMIO, VmMemMappingType::MapIdentical), // HPET 4K
+            ],
+        };
+        regions
+            .iter()
+            .map(|(gpa, size, flags, map_type)| VmMemConfig {
+                gpa: *gpa,
+                size: *size,
+                flags: *flags,
+                map_type: map_type.clone(),
+            })
+            .collect()
+    }
+
+    /// Passthrough devices of the architecture, as described in `templates/<arch>.toml`.
+    fn passthrough_devices(&self) -> Vec<PassThroughDeviceConfig> {
+        // Format: (`name`, `base_gpa`, `base_hpa`, `length`, `irq_id`).
+        let devices: &[(&str, usize, usize, usize, usize)] = match self {
+            Arch::Riscv64 => &[
+                ("PLIC@c00_0000", 0x0c00_0000, 0x0c00_0000, 0x21_0000, 0x1),
+                ("UART@1000_0000", 0x1000_0000, 0x1000_0000, 0x1000, 0x1),
+            ],
+            Arch::Aarch64 => &[
+                ("intc@8000000", 0x800_0000, 0x800_0000, 0x50_000, 0x1),
+                ("pl011@9000000", 0x900_0000, 0x900_0000, 0x1000, 0x1),
+                ("pl031@9010000", 0x901_0000, 0x901_0000, 0x1000, 0x1),
+                ("pl061@9030000", 0x903_0000, 0x903_0000, 0x1000, 0x1),
+                ("virtio_mmio", 0xa00_0000, 0xa00_0000, 0x4000, 0x1),
+            ],
+            Arch::X86_64 => &[
+                ("IoAPIC@fec00000", 0xfec0_0000, 0xfec0_0000, 0x1000, 0x1),
+                ("LocalAPIC@fee00000", 0xfee0_0000, 0xfee0_0000, 0x1000, 0x1),
+                ("HPET@fed00000", 0xfed0_0000, 0xfed0_0000, 0x1000, 0x1),
+            ],
+        };
+        devices
+            .iter()
+            .map(
+                |(name, base_gpa, base_hpa, length, irq_id)| PassThroughDeviceConfig {
+                    name: name.to_string(),
+                    base_gpa: *base_gpa,
+                    base_hpa: *base_hpa,
+                    length: *length,
+                    irq_id: *irq_id,
+                },
+            )
+            .collect()
+    }
+
+    /// The BIOS image path and load address, only x86_64 guests boot through a BIOS.
+    fn bios(&self) -> (Option<String>, Option<usize>) {
+        match self {
+            Arch::X86_64 => (Some("rvm-bios.bin".to_string()), Some(0x8000)),
+            Arch::Riscv64 | Arch::Aarch64 => (None, None),
+        }
+    }
+}
 
 /// Generate a VM configuration template with specified parameters.
 ///
 /// Creates a complete VM configuration structure with the provided parameters
-/// and sensible defaults for optional fields. This is used by the CLI tool
-/// to generate TOML configuration files.
+/// and the memory layout, devices and BIOS settings of the target architecture.
+/// This is used by the CLI tool to generate TOML configuration files.
 ///
 /// # Arguments
+/// * `arch` - Target architecture, selects the memory and device layout
 /// * `id` - Unique identifier for the VM
 /// * `name` - Human-readable name for the VM
-/// * `vm_type` - Type of VM (0=HostVM, 1=RTOS, 2=Linux)
+/// * `vm_type` - Type of VM
 /// * `cpu_num` - Number of virtual CPUs to allocate
-/// * `entry_point` - VM entry point address
+/// * `entry_point` - VM entry point address, defaults to the BIOS load address if the
+///   architecture boots through a BIOS, or to the kernel load address otherwise
 /// * `kernel_path` - Path to the kernel image file
 /// * `kernel_load_addr` - Address where kernel should be loaded
-/// * `image_location` - Location of kernel image ("fs" or "memory")
+/// * `image_location` - Location of the kernel image
 /// * `cmdline` - Optional kernel command line parameters
 ///
 /// # Returns
 /// * `AxVMCrateConfig` - Complete VM configuration structure
+#[allow(clippy::too_many_arguments)]
 pub fn get_vm_config_template(
+    arch: Arch,
     id: usize,
     name: String,
-    vm_type: usize,
+    vm_type: VMType,
     cpu_num: usize,
-    entry_point: usize,
+    entry_point: Option<usize>,
     kernel_path: String,
     kernel_load_addr: usize,
-    image_location: String,
+    image_location: ImageLocation,
     cmdline: Option<String>,
 ) -> AxVMCrateConfig {
+    let (bios_path, bios_load_addr) = arch.bios();
     AxVMCrateConfig {
+        // Generated for the latest schema version
+        config_version: Some(CURRENT_CONFIG_VERSION),
         // Basic VM configuration
         base: VMBaseConfig {
             id,
@@ -42,31 +161,67 @@ pub fn get_vm_config_template(
             vm_type,
             cpu_num,
             // Assign sequential CPU IDs starting from 0
-            phys_cpu_ids: Some((0..cpu_num).into_iter().collect()),
+            phys_cpu_ids: Some((0..cpu_num).collect()),
             phys_cpu_sets: None,
         },
         // Kernel and boot configuration
         kernel: VMKernelConfig {
-            entry_point,
+            entry_point: entry_point.or(bios_load_addr).unwrap_or(kernel_load_addr),
             kernel_path,
             kernel_load_addr,
-            bios_path: None, // BIOS not used in most configurations
-            bios_load_addr: None,
+            bios_path, // Only used by architectures booting through a BIOS
+            bios_load_addr,
             dtb_path: None, // Device tree not specified by default
             dtb_load_addr: None,
             ramdisk_path: None, // No initial ramdisk by default
             ramdisk_load_addr: None,
-            image_location: Some(image_location),
-            cmdline,                // Optional kernel command line
-            disk_path: None,        // No disk image by default
-            memory_regions: vec![], // Memory regions to be defined per architecture
+            image_location,
+            cmdline,                               // Optional kernel command line
+            disk_path: None,                       // No disk image by default
+            memory_regions: arch.memory_regions(), // Memory layout of the architecture
         },
-        // Device configuration - starts empty, can be customized
+        // Device configuration - platform devices are passed through
         devices: VMDevicesConfig {
-            emu_devices: vec![],                // No emulated devices by default
-            passthrough_devices: vec![],        // No passthrough devices by default
-            interrupt_mode: Default::default(), // Use default interrupt mode
-            excluded_devices: vec![],           // No excluded devices by default
+            emu_devices: vec![],                             // No emulated devices by default
+            passthrough_devices: arch.passthrough_devices(), // Platform devices of the architecture
+            interrupt_mode: Default::default(),              // Use default interrupt mode
+            excluded_devices: vec![],                        // No excluded devices by default
         },
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use clap::ValueEnum;
+
+    use super::*;
+
+    #[test]
+    fn test_templates_are_valid() {
+        for arch in Arch::value_variants() {
+            let config = get_vm_config_template(
+                *arch,
+                1,
+                format!("GuestVM-{arch}"),
+                VMType::VMTRTOS,
+                1,
+                None,
+                format!("arceos-{arch}.bin"),
+                match arch {
+                    Arch::Riscv64 => 0x8020_0000,
+                    Arch::Aarch64 => 0x8008_0000,
+                    Arch::X86_64 => 0x20_0000,
+                },
+                ImageLocation::Fs,
+                None,
+            );
+            // Device memory regions of passthrough devices overlap them on purpose (C401).
+            let errors: Vec<_> = config
+                .validate()
+                .into_iter()
+                .filter(|diag| diag.is_error())
+                .collect();
+            assert_eq!(errors, vec![], "template for {arch}");
+        }
+    }
+}
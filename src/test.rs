@@ -2,6 +2,7 @@ use crate::AxVMCrateConfig;
 use crate::EmulatedDeviceType;
 use crate::VMDevicesConfig;
 use crate::VMInterruptMode;
+use crate::VmMemFlags;
 use crate::VmMemMappingType;
 use enumerable::Enumerable;
 
@@ -46,13 +47,13 @@ interrupt_mode = "passthrough"
 
     assert_eq!(config.base.id, 12);
     assert_eq!(config.base.name, "test_vm");
-    assert_eq!(config.base.vm_type, 1);
+    assert_eq!(config.base.vm_type, crate::VMType::VMTRTOS);
     assert_eq!(config.base.cpu_num, 2);
     assert_eq!(config.base.phys_cpu_ids, Some(vec![0x500, 0x501]));
     assert_eq!(config.base.phys_cpu_sets, Some(vec![3, 4]));
 
     assert_eq!(config.kernel.entry_point, 0xdeadbeef);
-    assert_eq!(config.kernel.image_location, Some("memory".to_string()));
+    assert_eq!(config.kernel.image_location, crate::ImageLocation::Memory);
     assert_eq!(config.kernel.kernel_path, "amazing-os.bin");
     assert_eq!(config.kernel.kernel_load_addr, 0xdeadbeef);
     assert_eq!(
@@ -63,7 +64,10 @@ interrupt_mode = "passthrough"
     assert_eq!(config.kernel.memory_regions.len(), 1);
     assert_eq!(config.kernel.memory_regions[0].gpa, 0x8000_0000);
     assert_eq!(config.kernel.memory_regions[0].size, 0x8000_0000);
-    assert_eq!(config.kernel.memory_regions[0].flags, 0x7);
+    assert_eq!(
+        config.kernel.memory_regions[0].flags,
+        VmMemFlags::READ | VmMemFlags::WRITE | VmMemFlags::EXECUTE
+    );
     assert_eq!(
         config.kernel.memory_regions[0].map_type,
         VmMemMappingType::MapIdentical
@@ -155,6 +159,58 @@ fn test_vmtype_enum() {
     assert_eq!(usize::from(VMType::VMTHostVM), 0);
     assert_eq!(usize::from(VMType::VMTRTOS), 1);
     assert_eq!(usize::from(VMType::VMTLinux), 2);
+
+    assert_eq!(VMType::from_usize(2), Some(VMType::VMTLinux));
+    assert_eq!(VMType::from_usize(999), None);
+    assert_eq!("host".parse(), Ok(VMType::VMTHostVM));
+    assert_eq!("HostVM".parse(), Ok(VMType::VMTHostVM));
+    assert_eq!("Linux".parse(), Ok(VMType::VMTLinux));
+    assert_eq!("1".parse(), Ok(VMType::VMTRTOS));
+    assert!("999".parse::<VMType>().is_err());
+    assert!("windows".parse::<VMType>().is_err());
+    assert_eq!(VMType::VMTRTOS.to_string(), "rtos");
+}
+
+#[test]
+fn test_vmtype_deser() {
+    use crate::VMType;
+
+    const VMTYPE_CONFIG: &str = r#"
+[base]
+id = 1
+name = "vmtype"
+vm_type = "linux"
+cpu_num = 1
+
+[kernel]
+entry_point = 0x8020_0000
+kernel_path = "arceos-riscv64.bin"
+kernel_load_addr = 0x8020_0000
+memory_regions = [[0x8000_0000, 0x100_0000, 0x7, 1]]
+
+[devices]
+passthrough_devices = []
+emu_devices = []
+    "#;
+
+    let config = AxVMCrateConfig::from_toml(VMTYPE_CONFIG).unwrap();
+    assert_eq!(config.base.vm_type, VMType::VMTLinux);
+    let config =
+        AxVMCrateConfig::from_toml(&VMTYPE_CONFIG.replace("\"linux\"", "\"RTOS\"")).unwrap();
+    assert_eq!(config.base.vm_type, VMType::VMTRTOS);
+    let config = AxVMCrateConfig::from_toml(&VMTYPE_CONFIG.replace("\"linux\"", "0")).unwrap();
+    assert_eq!(config.base.vm_type, VMType::VMTHostVM);
+
+    // VM types are serialized as numbers.
+    let serialized = toml::to_string(&config).unwrap();
+    assert!(serialized.contains("vm_type = 0"));
+
+    // Unknown values are parse errors instead of falling back to the default.
+    assert!(AxVMCrateConfig::from_toml(&VMTYPE_CONFIG.replace("\"linux\"", "999")).is_err());
+    assert!(AxVMCrateConfig::from_toml(&VMTYPE_CONFIG.replace("\"linux\"", "-1")).is_err());
+    assert!(
+        AxVMCrateConfig::from_toml(&VMTYPE_CONFIG.replace("\"linux\"", "\"windows\"")).is_err()
+    );
 }
 
 #[test]
@@ -255,7 +311,7 @@ fn test_default_implementations() {
     let vm_mem_config = VmMemConfig::default();
     assert_eq!(vm_mem_config.gpa, 0);
     assert_eq!(vm_mem_config.size, 0);
-    assert_eq!(vm_mem_config.flags, 0);
+    assert_eq!(vm_mem_config.flags, VmMemFlags::empty());
     assert_eq!(vm_mem_config.map_type, VmMemMappingType::MapAlloc);
 
     let emu_device_config = EmulatedDeviceConfig::default();
@@ -276,7 +332,7 @@ fn test_default_implementations() {
     let vm_base_config = VMBaseConfig::default();
     assert_eq!(vm_base_config.id, 0);
     assert_eq!(vm_base_config.name, "");
-    assert_eq!(vm_base_config.vm_type, 0);
+    assert_eq!(vm_base_config.vm_type, VMType::VMTHostVM);
     assert_eq!(vm_base_config.cpu_num, 0);
     assert!(vm_base_config.phys_cpu_ids.is_none());
     assert!(vm_base_config.phys_cpu_sets.is_none());
@@ -291,7 +347,7 @@ fn test_default_implementations() {
     assert!(vm_kernel_config.dtb_load_addr.is_none());
     assert!(vm_kernel_config.ramdisk_path.is_none());
     assert!(vm_kernel_config.ramdisk_load_addr.is_none());
-    assert!(vm_kernel_config.image_location.is_none());
+    assert_eq!(vm_kernel_config.image_location, ImageLocation::Fs);
     assert!(vm_kernel_config.cmdline.is_none());
     assert!(vm_kernel_config.disk_path.is_none());
     assert!(vm_kernel_config.memory_regions.is_empty());
@@ -306,3 +362,1792 @@ fn test_default_implementations() {
     assert_eq!(axvm_crate_config.kernel.entry_point, 0);
     assert!(axvm_crate_config.devices.emu_devices.is_empty());
 }
+
+#[test]
+fn test_config_validate() {
+    use crate::Severity;
+
+    const VALID_CONFIG: &str = r#"
+[base]
+id = 1
+name = "test_vm"
+vm_type = 1
+cpu_num = 2
+phys_cpu_sets = [1, 2]
+
+[kernel]
+entry_point = 0x8020_0000
+kernel_path = "arceos-riscv64.bin"
+kernel_load_addr = 0x8020_0000
+memory_regions = [
+    [0x8000_0000, 0x100_0000, 0x7, 0],
+]
+
+[devices]
+passthrough_devices = [
+    ["UART@1000_0000", 0x1000_0000, 0x1000_0000, 0x1000, 0x1],
+]
+emu_devices = []
+    "#;
+
+    let config = AxVMCrateConfig::from_toml(VALID_CONFIG).unwrap();
+    assert!(config.validate().is_empty());
+
+    let mut config = AxVMCrateConfig::from_toml(VALID_CONFIG).unwrap();
+    config.base.phys_cpu_sets = Some(vec![1]);
+    let diags = config.validate();
+    assert_eq!(diags.len(), 1);
+    assert_eq!(diags[0].severity, Severity::Error);
+    assert_eq!(diags[0].path, "base.phys_cpu_sets");
+
+    let mut config = AxVMCrateConfig::from_toml(VALID_CONFIG).unwrap();
+    config.kernel.memory_regions.push(crate::VmMemConfig {
+        gpa: 0x8080_0000,
+        size: 0x100_0000,
+        ..Default::default()
+    });
+    config.base.name.clear();
+    let diags = config.validate();
+    assert_eq!(diags.len(), 2);
+    assert_eq!(diags[0].severity, Severity::Warning);
+    assert_eq!(diags[0].path, "base.name");
+    assert!(diags[1].is_error());
+    assert_eq!(diags[1].path, "kernel.memory_regions[1]");
+}
+
+#[test]
+fn test_diagnostic_location() {
+    use crate::{locate_key, SourceLocation};
+
+    const LOCATED_CONFIG: &str = r#"[base]
+id = 1
+name = "located"
+vm_type = 1
+cpu_num = 2
+phys_cpu_sets = [1]
+
+[kernel]
+entry_point = 0x8020_0000
+kernel_path = "arceos-riscv64.bin"
+kernel_load_addr = 0x8020_0000
+memory_regions = [
+    [0x8000_0000, 0x100_0000, 0x7, 0],
+    [0x8080_0000, 0x100_0000, 0x7, 0],
+]
+
+[devices]
+passthrough_devices = []
+emu_devices = []
+"#;
+
+    let config = AxVMCrateConfig::from_toml(LOCATED_CONFIG).unwrap();
+    let diags = config.validate();
+    let codes: Vec<&str> = diags.iter().map(|diag| diag.code).collect();
+    assert_eq!(codes, ["C105", "C401"]);
+    assert_eq!(
+        diags[0].to_string(),
+        "error[C105]: base.phys_cpu_sets: has 1 entries but cpu_num is 2"
+    );
+
+    // Diagnostics point at the value of their key path, tuple entries included.
+    let span = diags[0].span_in(LOCATED_CONFIG).unwrap();
+    assert_eq!(&LOCATED_CONFIG[span.clone()], "[1]");
+    assert_eq!(
+        SourceLocation::from_offset(LOCATED_CONFIG, span.start),
+        SourceLocation {
+            line: 6,
+            column: 17
+        }
+    );
+    let span = diags[1].span_in(LOCATED_CONFIG).unwrap();
+    assert_eq!(&LOCATED_CONFIG[span], "[0x8080_0000, 0x100_0000, 0x7, 0]");
+
+    // Missing keys fall back to their table, missing array entries are not located.
+    let span = locate_key(LOCATED_CONFIG, "kernel.dtb_load_addr").unwrap();
+    assert_eq!(&LOCATED_CONFIG[span], "[kernel]");
+    assert!(locate_key(LOCATED_CONFIG, "kernel.memory_regions[2]").is_none());
+    assert!(locate_key(LOCATED_CONFIG, "vm[0].base").is_none());
+    assert!(locate_key("not toml [", "base").is_none());
+}
+
+#[test]
+fn test_config_parse_error() {
+    use crate::{ConfigParseErrorKind, SourceLocation};
+
+    const BAD_DEVICE_CONFIG: &str = r#"[base]
+id = 1
+name = "bad"
+vm_type = 1
+cpu_num = 1
+
+[kernel]
+entry_point = 0x8020_0000
+kernel_path = "arceos-riscv64.bin"
+kernel_load_addr = 0x8020_0000
+memory_regions = [[0x8000_0000, 0x100_0000, 0x7, 0]]
+
+[devices]
+passthrough_devices = [
+    ["PLIC@c000000", 0x0c00_0000, 0x0c00_0000, 0x21_0000, 0x1],
+    ["UART@1000_0000", 0x1000_0000, "0x1000_0000", 0x1000, 0x1],
+]
+emu_devices = []
+"#;
+
+    // Schema errors name the offending tuple entry and show it.
+    let err = AxVMCrateConfig::from_toml_with_upgrade(BAD_DEVICE_CONFIG).unwrap_err();
+    assert_eq!(err.kind, ConfigParseErrorKind::Schema);
+    assert_eq!(err.code(), "C002");
+    assert_eq!(err.path, "devices.passthrough_devices[1][2]");
+    assert_eq!(
+        err.location,
+        Some(SourceLocation {
+            line: 16,
+            column: 37
+        })
+    );
+    assert_eq!(
+        err.to_string(),
+        r#"devices.passthrough_devices[1][2] at line 16, column 37: invalid type: string "0x1000_0000", expected usize
+   |
+16 |     ["UART@1000_0000", 0x1000_0000, "0x1000_0000", 0x1000, 0x1],
+   |                                     ^^^^^^^^^^^^^"#
+    );
+    let diag = err.to_diagnostic();
+    assert!(diag.is_error());
+    assert_eq!(diag.path, "devices.passthrough_devices[1][2]");
+
+    // Syntax errors are located too.
+    let err = AxVMCrateConfig::from_toml_with_upgrade("[base]\nid = \n").unwrap_err();
+    assert_eq!(err.kind, ConfigParseErrorKind::Syntax);
+    assert_eq!(err.location.map(|location| location.line), Some(2));
+
+    // Unsupported versions point at `config_version`.
+    let err = AxVMCrateConfig::from_toml_with_upgrade(&format!(
+        "config_version = 9\n{BAD_DEVICE_CONFIG}"
+    ))
+    .unwrap_err();
+    assert_eq!(err.kind, ConfigParseErrorKind::Version);
+    assert_eq!(err.path, "config_version");
+    assert_eq!(
+        err.location,
+        Some(SourceLocation {
+            line: 1,
+            column: 18
+        })
+    );
+
+    // `from_toml` still returns an `AxError`.
+    assert_eq!(
+        AxVMCrateConfig::from_toml(BAD_DEVICE_CONFIG).unwrap_err(),
+        axerrno::AxError::InvalidInput
+    );
+}
+
+#[test]
+fn test_gpa_map_overlaps() {
+    use crate::{GpaOwnerKind, Severity};
+
+    const OVERLAPPING_CONFIG: &str = r#"
+[base]
+id = 1
+name = "test_vm"
+vm_type = 1
+cpu_num = 1
+
+[kernel]
+entry_point = 0x4008_0000
+kernel_path = "arceos-aarch64.bin"
+kernel_load_addr = 0x4008_0000
+memory_regions = [
+    [0x4000_0000, 0x4000_0000, 0x7, 0],
+    [0x0900_0000, 0x1000, 0x17, 1],
+]
+
+[devices]
+passthrough_devices = [
+    ["pl011@9000000", 0x0900_0000, 0x0900_0000, 0x1000, 0x1],
+]
+emu_devices = [
+    ["virtio-blk", 0x4800_0000, 0x200, 0x21, 0xE1, []],
+]
+    "#;
+
+    let config = AxVMCrateConfig::from_toml(OVERLAPPING_CONFIG).unwrap();
+    let map = config.gpa_map();
+    assert_eq!(map.ranges().len(), 4);
+    assert_eq!(map.ranges()[0].start, 0x0900_0000);
+
+    let overlaps = map.overlaps();
+    assert_eq!(overlaps.len(), 2);
+    assert_eq!(overlaps[0].first.kind, GpaOwnerKind::MemoryRegion);
+    assert_eq!(overlaps[0].second.name, "pl011@9000000");
+    assert_eq!(overlaps[1].first.name, "kernel.memory_regions[0]");
+    assert_eq!(overlaps[1].second.kind, GpaOwnerKind::EmulatedDevice);
+    assert_eq!(overlaps[1].second.name, "virtio-blk");
+
+    // The device memory region mapping the passthrough UART is only redundant, while the virtio
+    // window inside RAM is an error.
+    let diags = config.validate();
+    assert_eq!(diags.len(), 2);
+    assert_eq!(diags[0].severity, Severity::Warning);
+    assert_eq!(diags[0].path, "devices.passthrough_devices[0]");
+    assert_eq!(diags[1].severity, Severity::Error);
+    assert_eq!(diags[1].path, "devices.emu_devices[0]");
+    assert!(diags[1].message.contains("virtio-blk"));
+    assert!(diags[1].message.contains("kernel.memory_regions[0]"));
+}
+
+#[test]
+fn test_validate_image_placement() {
+    use crate::Severity;
+
+    const IMAGE_CONFIG: &str = r#"
+[base]
+id = 1
+name = "test_vm"
+vm_type = 1
+cpu_num = 1
+
+[kernel]
+entry_point = 0x4008_0000
+image_location = "memory"
+kernel_path = "kernel.bin"
+kernel_load_addr = 0x4008_0000
+dtb_path = "board.dtb"
+dtb_load_addr = 0x4200_0000
+ramdisk_path = "initrd.img"
+ramdisk_load_addr = 0x4100_0000
+memory_regions = [
+    [0x4000_0000, 0x400_0000, 0x7, 0],
+]
+
+[devices]
+passthrough_devices = []
+emu_devices = []
+    "#;
+
+    let config = AxVMCrateConfig::from_toml(IMAGE_CONFIG).unwrap();
+    assert!(config.validate().is_empty());
+
+    // The 20M kernel runs into the ramdisk, and the 56M ramdisk covers the DTB and does not fit
+    // into RAM.
+    let diags = config.validate_with_image_sizes(|path| match path {
+        "kernel.bin" => Some(0x140_0000),
+        "initrd.img" => Some(0x380_0000),
+        _ => None,
+    });
+    assert_eq!(diags.len(), 3);
+    assert!(diags.iter().all(|diag| diag.severity == Severity::Error));
+    assert!(diags
+        .iter()
+        .all(|diag| diag.path == "kernel.ramdisk_load_addr"));
+    assert!(diags[0].message.contains("does not fit in guest RAM"));
+    assert!(diags[1].message.contains("collides with kernel image"));
+    assert!(diags[2].message.contains("collides with dtb image"));
+
+    // Image sizes are only used for images that are read from the host.
+    let mut config = config;
+    config.kernel.image_location = crate::ImageLocation::Fs;
+    assert!(config
+        .validate_with_image_sizes(|_| Some(0x1000_0000))
+        .is_empty());
+
+    config.kernel.entry_point = 0x1000;
+    config.kernel.dtb_load_addr = Some(0x4008_0000);
+    let diags = config.validate();
+    assert_eq!(diags.len(), 2);
+    assert_eq!(diags[0].path, "kernel.dtb_load_addr");
+    assert!(diags[0].message.contains("dtb image at 0x40080000"));
+    assert!(diags[0].message.contains("kernel image at 0x40080000"));
+    assert_eq!(diags[1].path, "kernel.entry_point");
+}
+
+#[test]
+fn test_legacy_config() {
+    use crate::migrate_toml;
+
+    const LEGACY_CONFIG: &str = r#"
+# Legacy x86_64 guest.
+
+id = 1
+name = "GuestVM-x86_64"
+vm_type = 1
+cpu_num = 1
+phys_cpu_sets = [1] # Pinned to the second CPU
+entry_point = 0x8000
+bios_path = "rvm-bios.bin"
+bios_load_addr = 0x8000
+kernel_path = "arceos-x86_64.bin"
+kernel_load_addr = 0x20_0000
+image_location = "fs"
+
+# Guest RAM, then the IOAPIC and the LAPIC
+memory_regions = [
+    [0x0000_0000, 0x100_0000, 0x7],
+    [0xfec0_0000, 0x1000, 0x17],
+    [0xfee0_0000, 0x1000, 0x17, 0],
+]
+
+emu_devices = []
+passthrough_devices = [
+    ["IoAPIC@fec00000", 0xfec0_0000, 0xfec0_0000, 0x1000, 0x1],
+]
+    "#;
+
+    assert!(AxVMCrateConfig::is_legacy_toml(LEGACY_CONFIG));
+    let config = AxVMCrateConfig::from_toml(LEGACY_CONFIG).unwrap();
+    assert_eq!(config.base.name, "GuestVM-x86_64");
+    assert_eq!(config.base.phys_cpu_sets, Some(vec![1]));
+    assert_eq!(config.kernel.bios_load_addr, Some(0x8000));
+    assert_eq!(config.kernel.kernel_load_addr, 0x20_0000);
+    assert_eq!(config.kernel.memory_regions.len(), 3);
+    assert_eq!(
+        config.kernel.memory_regions[0].map_type,
+        VmMemMappingType::MapAlloc
+    );
+    assert_eq!(
+        config.kernel.memory_regions[1].map_type,
+        VmMemMappingType::MapIdentical
+    );
+    assert_eq!(
+        config.kernel.memory_regions[2].map_type,
+        VmMemMappingType::MapAlloc
+    );
+    assert_eq!(config.devices.passthrough_devices.len(), 1);
+    assert_eq!(config.devices.interrupt_mode, VMInterruptMode::NoIrq);
+
+    // The migrated config is no longer legacy, keeps the comments and the values as written,
+    // and reads back the same.
+    let (migrated, upgrade) = migrate_toml(LEGACY_CONFIG).unwrap();
+    assert_eq!(upgrade.source_version, 0);
+    assert!(!AxVMCrateConfig::is_legacy_toml(&migrated));
+    assert!(migrated
+        .trim_start()
+        .starts_with("# Legacy x86_64 guest.\n\nconfig_version = 1\n"));
+    assert!(migrated.contains("\n[base]\nid = 1\n"));
+    assert!(migrated.contains("vm_type = 1\n"));
+    assert!(migrated.contains("phys_cpu_sets = [1] # Pinned to the second CPU\n"));
+    assert!(migrated.contains(
+        "# Guest RAM, then the IOAPIC and the LAPIC\nmemory_regions = [\n\
+         \x20   [0x0,         0x100_0000, 0x7,  0],\n\
+         \x20   [0xfec0_0000, 0x1000,     0x17, 1],\n\
+         \x20   [0xfee0_0000, 0x1000,     0x17, 0],\n]"
+    ));
+    assert_eq!(migrate_toml(&migrated).unwrap().0, migrated);
+    let reparsed = AxVMCrateConfig::from_toml(&migrated).unwrap();
+    assert_eq!(reparsed, config);
+    assert_eq!(reparsed.kernel.memory_regions[1].gpa, 0xfec0_0000);
+    assert_eq!(
+        reparsed.kernel.memory_regions[1].map_type,
+        VmMemMappingType::MapIdentical
+    );
+}
+
+#[test]
+fn test_templates() {
+    for template in [
+        include_str!("../templates/aarch64.toml"),
+        include_str!("../templates/riscv64.toml"),
+        include_str!("../templates/x86_64.toml"),
+    ] {
+        assert!(!AxVMCrateConfig::is_legacy_toml(template));
+        let config = AxVMCrateConfig::from_toml(template).unwrap();
+        assert!(!config.validate().iter().any(|diag| diag.is_error()));
+    }
+}
+
+#[test]
+fn test_to_toml() {
+    const FULL_CONFIG: &str = r#"
+[base]
+id = 12
+name = "test \"vm\""
+vm_type = "linux"
+cpu_num = 2
+phys_cpu_ids = [0x500, 0x501]
+phys_cpu_sets = [0x1, 0x2]
+
+[kernel]
+entry_point = 0x4008_0000
+kernel_path = "linux.bin"
+kernel_load_addr = 0x4008_0000
+dtb_path = "linux.dtb"
+dtb_load_addr = 0x4000_0000
+ramdisk_path = "initrd.img"
+ramdisk_load_addr = 0x4800_0000
+image_location = "memory"
+cmdline = "console=ttyAMA0 root=/dev/vda"
+memory_regions = [
+    [0x4000_0000, "1G", ["read", "write", "exec"], 0],
+    [0x0900_0000, 0x1000, 0x17, 1],
+]
+
+[devices]
+emu_devices = [
+    ["gicd", 0x0800_0000, 0x1_0000, 0, 0x21, []],
+    ["virtio-blk", 0x0a00_0000, 0x200, 0x10, 0xe1, [1, 2]],
+]
+passthrough_devices = [
+    ["uart", 0x0900_0000, 0x0900_0000, 0x1000, 0x21],
+]
+interrupt_mode = "passthrough"
+excluded_devices = [["/soc/ethernet@ff0e0000", "/soc/phy@ff0f0000"]]
+"#;
+
+    for source in [
+        FULL_CONFIG,
+        include_str!("../templates/aarch64.toml"),
+        include_str!("../templates/riscv64.toml"),
+        include_str!("../templates/x86_64.toml"),
+    ] {
+        let config = AxVMCrateConfig::from_toml(source).unwrap();
+        let written = config.to_toml();
+        assert_eq!(AxVMCrateConfig::from_toml(&written).unwrap(), config);
+        assert_eq!(
+            AxVMCrateConfig::from_toml(&written).unwrap().to_toml(),
+            written
+        );
+    }
+
+    let written = AxVMCrateConfig::from_toml(FULL_CONFIG).unwrap().to_toml();
+    assert!(written.contains("vm_type = \"linux\"\n"));
+    assert!(written.contains("phys_cpu_ids = [0x500, 0x501]\n"));
+    assert!(written.contains(
+        r#"memory_regions = [
+    [0x4000_0000, 0x4000_0000, ["read", "write", "exec"],           0], # 1 GiB
+    [0x900_0000,  0x1000,      ["read", "write", "exec", "device"], 1], # 4 KiB
+]"#
+    ));
+    assert!(written.contains(
+        r#"emu_devices = [
+    ["gicd",       0x800_0000, 0x1_0000, 0x0,  0x21, []],         # gic partial passthrough distributor
+    ["virtio-blk", 0xa00_0000, 0x200,    0x10, 0xe1, [0x1, 0x2]], # virtio block
+]"#
+    ));
+    assert!(written.contains(
+        "excluded_devices = [\n    [\"/soc/ethernet@ff0e0000\", \"/soc/phy@ff0f0000\"],\n]\n"
+    ));
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_format_toml() {
+    use crate::format_toml;
+
+    const MESSY_CONFIG: &str = r#"# A messy config.
+config_version = 1
+
+[devices]
+interrupt_mode="passthrough"   # pass all
+passthrough_devices = [ # host devices
+    ["uart",0x9000000,0x09000000,4096,33], # console
+    # the rtc
+    ["rtc@9010000", 0x9010000, 0x9010000, 0x1000, 0x22]
+]
+emu_devices = []
+
+[kernel]
+memory_regions = [[0x40000000, "1G", ["read","write","exec"], 0]]
+entry_point=0x40080000
+kernel_load_addr   = 1074266112 # decimal
+kernel_path = "linux.bin"
+
+[base]
+name = "messy"
+id = 3
+cpu_num = 1
+vm_type = "linux"
+
+"#;
+
+    let formatted = format_toml(MESSY_CONFIG).unwrap();
+    assert_eq!(
+        formatted,
+        r#"# A messy config.
+config_version = 1
+
+[base]
+id = 3
+name = "messy"
+vm_type = "linux"
+cpu_num = 1
+
+[kernel]
+entry_point = 0x4008_0000
+kernel_path = "linux.bin"
+kernel_load_addr = 0x4008_0000 # decimal
+memory_regions = [
+    [0x4000_0000, "1G", ["read", "write", "exec"], 0],
+]
+
+[devices]
+emu_devices = []
+passthrough_devices = [ # host devices
+    ["uart",        0x900_0000, 0x900_0000, 0x1000, 33],   # console
+    # the rtc
+    ["rtc@9010000", 0x901_0000, 0x901_0000, 0x1000, 0x22],
+]
+interrupt_mode = "passthrough"   # pass all
+"#
+    );
+    assert_eq!(
+        AxVMCrateConfig::from_toml(&formatted).unwrap(),
+        AxVMCrateConfig::from_toml(MESSY_CONFIG).unwrap()
+    );
+    assert_eq!(format_toml(&formatted).unwrap(), formatted);
+
+    // The templates are formatted, and keep their comments.
+    for template in [
+        include_str!("../templates/aarch64.toml"),
+        include_str!("../templates/riscv64.toml"),
+        include_str!("../templates/x86_64.toml"),
+    ] {
+        assert!(template.contains("# Pass-through devices\n"));
+        assert_eq!(format_toml(template).unwrap(), template);
+    }
+
+    // Table forms are ordered and written in hexadecimal, but not turned into tuples.
+    let formatted = format_toml(&MESSY_CONFIG.replace(
+        r#"memory_regions = [[0x40000000, "1G", ["read","write","exec"], 0]]"#,
+        "memory_regions = [{ map_type = 0, flags = 7, size = 1073741824, gpa = 0x40000000 }]",
+    ))
+    .unwrap();
+    assert!(formatted
+        .contains("    { gpa = 0x4000_0000, size = 0x4000_0000, flags = 7, map_type = 0 },\n"));
+
+    let err = format_toml("[base]\nid = ").unwrap_err();
+    assert_eq!(err.kind, crate::ConfigParseErrorKind::Syntax);
+}
+
+#[test]
+fn test_config_diff() {
+    use crate::{ChangeKind, ConfigChange};
+
+    let old = AxVMCrateConfig::from_toml(include_str!("../templates/aarch64.toml")).unwrap();
+    assert!(old.diff(&old).is_empty());
+
+    // Devices are matched by name, so reordering them is not a difference.
+    let mut new = old.clone();
+    new.devices.passthrough_devices.reverse();
+    assert!(old.diff(&new).is_empty());
+
+    new.base.cpu_num = 2;
+    new.kernel.memory_regions[0].size = 0x2000_0000;
+    new.kernel.memory_regions.push(crate::VmMemConfig {
+        gpa: 0x1000_0000,
+        size: 0x1000,
+        flags: VmMemFlags::READ | VmMemFlags::DEVICE,
+        map_type: VmMemMappingType::MapIdentical,
+    });
+    let devices = &mut new.devices.passthrough_devices;
+    devices.retain(|dev| dev.name != "virtio_mmio");
+    for dev in devices.iter_mut() {
+        match dev.name.as_str() {
+            "pl031@9010000" => dev.name = "rtc@9010000".into(),
+            "pl061@9030000" => dev.base_gpa = 0x904_0000,
+            _ => {}
+        }
+    }
+    new.devices.interrupt_mode = VMInterruptMode::Passthrough;
+    new.devices.excluded_devices = vec![vec!["/pcie@10000000".into()]];
+
+    let changes = old.diff(&new);
+    let lines: Vec<String> = changes.iter().map(ConfigChange::to_string).collect();
+    assert_eq!(
+        lines,
+        [
+            "changed base.cpu_num: 1 -> 2",
+            "changed kernel.memory_regions[gpa=0x8000_0000].size: 0x4000_0000 -> 0x2000_0000",
+            r#"added kernel.memory_regions[gpa=0x1000_0000]: [0x1000_0000, 0x1000, ["read", "device"], 1]"#,
+            "changed devices.passthrough_devices[name=pl061@9030000].base_gpa: 0x903_0000 -> 0x904_0000",
+            r#"renamed devices.passthrough_devices[name=pl031@9010000]: "pl031@9010000" -> "rtc@9010000""#,
+            r#"removed devices.passthrough_devices[name=virtio_mmio]: ["virtio_mmio", 0xa00_0000, 0xa00_0000, 0x4000, 0x1]"#,
+            r#"changed devices.interrupt_mode: "no_irq" -> "passthrough""#,
+            r#"added devices.excluded_devices: ["/pcie@10000000"]"#,
+        ]
+    );
+    assert_eq!(changes[2].kind, ChangeKind::Added);
+    assert_eq!(changes[2].old, None);
+
+    // JSON gets the values themselves instead of their TOML text.
+    #[cfg(feature = "std")]
+    {
+        let json = serde_json::to_value(&changes).unwrap();
+        assert_eq!(json[1]["old"], 0x4000_0000);
+        assert_eq!(json[2]["old"], serde_json::Value::Null);
+        assert_eq!(
+            json[2]["new"],
+            serde_json::json!([0x1000_0000, 0x1000, ["read", "device"], 1])
+        );
+        assert_eq!(json[6]["new"], "passthrough");
+    }
+
+    // Names that could be confused with the key path syntax are quoted.
+    let mut renamed = old.clone();
+    renamed.devices.passthrough_devices[0].name = "intc.gic".into();
+    renamed.devices.passthrough_devices[0].irq_id = 2;
+    let changes = old.diff(&renamed);
+    assert_eq!(
+        changes[0].path,
+        "devices.passthrough_devices[name=intc@8000000]"
+    );
+    assert_eq!(changes[0].kind, ChangeKind::Removed);
+    assert_eq!(
+        changes[1].path,
+        r#"devices.passthrough_devices[name="intc.gic"]"#
+    );
+    assert_eq!(changes[1].kind, ChangeKind::Added);
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_get_set_config_value() {
+    use crate::{get_config_value, set_config_value, ConfigEditError};
+
+    const TEMPLATE: &str = include_str!("../templates/aarch64.toml");
+    let get = |source: &str, path: &str| get_config_value(source, path).unwrap();
+
+    assert_eq!(get(TEMPLATE, "base.cpu_num"), "1");
+    assert_eq!(get(TEMPLATE, "base.name"), "GuestVM-aarch64");
+    assert_eq!(
+        get(
+            TEMPLATE,
+            "devices.passthrough_devices[name=pl011@9000000].base_hpa"
+        ),
+        "0x900_0000"
+    );
+    // Addresses and sizes match by value.
+    assert_eq!(
+        get(TEMPLATE, "kernel.memory_regions[gpa=2147483648].flags"),
+        r#"["read", "write", "exec"]"#
+    );
+    assert_eq!(
+        get(TEMPLATE, "kernel.memory_regions[0]"),
+        r#"[0x8000_0000, 0x4000_0000, ["read", "write", "exec"], 1]"#
+    );
+    assert_eq!(
+        get_config_value(TEMPLATE, "devices.passthrough_devices[name=uart].irq_id"),
+        Err(ConfigEditError::NotFound(
+            "devices.passthrough_devices[name=uart]".into()
+        ))
+    );
+    assert!(matches!(
+        get_config_value(TEMPLATE, "devices.passthrough_devices[name=uart"),
+        Err(ConfigEditError::BadPath(_))
+    ));
+
+    // Only the edited values change, comments included.
+    let edited = set_config_value(
+        TEMPLATE,
+        "devices.passthrough_devices[name=pl011@9000000].irq_id",
+        "0x21",
+    )
+    .unwrap();
+    let edited = set_config_value(&edited, "kernel.cmdline", "console=ttyAMA0 rw").unwrap();
+    let edited = set_config_value(&edited, "base.name", "42").unwrap();
+    assert_eq!(
+        edited,
+        TEMPLATE
+            .replace(
+                r#"["pl011@9000000", 0x900_0000, 0x900_0000, 0x1000,   0x1],"#,
+                r#"["pl011@9000000", 0x900_0000, 0x900_0000, 0x1000,   0x21],"#
+            )
+            .replace(r#"name = "GuestVM-aarch64""#, r#"name = "42""#)
+            .replace(
+                "\n\n[devices]",
+                "\ncmdline = \"console=ttyAMA0 rw\"\n\n[devices]"
+            )
+    );
+    let config = AxVMCrateConfig::from_toml(&edited).unwrap();
+    assert_eq!(config.devices.passthrough_devices[1].irq_id, 0x21);
+    assert_eq!(config.kernel.cmdline.as_deref(), Some("console=ttyAMA0 rw"));
+
+    // Values not matching the schema and unknown keys are rejected.
+    let err = set_config_value(TEMPLATE, "base.cpu_num", r#""two""#).unwrap_err();
+    let ConfigEditError::Invalid(err) = err else {
+        panic!("unexpected error {err:?}");
+    };
+    assert_eq!(err.path, "base.cpu_num");
+    assert_eq!(
+        set_config_value(TEMPLATE, "base.cpu_nmu", "2"),
+        Err(ConfigEditError::NotFound("base.cpu_nmu".into()))
+    );
+
+    // Table forms, quoted selectors and missing optional tuple fields.
+    const TABLES: &str = r#"
+[base]
+id = 0
+name = "tables"
+vm_type = 1
+cpu_num = 1
+
+[kernel]
+entry_point = 0x8020_0000
+kernel_path = "rtos.bin"
+kernel_load_addr = 0x8020_0000
+
+[[kernel.memory_regions]]
+gpa = 0x8000_0000
+size = "16M" # RAM
+flags = 7
+map_type = 0
+
+[devices]
+emu_devices = [["intc.gic", 0x800_0000, 0x1_0000, 0, 0x1]]
+passthrough_devices = [{ name = "uart", base_gpa = 0x1000_0000 }]
+"#;
+    assert_eq!(
+        get(TABLES, "kernel.memory_regions[size=0x100_0000].gpa"),
+        "0x8000_0000"
+    );
+    let edited = set_config_value(TABLES, "kernel.memory_regions[0].size", "32M").unwrap();
+    assert!(edited.contains("size = \"32M\" # RAM\n"));
+    let edited = set_config_value(TABLES, "passthrough_devices", "[]");
+    assert!(matches!(edited, Err(ConfigEditError::NotFound(_))));
+    let edited = set_config_value(
+        TABLES,
+        r#"devices.emu_devices[name="intc.gic"].cfg_list"#,
+        "[1, 2]",
+    )
+    .unwrap();
+    assert!(
+        edited.contains(r#"emu_devices = [["intc.gic", 0x800_0000, 0x1_0000, 0, 0x1, [1, 2]]]"#)
+    );
+    let edited = set_config_value(
+        TABLES,
+        "devices.passthrough_devices[name=uart].irq_id",
+        "33",
+    )
+    .unwrap();
+    let config = AxVMCrateConfig::from_toml(&edited).unwrap();
+    assert_eq!(config.devices.passthrough_devices[0].irq_id, 33);
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_resolve_config() {
+    use crate::{format_toml, resolve_config, ConfigResolveError};
+    use std::fs;
+    use std::path::Path;
+
+    let dir = std::env::temp_dir().join(format!("axvmconfig-resolve-{}", std::process::id()));
+    fs::create_dir_all(dir.join("boards")).unwrap();
+    let write = |name: &str, content: &str| fs::write(dir.join(name), content).unwrap();
+    write("base.toml", include_str!("../templates/aarch64.toml"));
+    write(
+        "devices.toml",
+        r#"
+[devices]
+interrupt_mode = "passthrough"
+passthrough_devices = [["gpio@9040000", 0x904_0000, 0x904_0000, 0x1000, 0x22]]
+"#,
+    );
+    const BOARD: &str = r#"extends = "../base.toml"
+include = ["../devices.toml"]
+
+[base]
+name = "board"
+
+[kernel]
+cmdline = "console=ttyAMA0"
+memory_regions = [[0x4000_0000, "512M", ["read", "write"], 1]]
+
+[[devices.passthrough_devices]]
+name = "pl011@9000000"
+irq_id = 0x21
+
+[[devices.passthrough_devices]]
+name = "virtio_mmio"
+base_gpa = 0xa00_0000
+"#;
+    write("boards/board.toml", BOARD);
+
+    let base = AxVMCrateConfig::from_toml(include_str!("../templates/aarch64.toml")).unwrap();
+    let config = AxVMCrateConfig::from_file(dir.join("boards/board.toml")).unwrap();
+    // Tables are merged key by key, other arrays are replaced.
+    assert_eq!(config.base.name, "board");
+    assert_eq!(config.base.cpu_num, base.base.cpu_num);
+    assert_eq!(config.kernel.kernel_path, base.kernel.kernel_path);
+    assert_eq!(config.kernel.cmdline.as_deref(), Some("console=ttyAMA0"));
+    assert_eq!(config.kernel.memory_regions.len(), 1);
+    assert_eq!(config.kernel.memory_regions[0].size, 0x2000_0000);
+    assert_eq!(config.devices.interrupt_mode, VMInterruptMode::Passthrough);
+    // Devices are merged by name: a table only replaces the fields it gives, a tuple replaces
+    // the whole device, and new devices are appended.
+    let devices = &config.devices.passthrough_devices;
+    let names: Vec<&str> = devices.iter().map(|dev| dev.name.as_str()).collect();
+    assert_eq!(
+        names,
+        [
+            "intc@8000000",
+            "pl011@9000000",
+            "pl031@9010000",
+            "pl061@9030000",
+            "virtio_mmio",
+            "gpio@9040000",
+        ]
+    );
+    assert_eq!(devices[1].irq_id, 0x21);
+    assert_eq!(devices[1].base_hpa, 0x900_0000);
+    assert_eq!(devices[4].length, 0x4000);
+    assert_eq!(devices[5].irq_id, 0x22);
+
+    // Files without `extends` or `include` are returned as they are, and files with them are
+    // formatted without being complete configurations.
+    let source = include_str!("../templates/aarch64.toml");
+    assert_eq!(
+        resolve_config(source, Path::new("base.toml")).unwrap(),
+        source
+    );
+    assert!(format_toml(BOARD)
+        .unwrap()
+        .starts_with("extends = \"../base.toml\"\ninclude = [\"../devices.toml\"]\n\n[base]\n"));
+
+    // Errors of the merged configuration are reported on the file without a location.
+    write(
+        "broken.toml",
+        "extends = \"base.toml\"\n[base]\ncpu_num = \"two\"\n",
+    );
+    let err = AxVMCrateConfig::from_file(dir.join("broken.toml")).unwrap_err();
+    let ConfigResolveError::Parse { path, error } = err else {
+        panic!("unexpected error {err:?}");
+    };
+    assert_eq!(path, dir.join("broken.toml"));
+    assert_eq!(error.path, "base.cpu_num");
+    assert_eq!(error.location, None);
+
+    write("a.toml", "extends = \"b.toml\"\n");
+    write("b.toml", "include = [\"a.toml\"]\n");
+    let err = AxVMCrateConfig::from_file(dir.join("a.toml")).unwrap_err();
+    let ConfigResolveError::Cycle(cycle) = err else {
+        panic!("unexpected error {err:?}");
+    };
+    let names: Vec<_> = cycle.iter().map(|path| path.file_name().unwrap()).collect();
+    assert_eq!(names, ["a.toml", "b.toml", "a.toml"]);
+
+    write("missing.toml", "extends = \"nope.toml\"\n");
+    assert!(matches!(
+        AxVMCrateConfig::from_file(dir.join("missing.toml")),
+        Err(ConfigResolveError::Io { .. })
+    ));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_config_version_upgrade() {
+    use crate::CURRENT_CONFIG_VERSION;
+
+    const V1_CONFIG: &str = r#"
+[base]
+id = 1
+name = "test_vm"
+vm_type = 1
+cpu_num = 1
+
+[kernel]
+entry_point = 0x8020_0000
+kernel_path = "arceos-riscv64.bin"
+kernel_load_addr = 0x8020_0000
+memory_regions = [
+    [0x8000_0000, 0x100_0000, 0x7, 1],
+]
+
+[devices]
+passthrough_devices = []
+emu_devices = []
+    "#;
+
+    let (config, upgrade) = AxVMCrateConfig::from_toml_with_upgrade(V1_CONFIG).unwrap();
+    assert_eq!(config.config_version, Some(CURRENT_CONFIG_VERSION));
+    assert_eq!(config.devices.interrupt_mode, VMInterruptMode::NoIrq);
+    assert_eq!(upgrade.source_version, 1);
+    assert!(upgrade.inferred);
+    assert!(upgrade.warnings.is_empty());
+
+    let explicit = format!("config_version = 1\n{V1_CONFIG}interrupt_mode = \"pt\"\n");
+    let (config, upgrade) = AxVMCrateConfig::from_toml_with_upgrade(&explicit).unwrap();
+    assert_eq!(config.devices.interrupt_mode, VMInterruptMode::Passthrough);
+    assert_eq!(upgrade.source_version, 1);
+    assert!(!upgrade.inferred);
+    assert!(upgrade.warnings.is_empty());
+
+    let legacy = "config_version = 0\nid = 1\nname = \"vm\"\nvm_type = 1\ncpu_num = 1\n\
+        entry_point = 0x1000\nkernel_path = \"k.bin\"\nkernel_load_addr = 0x1000\n\
+        memory_regions = [[0x0, 0x100_0000, 0x7]]\n";
+    let (config, upgrade) = AxVMCrateConfig::from_toml_with_upgrade(legacy).unwrap();
+    assert_eq!(config.base.name, "vm");
+    assert_eq!(config.kernel.memory_regions.len(), 1);
+    assert_eq!(upgrade.source_version, 0);
+    assert!(!upgrade.inferred);
+    assert_eq!(upgrade.warnings.len(), 1);
+
+    let future = format!(
+        "config_version = {}\n{V1_CONFIG}",
+        CURRENT_CONFIG_VERSION + 1
+    );
+    assert!(AxVMCrateConfig::from_toml(&future).is_err());
+    assert!(AxVMCrateConfig::from_toml(&format!("config_version = \"1\"\n{V1_CONFIG}")).is_err());
+}
+
+#[test]
+fn test_named_field_tables() {
+    const TUPLE_CONFIG: &str = r#"
+[base]
+id = 1
+name = "test_vm"
+vm_type = 1
+cpu_num = 1
+
+[kernel]
+entry_point = 0x8020_0000
+kernel_path = "arceos-riscv64.bin"
+kernel_load_addr = 0x8020_0000
+memory_regions = [
+    [0x8000_0000, 0x100_0000, 0x7, 1],
+]
+
+[devices]
+passthrough_devices = [
+    ["UART@1000_0000", 0x1000_0000, 0x1000_0000, 0x1000, 0xa],
+    ["PLIC@c00_0000"],
+]
+emu_devices = [
+    ["virtio-blk", 0xa000_0000, 0x1000, 0x21, 0xE1, [1, 2]],
+    ["console", 0xa000_1000, 0x1000, 0x22, 0x2],
+]
+    "#;
+
+    const TABLE_CONFIG: &str = r#"
+[base]
+id = 1
+name = "test_vm"
+vm_type = 1
+cpu_num = 1
+
+[kernel]
+entry_point = 0x8020_0000
+kernel_path = "arceos-riscv64.bin"
+kernel_load_addr = 0x8020_0000
+
+[[kernel.memory_regions]]
+gpa = 0x8000_0000
+size = 0x100_0000
+flags = 0x7
+map_type = 1
+
+[devices]
+emu_devices = [
+    { name = "virtio-blk", base_gpa = 0xa000_0000, length = 0x1000, irq_id = 0x21, emu_type = 0xE1, cfg_list = [1, 2] },
+    ["console", 0xa000_1000, 0x1000, 0x22, 0x2],
+]
+
+[[devices.passthrough_devices]]
+name = "UART@1000_0000"
+base_gpa = 0x1000_0000
+base_hpa = 0x1000_0000
+length = 0x1000
+irq_id = 0xa
+
+[[devices.passthrough_devices]]
+name = "PLIC@c00_0000"
+    "#;
+
+    let tuple = AxVMCrateConfig::from_toml(TUPLE_CONFIG).unwrap();
+    let table = AxVMCrateConfig::from_toml(TABLE_CONFIG).unwrap();
+    assert_eq!(tuple.kernel.memory_regions, table.kernel.memory_regions);
+    assert_eq!(tuple.devices.emu_devices, table.devices.emu_devices);
+    assert_eq!(
+        tuple.devices.passthrough_devices,
+        table.devices.passthrough_devices
+    );
+    assert_eq!(table.devices.emu_devices[1].cfg_list, Vec::<usize>::new());
+    assert_eq!(table.devices.passthrough_devices[1].length, 0);
+
+    // Misspelled keys are rejected instead of silently falling back to defaults.
+    let misspelled = TABLE_CONFIG.replace("irq_id = 0xa", "irq = 0xa");
+    assert!(AxVMCrateConfig::from_toml(&misspelled).is_err());
+}
+
+#[test]
+fn test_parse_size() {
+    use crate::{parse_size, ParseSizeError};
+
+    assert_eq!(parse_size("4096"), Ok(4096));
+    assert_eq!(parse_size("0x4000_0000"), Ok(0x4000_0000));
+    assert_eq!(parse_size("0X1000"), Ok(0x1000));
+    assert_eq!(parse_size("0b1010"), Ok(0b1010));
+    assert_eq!(parse_size("0o777"), Ok(0o777));
+    assert_eq!(parse_size("4K"), Ok(0x1000));
+    assert_eq!(parse_size("16MiB"), Ok(0x100_0000));
+    assert_eq!(parse_size("16mb"), Ok(0x100_0000));
+    assert_eq!(parse_size("1G"), Ok(0x4000_0000));
+    assert_eq!(parse_size(" 2 GiB "), Ok(0x8000_0000));
+    assert_eq!(parse_size("1_024B"), Ok(1024));
+
+    assert_eq!(parse_size(""), Err(ParseSizeError::Empty));
+    assert_eq!(
+        parse_size("16XB"),
+        Err(ParseSizeError::UnknownUnit("XB".into()))
+    );
+    assert!(matches!(
+        parse_size("0xZZ"),
+        Err(ParseSizeError::InvalidNumber(_))
+    ));
+    assert!(matches!(
+        parse_size("-1"),
+        Err(ParseSizeError::InvalidNumber(_))
+    ));
+    assert_eq!(
+        parse_size("0x1_0000_0000_0000_0000"),
+        Err(ParseSizeError::Overflow)
+    );
+}
+
+#[test]
+fn test_size_units_deser() {
+    const UNITS_CONFIG: &str = r#"
+[base]
+id = 1
+name = "test_vm"
+vm_type = 1
+cpu_num = 1
+
+[kernel]
+entry_point = 0x8020_0000
+kernel_path = "arceos-riscv64.bin"
+kernel_load_addr = 0x8020_0000
+memory_regions = [
+    [0x8000_0000, "16MiB", 0x7, 1],
+    { gpa = 0x9000_0000, size = "1G", flags = 0x7, map_type = 0 },
+]
+
+[devices]
+passthrough_devices = [
+    ["UART@1000_0000", 0x1000_0000, 0x1000_0000, "4K", 0xa],
+]
+emu_devices = [
+    ["virtio-blk", 0xa000_0000, "0x1000", 0x21, 0xE1],
+]
+    "#;
+
+    let config = AxVMCrateConfig::from_toml(UNITS_CONFIG).unwrap();
+    assert_eq!(config.kernel.memory_regions[0].size, 0x100_0000);
+    assert_eq!(config.kernel.memory_regions[1].size, 0x4000_0000);
+    assert_eq!(config.devices.passthrough_devices[0].length, 0x1000);
+    assert_eq!(config.devices.emu_devices[0].length, 0x1000);
+
+    assert!(AxVMCrateConfig::from_toml(&UNITS_CONFIG.replace("16MiB", "16 parsecs")).is_err());
+    assert!(AxVMCrateConfig::from_toml(&UNITS_CONFIG.replace("\"4K\"", "-4")).is_err());
+}
+
+#[test]
+fn test_mem_flags_deser() {
+    const FLAGS_CONFIG: &str = r#"
+[base]
+id = 1
+name = "flags"
+vm_type = 1
+cpu_num = 1
+
+[kernel]
+entry_point = 0x8020_0000
+kernel_path = "arceos-riscv64.bin"
+kernel_load_addr = 0x8020_0000
+memory_regions = [
+    [0x8000_0000, 0x100_0000, ["read", "write", "exec"], 1],
+    { gpa = 0x1000_0000, size = 0x1000, flags = ["R", "W", "device"], map_type = 1 },
+    [0x9000_0000, 0x1000, 0x3f, 0],
+]
+
+[devices]
+passthrough_devices = []
+emu_devices = []
+    "#;
+
+    let config = AxVMCrateConfig::from_toml(FLAGS_CONFIG).unwrap();
+    let regions = &config.kernel.memory_regions;
+    assert_eq!(regions[0].flags.bits(), 0x7);
+    assert_eq!(
+        regions[1].flags,
+        VmMemFlags::READ | VmMemFlags::WRITE | VmMemFlags::DEVICE
+    );
+    assert_eq!(regions[2].flags, VmMemFlags::all());
+
+    assert_eq!(regions[0].flags.to_string(), "READ | WRITE | EXECUTE");
+    assert_eq!(VmMemFlags::empty().to_string(), "NONE");
+
+    // Flags are serialized as integers.
+    let serialized = toml::to_string(&config).unwrap();
+    let reparsed = AxVMCrateConfig::from_toml(&serialized).unwrap();
+    assert_eq!(reparsed.kernel.memory_regions, config.kernel.memory_regions);
+
+    // Unknown bits and names are rejected.
+    assert!(AxVMCrateConfig::from_toml(&FLAGS_CONFIG.replace("0x3f", "0x40")).is_err());
+    assert!(AxVMCrateConfig::from_toml(&FLAGS_CONFIG.replace("\"exec\"", "\"huge\"")).is_err());
+}
+
+#[test]
+fn test_image_location_deser() {
+    use crate::ImageLocation;
+
+    const LOCATION_CONFIG: &str = r#"
+[base]
+id = 1
+name = "location"
+vm_type = 1
+cpu_num = 1
+
+[kernel]
+entry_point = 0x8020_0000
+image_location = "Memory"
+kernel_path = "arceos-riscv64.bin"
+kernel_load_addr = 0x8020_0000
+memory_regions = [[0x8000_0000, 0x100_0000, 0x7, 1]]
+
+[devices]
+passthrough_devices = []
+emu_devices = []
+    "#;
+
+    let location = |value: &str| {
+        AxVMCrateConfig::from_toml(&LOCATION_CONFIG.replace("\"Memory\"", value))
+            .map(|config| config.kernel.image_location)
+    };
+    assert_eq!(location("\"Memory\""), Ok(ImageLocation::Memory));
+    assert_eq!(location("\"ram\""), Ok(ImageLocation::Memory));
+    assert_eq!(location("\"FS\""), Ok(ImageLocation::Fs));
+    assert_eq!(location("\"rootfs\""), Ok(ImageLocation::Fs));
+
+    // A missing image location defaults to fs.
+    let config =
+        AxVMCrateConfig::from_toml(&LOCATION_CONFIG.replace("image_location = \"Memory\"\n", ""))
+            .unwrap();
+    assert_eq!(config.kernel.image_location, ImageLocation::Fs);
+    assert!(toml::to_string(&config)
+        .unwrap()
+        .contains("image_location = \"fs\""));
+
+    // Typos are parse errors instead of silently meaning fs.
+    assert!(location("\"mem\"").is_err());
+    assert!(location("1").is_err());
+    assert_eq!(
+        "disk".parse::<ImageLocation>().unwrap_err().to_string(),
+        "unknown image location 'disk', expected one of \"fs\", \"memory\""
+    );
+}
+
+#[test]
+fn test_runtime_config() {
+    use crate::{AxVMConfig, AxVMImage, ImageLocation, VMInterruptMode, VMType};
+
+    const RUNTIME_CONFIG: &str = r#"
+[base]
+id = 3
+name = "runtime"
+vm_type = "linux"
+cpu_num = 2
+phys_cpu_sets = [1, 2]
+
+[kernel]
+entry_point = 0x8020_0000
+kernel_path = "linux.bin"
+kernel_load_addr = 0x8020_0000
+dtb_path = "linux.dtb"
+dtb_load_addr = 0x8200_0000
+ramdisk_load_addr = 0x8300_0000
+memory_regions = [[0x8000_0000, 0x400_0000, 0x7, 1]]
+
+[devices]
+passthrough_devices = [["UART@1000_0000", 0x1000_0000, 0x1000_0000, 0x1000, 0xa]]
+emu_devices = []
+    "#;
+
+    let config = AxVMConfig::from_toml(RUNTIME_CONFIG).unwrap();
+    assert_eq!(config.id, 3);
+    assert_eq!(config.vm_type, VMType::VMTLinux);
+    assert_eq!(config.vcpus.len(), 2);
+    // Physical ids default to the vCPU ids.
+    assert_eq!(config.vcpus[1].phys_id, 1);
+    assert_eq!(config.vcpus[1].phys_cpu_set, Some(2));
+    assert_eq!(config.images.location, ImageLocation::Fs);
+    assert_eq!(
+        config.images.dtb,
+        Some(AxVMImage {
+            path: "linux.dtb".into(),
+            load_addr: 0x8200_0000,
+        })
+    );
+    // A load address without an image is ignored.
+    assert!(config.images.ramdisk.is_none());
+    assert!(config.images.bios.is_none());
+    assert_eq!(config.interrupt_mode, VMInterruptMode::NoIrq);
+    assert_eq!(config.passthrough_devices.len(), 1);
+
+    // Configurations with validation errors are rejected.
+    let mut crate_config = AxVMCrateConfig::from_toml(RUNTIME_CONFIG).unwrap();
+    crate_config.base.phys_cpu_ids = Some(vec![0, 0]);
+    let err = AxVMConfig::try_from(crate_config).unwrap_err();
+    assert_eq!(err.errors().count(), 1);
+    assert!(err.to_string().contains("base.phys_cpu_ids[1]"));
+    assert!(AxVMConfig::from_toml(&RUNTIME_CONFIG.replace("cpu_num = 2", "cpu_num = 3")).is_err());
+}
+
+#[test]
+fn test_system_config() {
+    use crate::{AxSystemConfig, ConfigResolveError};
+    use std::fs;
+
+    const SYSTEM_CONFIG: &str = r#"
+vm_configs = ["rtos.toml"]
+
+[[vm]]
+[vm.base]
+id = 1
+name = "linux"
+vm_type = "linux"
+cpu_num = 2
+phys_cpu_sets = [1, 2]
+
+[vm.kernel]
+entry_point = 0x8020_0000
+kernel_path = "linux.bin"
+kernel_load_addr = 0x8020_0000
+memory_regions = [[0x8000_0000, 0x1000_0000, 0x7, 1]]
+
+[vm.devices]
+passthrough_devices = [["UART@1000_0000", 0x1000_0000, 0x1000_0000, 0x1000, 0xa]]
+emu_devices = []
+    "#;
+
+    const RTOS_CONFIG: &str = r#"
+[base]
+id = 2
+name = "rtos"
+vm_type = "rtos"
+cpu_num = 1
+phys_cpu_sets = [4]
+
+[kernel]
+entry_point = 0x9020_0000
+kernel_path = "rtos.bin"
+kernel_load_addr = 0x9020_0000
+memory_regions = [[0x9000_0000, 0x100_0000, 0x7, 1]]
+
+[devices]
+passthrough_devices = [["UART@1000_1000", 0x1000_1000, 0x1000_1000, 0x1000, 0xb]]
+emu_devices = []
+    "#;
+
+    let load = |rtos: String| {
+        move |path: &str| {
+            assert_eq!(path, "rtos.toml");
+            Ok(rtos.clone())
+        }
+    };
+
+    let system = AxSystemConfig::from_toml(SYSTEM_CONFIG, load(RTOS_CONFIG.into())).unwrap();
+    assert_eq!(system.vms.len(), 2);
+    assert_eq!(system.vms[1].base.name, "rtos");
+    assert!(system.validate().is_empty());
+
+    // Conflicting ids, names, identity-mapped memory, passthrough devices and CPUs.
+    let rtos = RTOS_CONFIG
+        .replace("id = 2", "id = 1")
+        .replace("name = \"rtos\"", "name = \"linux\"")
+        .replace("0x9000_0000, 0x100_0000", "0x8f00_0000, 0x200_0000")
+        .replace("0x1000_1000, 0x1000_1000", "0x1000_1000, 0x1000_0800")
+        .replace("[4]", "[3]");
+    let system = AxSystemConfig::from_toml(SYSTEM_CONFIG, load(rtos)).unwrap();
+    let diags = system.validate();
+    let paths: Vec<&str> = diags.iter().map(|diag| diag.path.as_str()).collect();
+    assert_eq!(
+        paths,
+        [
+            "vm[1].base.id",
+            "vm[1].base.name",
+            "vm[1].kernel.memory_regions[0]",
+            "vm[1].devices.passthrough_devices[0]",
+            "vm[1].base.phys_cpu_sets",
+        ]
+    );
+    assert!(diags.iter().all(|diag| diag.is_error()));
+    assert!(diags[4].message.contains("physical CPUs 0x3"));
+
+    // Physical CPU ids given to the vCPUs of two VMs.
+    let ids = |config: &str, ids: &str| {
+        config.replacen("cpu_num", &format!("phys_cpu_ids = {ids}\ncpu_num"), 1)
+    };
+    let system = AxSystemConfig::from_toml(
+        &ids(SYSTEM_CONFIG, "[0x100, 0x101]"),
+        load(ids(RTOS_CONFIG, "[0x101]")),
+    )
+    .unwrap();
+    let diags = system.validate_between_vms();
+    assert_eq!(diags.len(), 1);
+    assert_eq!(diags[0].code, "C607");
+    assert_eq!(diags[0].path, "vm[1].base.phys_cpu_ids");
+    assert!(diags[0].message.contains("physical CPU ids 0x101"));
+
+    // Per-VM diagnostics are prefixed with the VM index.
+    let rtos = RTOS_CONFIG.replace("cpu_num = 1", "cpu_num = 2");
+    let system = AxSystemConfig::from_toml(SYSTEM_CONFIG, load(rtos)).unwrap();
+    assert_eq!(system.validate()[0].path, "vm[1].base.phys_cpu_sets");
+    // ... and are not part of the checks between VMs.
+    assert!(system.validate_between_vms().is_empty());
+
+    // Parse errors are located in the system configuration.
+    let err = AxSystemConfig::from_toml("vms = []", load(String::new())).unwrap_err();
+    assert_eq!(err.kind, crate::ConfigParseErrorKind::Schema);
+    assert_eq!(err.location.unwrap().line, 1);
+    let inline = SYSTEM_CONFIG.replace("cpu_num = 2", "cpu_num = \"two\"");
+    let err = AxSystemConfig::from_toml(&inline, load(RTOS_CONFIG.into())).unwrap_err();
+    assert_eq!(err.path, "vm[0].base.cpu_num");
+    assert_eq!(err.location.unwrap().line, 9);
+    let rtos = RTOS_CONFIG.replace("cpu_num = 1", "cpu_num = \"one\"");
+    let err = AxSystemConfig::from_toml(SYSTEM_CONFIG, load(rtos)).unwrap_err();
+    assert_eq!(err.path, "vm_configs[0]");
+    assert_eq!(err.location.unwrap().line, 2);
+    assert!(err
+        .message
+        .starts_with("in 'rtos.toml': base.cpu_num at line 6, column 11: "));
+
+    // Inline VMs can only build on other files when the system config is read from a file.
+    let extends = SYSTEM_CONFIG.replace("[vm.base]", "extends = \"linux.toml\"\n[vm.base]");
+    let err = AxSystemConfig::from_toml(&extends, load(RTOS_CONFIG.into())).unwrap_err();
+    assert_eq!(err.path, "vm[0].extends");
+    assert_eq!(err.location.unwrap().line, 5);
+
+    let dir = std::env::temp_dir().join(format!("axvmconfig-system-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let write = |name: &str, content: &str| fs::write(dir.join(name), content).unwrap();
+    let (linux_base, linux_rest) = SYSTEM_CONFIG.split_once("[vm.kernel]").unwrap();
+    write(
+        "linux.toml",
+        &format!("[kernel]{linux_rest}").replace("[vm.", "["),
+    );
+    write(
+        "system.toml",
+        &linux_base.replace("[vm.base]", "extends = \"linux.toml\"\n[vm.base]"),
+    );
+    write("rtos.toml", RTOS_CONFIG);
+    let system = AxSystemConfig::from_file(dir.join("system.toml")).unwrap();
+    assert_eq!(system.vms.len(), 2);
+    assert_eq!(system.vms[0].kernel.kernel_path, "linux.bin");
+    assert!(system.validate().is_empty());
+
+    // Errors of inline VMs are located in the system config, those of other files are not.
+    write(
+        "system.toml",
+        &extends.replace("cpu_num = 2", "cpu_num = \"two\""),
+    );
+    match AxSystemConfig::from_file(dir.join("system.toml")) {
+        Err(ConfigResolveError::Parse { path, error }) => {
+            assert_eq!(path, dir.join("system.toml"));
+            assert_eq!(error.path, "vm[0].base.cpu_num");
+            assert_eq!(error.location.unwrap().line, 10);
+        }
+        other => panic!("unexpected result {other:?}"),
+    }
+    write(
+        "system.toml",
+        &extends.replace("linux.toml", "missing.toml"),
+    );
+    assert!(matches!(
+        AxSystemConfig::from_file(dir.join("system.toml")),
+        Err(ConfigResolveError::Io { .. })
+    ));
+    write(
+        "rtos.toml",
+        &RTOS_CONFIG.replace("cpu_num = 1", "cpu_num = \"one\""),
+    );
+    write("system.toml", &extends);
+    match AxSystemConfig::from_file(dir.join("system.toml")) {
+        Err(ConfigResolveError::Parse { path, error }) => {
+            assert_eq!(path, dir.join("rtos.toml"));
+            assert_eq!(error.path, "base.cpu_num");
+        }
+        other => panic!("unexpected result {other:?}"),
+    }
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert!(!AxSystemConfig::from_toml("", load(String::new()))
+        .unwrap()
+        .validate()
+        .is_empty());
+}
+
+#[test]
+fn test_platform_validate() {
+    use crate::AxPlatformConfig;
+
+    const PLATFORM: &str = r#"
+name = "board"
+cpu_num = 2
+cpu_ids = [0x100, 0x101]
+memory = [[0x8000_0000, "1G"], { base = 0xc000_0000, size = "1G" }]
+reserved = [[0x8000_0000, "2M", "firmware"]]
+devices = [
+    ["uart0", 0x1000_0000, 0x1000, [10]],
+    { name = "gpio", base = 0x2000_0000, size = 0x1000 },
+]
+    "#;
+
+    const VM_CONFIG: &str = r#"
+[base]
+id = 1
+name = "vm"
+vm_type = 1
+cpu_num = 1
+phys_cpu_ids = [0x101]
+phys_cpu_sets = [2]
+
+[kernel]
+entry_point = 0xbfe0_0000
+kernel_path = "kernel.bin"
+kernel_load_addr = 0xbfe0_0000
+memory_regions = [[0xbfe0_0000, 0x40_0000, 0x7, 1]]
+
+[devices]
+passthrough_devices = [
+    ["uart0", 0x1000_0000, 0x1000_0000, 0x1000, 10],
+    ["gpio", 0x2000_0000, 0x2000_0000, 0x1000, 7],
+]
+emu_devices = []
+    "#;
+
+    let platform = AxPlatformConfig::from_toml(PLATFORM).unwrap();
+    assert_eq!(platform.memory[1].size, 0x4000_0000);
+    let config = AxVMCrateConfig::from_toml(VM_CONFIG).unwrap();
+    // RAM split over two adjacent platform ranges is fine, and devices without IRQs are not
+    // checked for IRQs.
+    assert!(config.validate_against_platform(&platform).is_empty());
+
+    let config = AxVMCrateConfig::from_toml(
+        &VM_CONFIG
+            .replace("[0x101]", "[0x102]")
+            .replace("phys_cpu_sets = [2]", "phys_cpu_sets = [4]")
+            .replace("0xbfe0_0000, 0x40_0000", "0x7fe0_0000, 0x40_0000")
+            .replace("0x1000_0000, 0x1000, 10", "0x1000_0000, 0x1000, 11")
+            .replace(
+                "0x2000_0000, 0x2000_0000, 0x1000",
+                "0x2000_0000, 0x2000_0800, 0x1000",
+            ),
+    )
+    .unwrap();
+    let diags = config.validate_against_platform(&platform);
+    let paths: Vec<&str> = diags.iter().map(|diag| diag.path.as_str()).collect();
+    assert_eq!(
+        paths,
+        [
+            "base.phys_cpu_ids[0]",
+            "base.phys_cpu_sets[0]",
+            "kernel.memory_regions[0]",
+            "kernel.memory_regions[0]",
+            "devices.passthrough_devices[0]",
+            "devices.passthrough_devices[1]",
+        ]
+    );
+    assert!(diags[2].message.contains("is not RAM of the platform"));
+    assert!(diags[3].message.contains("reserved range 'firmware'"));
+    assert!(diags[4].message.contains("IRQ 0xb"));
+
+    let err =
+        AxPlatformConfig::from_toml(&PLATFORM.replace("cpu_num = 2", "cpu_num = 3")).unwrap_err();
+    assert_eq!(err.path, "cpu_ids");
+    assert_eq!(err.location.unwrap().line, 4);
+    let err = AxPlatformConfig::from_toml(&PLATFORM.replace("cpu_num = 2", "cpu_num = \"2\""))
+        .unwrap_err();
+    assert_eq!(err.path, "cpu_num");
+    assert_eq!(err.location.unwrap().line, 3);
+}
+
+#[test]
+fn test_fdt_parse() {
+    use crate::{Fdt, FdtError};
+
+    const DTB: &[u8] = include_bytes!("../testdata/qemu-virt-aarch64.dtb");
+
+    let fdt = Fdt::from_bytes(DTB).unwrap();
+    assert_eq!(fdt.mem_reserve, [(0x4800_0000, 0x10_0000)]);
+    assert_eq!(fdt.root.address_cells(), 2);
+    assert_eq!(fdt.root.prop_str("compatible"), Some("linux,dummy-virt"));
+
+    let uart = fdt.find_node("/pl011@9000000").unwrap();
+    assert!(uart.is_compatible_with("arm,primecell"));
+    assert_eq!(uart.property("interrupts").unwrap().cells(), [0, 1, 4]);
+    assert!(uart.is_enabled());
+    assert!(!fdt.find_node("/pl061@9030000").unwrap().is_enabled());
+    assert!(fdt.find_node("/soc/virtio_mmio@200").is_some());
+    assert!(fdt.find_node("/soc/missing").is_none());
+
+    let (path, gic) = fdt.find_phandle(1).unwrap();
+    assert_eq!(path, "/intc@8000000");
+    assert!(gic.property("interrupt-controller").is_some());
+
+    let mut bad_magic = DTB.to_vec();
+    bad_magic[0] = 0;
+    assert_eq!(
+        Fdt::from_bytes(&bad_magic),
+        Err(FdtError::BadMagic(0x000d_feed))
+    );
+    assert_eq!(Fdt::from_bytes(&DTB[..100]), Err(FdtError::Truncated));
+}
+
+#[test]
+fn test_fdt_passthrough_devices() {
+    use crate::Fdt;
+
+    const DTB: &[u8] = include_bytes!("../testdata/qemu-virt-aarch64.dtb");
+
+    let fdt = Fdt::from_bytes(DTB).unwrap();
+    let devices = fdt.passthrough_devices(&[]);
+    let summary: Vec<(&str, usize, usize, usize)> = devices
+        .iter()
+        .map(|dev| (dev.name.as_str(), dev.base_hpa, dev.length, dev.irq_id))
+        .collect();
+    // CPUs, memory, reserved memory, disabled nodes, nodes without `reg` and devices on buses
+    // that are not memory-mapped (the RTC on I2C) are skipped. Interrupts of the GIC are
+    // translated to GIC interrupt ids.
+    assert_eq!(
+        summary,
+        [
+            ("/intc@8000000", 0x800_0000, 0x1_0000, 0),
+            ("/intc@8000000#1", 0x801_0000, 0x1_0000, 0),
+            ("/pl011@9000000", 0x900_0000, 0x1000, 33),
+            ("/pl031@9010000", 0x901_0000, 0x1000, 34),
+            ("/i2c@9040000", 0x904_0000, 0x1000, 40),
+            ("/soc/virtio_mmio@0", 0xa00_0000, 0x200, 48),
+            ("/soc/virtio_mmio@200", 0xa00_0200, 0x200, 49),
+        ]
+    );
+    assert!(devices.iter().all(|dev| dev.base_gpa == dev.base_hpa));
+
+    let excluded = vec![
+        vec!["/soc/".to_string()],
+        vec!["/pl031@9010000".to_string(), "/i2c@9040000".to_string()],
+    ];
+    let names: Vec<String> = fdt
+        .passthrough_devices(&excluded)
+        .into_iter()
+        .map(|dev| dev.name)
+        .collect();
+    assert_eq!(
+        names,
+        ["/intc@8000000", "/intc@8000000#1", "/pl011@9000000"]
+    );
+}
+
+#[test]
+fn test_guest_fdt() {
+    use crate::{Fdt, GuestArch};
+
+    const GUEST_CONFIG: &str = r#"
+[base]
+id = 1
+name = "linux"
+vm_type = "linux"
+cpu_num = 2
+phys_cpu_ids = [0x0, 0x100]
+
+[kernel]
+entry_point = 0x4008_0000
+kernel_path = "Image"
+kernel_load_addr = 0x4008_0000
+ramdisk_path = "initrd.img"
+ramdisk_load_addr = 0x4800_0000
+cmdline = "console=ttyAMA0 root=/dev/vda"
+memory_regions = [
+    [0x4000_0000, "256M", ["read", "write", "exec"], 0],
+    [0x900_0000, 0x1000, ["read", "write", "device"], 1],
+]
+
+[devices]
+passthrough_devices = [
+    ["/intc@8000000", 0x800_0000, 0x800_0000, 0x1_0000, 0],
+    ["/intc@8000000#1", 0x801_0000, 0x801_0000, 0x1_0000, 0],
+    ["/pl011@9000000", 0x900_0000, 0x900_0000, 0x1000, 33],
+]
+emu_devices = [["virtio-blk", 0xa00_0000, 0x200, 48, 0xE1, []]]
+    "#;
+
+    let config = AxVMCrateConfig::from_toml(GUEST_CONFIG).unwrap();
+    let fdt = config.guest_fdt(GuestArch::Aarch64, Some(0x20_0000), None);
+    assert_eq!(fdt.boot_cpuid_phys, 0);
+
+    let chosen = fdt.find_node("/chosen").unwrap();
+    assert_eq!(
+        chosen.prop_str("bootargs"),
+        Some("console=ttyAMA0 root=/dev/vda")
+    );
+    assert_eq!(
+        chosen.property("linux,initrd-end").unwrap().cells(),
+        [0, 0x4820_0000]
+    );
+    assert_eq!(chosen.prop_str("stdout-path"), Some("/pl011@9000000"));
+    assert_eq!(
+        fdt.find_node("/cpus/cpu@100").unwrap().prop_u32("reg"),
+        Some(0x100)
+    );
+
+    // Only RAM gets a memory node.
+    let memory = fdt.find_node("/memory@40000000").unwrap();
+    assert_eq!(
+        memory.property("reg").unwrap().cells(),
+        [0, 0x4000_0000, 0, 0x1000_0000]
+    );
+    assert!(fdt.find_node("/memory@9000000").is_none());
+
+    // The passthrough GIC becomes the interrupt controller, with both of its regions, and
+    // interrupts are written as GIC specifiers.
+    let (path, gic) = fdt
+        .find_phandle(fdt.root.prop_u32("interrupt-parent").unwrap())
+        .unwrap();
+    assert_eq!(path, "/intc@8000000");
+    assert_eq!(
+        gic.property("reg").unwrap().cells(),
+        [0, 0x800_0000, 0, 0x1_0000, 0, 0x801_0000, 0, 0x1_0000]
+    );
+    let uart = fdt.find_node("/pl011@9000000").unwrap();
+    assert_eq!(uart.property("interrupts").unwrap().cells(), [0, 1, 4]);
+    let virtio = fdt.find_node("/virtio_mmio@a000000").unwrap();
+    assert!(virtio.is_compatible_with("virtio,mmio"));
+    assert_eq!(virtio.property("interrupts").unwrap().cells(), [0, 16, 4]);
+
+    // The blob can be parsed back, and yields the passthrough devices again.
+    let parsed = Fdt::from_bytes(&fdt.to_bytes()).unwrap();
+    assert_eq!(parsed, fdt);
+    let names: Vec<String> = parsed
+        .passthrough_devices(&[])
+        .into_iter()
+        .map(|dev| dev.name)
+        .collect();
+    assert_eq!(
+        names,
+        [
+            "/intc@8000000",
+            "/intc@8000000#1",
+            "/pl011@9000000",
+            "/virtio_mmio@a000000"
+        ]
+    );
+
+    // A GICv2 given as a single region gets both the distributor and CPU interface ranges,
+    // and one too small for both is no interrupt controller.
+    let mut single = config.clone();
+    single.devices.passthrough_devices.remove(1);
+    single.devices.passthrough_devices[0].length = 0x5_0000;
+    let fdt = single.guest_fdt(GuestArch::Aarch64, None, None);
+    assert_eq!(
+        fdt.find_node("/intc@8000000")
+            .unwrap()
+            .property("reg")
+            .unwrap()
+            .cells(),
+        [0, 0x800_0000, 0, 0x1_0000, 0, 0x801_0000, 0, 0x1_0000]
+    );
+    single.devices.passthrough_devices[0].length = 0x1_0000;
+    let fdt = single.guest_fdt(GuestArch::Aarch64, None, None);
+    assert!(fdt.root.property("interrupt-parent").is_none());
+
+    // With the host device tree, devices get their `compatible`, and the interrupt controller
+    // is found by being the interrupt parent, whatever its name.
+    const HOST_DTB: &[u8] = include_bytes!("../testdata/qemu-virt-aarch64.dtb");
+    let host = Fdt::from_bytes(HOST_DTB).unwrap();
+    let mut renamed = config.clone();
+    for (dev, name) in
+        renamed
+            .devices
+            .passthrough_devices
+            .iter_mut()
+            .zip(["irqchip", "irqchip#1", "console"])
+    {
+        dev.name = name.to_string();
+    }
+    assert!(renamed
+        .guest_fdt(GuestArch::Aarch64, None, None)
+        .root
+        .property("interrupt-parent")
+        .is_none());
+    let fdt = renamed.guest_fdt(GuestArch::Aarch64, None, Some(&host));
+    let (path, gic) = fdt
+        .find_phandle(fdt.root.prop_u32("interrupt-parent").unwrap())
+        .unwrap();
+    assert_eq!(path, "/intc@8000000");
+    assert!(gic.is_compatible_with("arm,cortex-a15-gic"));
+    let uart = fdt.find_node("/console@9000000").unwrap();
+    assert_eq!(
+        uart.property("compatible")
+            .unwrap()
+            .strings()
+            .collect::<Vec<_>>(),
+        ["arm,pl011", "arm,primecell"]
+    );
+    assert_eq!(uart.property("interrupts").unwrap().cells(), [0, 1, 4]);
+    let fdt = config.guest_fdt(GuestArch::Aarch64, None, Some(&host));
+    assert!(fdt
+        .find_node("/pl011@9000000")
+        .unwrap()
+        .is_compatible_with("arm,pl011"));
+
+    // Without a known ramdisk size, no initrd range is written.
+    let fdt = config.guest_fdt(GuestArch::Riscv64, None, None);
+    let chosen = fdt.find_node("/chosen").unwrap();
+    assert!(chosen.property("linux,initrd-start").is_none());
+    let cpu = fdt.find_node("/cpus/cpu@100").unwrap();
+    assert!(cpu.child("interrupt-controller").is_some());
+    // No PLIC on riscv64, so no interrupts.
+    assert!(fdt.root.property("interrupt-parent").is_none());
+    assert!(fdt.find_node("/intc@8000000").is_some());
+    assert!(fdt
+        .find_node("/pl011@9000000")
+        .unwrap()
+        .property("interrupts")
+        .is_none());
+}
+
+#[test]
+fn test_fdt_remove_devices() {
+    use crate::Fdt;
+
+    const DTB: &[u8] = include_bytes!("../testdata/qemu-virt-aarch64.dtb");
+
+    // Devices using a removed clock are removed too, and so is the console.
+    let mut fdt = Fdt::from_bytes(DTB).unwrap();
+    let removed = fdt.remove_devices(&[vec!["/apb-pclk".to_string()]]);
+    assert_eq!(removed, ["/apb-pclk", "/pl011@9000000", "/pl031@9010000"]);
+    assert!(fdt.find_node("/pl011@9000000").is_none());
+    assert!(fdt.find_node("/i2c@9040000").is_some());
+    let chosen = fdt.find_node("/chosen").unwrap();
+    assert!(chosen.property("stdout-path").is_none());
+    // The result is a valid device tree.
+    assert_eq!(Fdt::from_bytes(&fdt.to_bytes()).unwrap(), fdt);
+
+    // Without the interrupt controller, every device with interrupts goes, whether it names
+    // the controller itself or inherits it. Buses stay, only their children are removed.
+    let mut fdt = Fdt::from_bytes(DTB).unwrap();
+    let removed = fdt.remove_devices(&[vec!["/intc@8000000/".to_string()]]);
+    assert_eq!(
+        removed,
+        [
+            "/intc@8000000",
+            "/pl011@9000000",
+            "/pl031@9010000",
+            "/pl061@9030000",
+            "/i2c@9040000",
+            "/soc/virtio_mmio@0",
+            "/soc/virtio_mmio@200",
+            "/timer",
+        ]
+    );
+    assert!(fdt.root.property("interrupt-parent").is_none());
+    assert!(fdt.find_node("/soc").unwrap().children.is_empty());
+    assert!(fdt.find_node("/apb-pclk").is_some());
+
+    // Nodes that do not exist are ignored.
+    let mut fdt = Fdt::from_bytes(DTB).unwrap();
+    assert!(fdt
+        .remove_devices(&[vec!["/missing".to_string()]])
+        .is_empty());
+    assert_eq!(fdt, Fdt::from_bytes(DTB).unwrap());
+}
@@ -5,12 +5,19 @@
 use std::env;
 use std::error::Error;
 use std::fs;
+use std::io::Write;
+use std::ops::Range;
 use std::path::Path;
 
 use clap::{Args, Parser, Subcommand};
 
-use crate::templates::get_vm_config_template;
-use crate::AxVMCrateConfig;
+use crate::templates::{get_vm_config_template, Arch};
+use crate::{
+    format_toml, get_config_value, migrate_toml, parse_size, resolve_config, set_config_value,
+    AxPlatformConfig, AxSystemConfig, AxVMCrateConfig, ConfigParseError, ConfigResolveError,
+    Diagnostic, Fdt, GuestArch, ImageLocation, PassThroughDeviceConfig, SourceLocation, VMType,
+    CURRENT_CONFIG_VERSION,
+};
 
 /// Main CLI structure for the axvmconfig tool
 ///
@@ -22,6 +29,7 @@ use crate::AxVMCrateConfig;
 #[command(about = "A simple VM configuration tool for ArceOS-Hypervisor.", long_about = None)]
 #[command(args_conflicts_with_subcommands = true)]
 #[command(flatten_help = true)]
+#[allow(clippy::upper_case_acronyms)]
 pub struct CLI {
     #[command(subcommand)]
     pub subcmd: CLISubCmd,
@@ -29,17 +37,47 @@ pub struct CLI {
 
 /// Available subcommands for the CLI tool
 ///
-/// Currently supports two main operations:
+/// Currently supports the following operations:
 /// - Check: Validate existing TOML configuration files
 /// - Generate: Create new configuration templates from command-line parameters
+/// - Migrate: Upgrade configuration files written for older schema versions
+/// - ImportDtb: Generate passthrough devices from a host device tree blob
+/// - GenDtb: Generate the device tree of the guest from a VM configuration
+/// - FilterDtb: Remove the excluded devices of a VM from a host device tree blob
 #[derive(Subcommand)]
 #[command(args_conflicts_with_subcommands = true)]
 #[command(flatten_help = true)]
 pub enum CLISubCmd {
-    /// Parse the configuration file and check its validity.
+    /// Parse the configuration file and check its validity, including cross-field rules.
     Check(CheckArgs),
     /// Generate a template configuration file.
     Generate(TemplateArgs),
+    /// Upgrade a configuration file written for an older schema version, including the legacy
+    /// flat format, to the current schema.
+    Migrate(MigrateArgs),
+    /// Rewrite configuration files into the canonical layout, keeping their comments.
+    Fmt(FmtArgs),
+    /// Compare two VM configuration files field by field, matching memory regions by address
+    /// and devices by name.
+    Diff(DiffArgs),
+    /// Print the value at a key path of a configuration file, e.g. `kernel.cmdline` or
+    /// `devices.passthrough_devices[name=uart].irq_id`.
+    Get(GetArgs),
+    /// Set the value at a key path of a configuration file, keeping the rest of the file and
+    /// its comments as written.
+    Set(SetArgs),
+    /// Print a configuration file merged with the files it extends or includes, as a
+    /// configuration of its own.
+    Resolve(ResolveArgs),
+    /// Generate `passthrough_devices` entries from the memory-mapped devices of a host device
+    /// tree blob.
+    ImportDtb(ImportDtbArgs),
+    /// Generate the device tree of the guest, with its CPUs, memory, devices and boot
+    /// parameters, from a VM configuration file.
+    GenDtb(GenDtbArgs),
+    /// Derive the device tree of a VM from the host device tree blob, by removing the VM's
+    /// `devices.excluded_devices` and the nodes depending on them.
+    FilterDtb(FilterDtbArgs),
 }
 
 /// Arguments for the 'check' subcommand
@@ -47,9 +85,44 @@ pub enum CLISubCmd {
 /// Used to validate existing TOML configuration files for correctness.
 #[derive(Debug, Args)]
 pub struct CheckArgs {
-    /// Path to the TOML configuration file to validate
+    /// Paths of the TOML configuration files to validate: files, directories (every `*.toml`
+    /// file in them) or glob patterns such as `configs/vms/*.toml`
+    #[arg(short, long = "config-path", num_args = 1.., required = true)]
+    config_paths: Vec<String>,
+    /// Print the guest physical address map of the VM(s)
+    #[arg(long)]
+    gpa_map: bool,
+    /// Treat the files as system configurations listing several VMs, and check them against
+    /// each other
+    #[arg(long, conflicts_with = "gpa_map")]
+    system: bool,
+    /// Check the VM configuration files against each other too, as if one system
+    /// configuration listed them all
+    #[arg(long, conflicts_with = "system")]
+    as_system: bool,
+    /// Path to a platform description file; host resources used by the VM(s) must exist on
+    /// the platform and must not be reserved
     #[arg(short, long)]
-    config_path: String,
+    platform: Option<String>,
+    /// The output format: "text" for people, "json" or "sarif" for CI and code scanning
+    /// tools; the machine-readable formats are printed to stdout without other output
+    #[arg(long, value_enum, default_value_t = CheckFormat::Text)]
+    format: CheckFormat,
+    /// Fail the check on warnings too, exiting with code 5 if a configuration is valid but
+    /// has warnings
+    #[arg(long)]
+    deny_warnings: bool,
+}
+
+/// Output formats of the 'check' subcommand
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum CheckFormat {
+    /// Human-readable messages
+    Text,
+    /// A JSON report with every diagnostic, its code, key path and location
+    Json,
+    /// A SARIF 2.1.0 log
+    Sarif,
 }
 
 /// Arguments for the 'generate' subcommand
@@ -59,23 +132,25 @@ pub struct CheckArgs {
 #[derive(Debug, Args)]
 pub struct TemplateArgs {
     /// The architecture of the VM, currently only support "riscv64", "aarch64" and "x86_64".
+    /// It selects the memory regions, passthrough devices and BIOS settings of the template.
     #[arg(short = 'a', long)]
-    arch: String,
+    arch: Arch,
     /// The ID of the VM.
     #[arg(short = 'i', long, default_value_t = 0)]
     id: usize,
     /// The name of the VM.
     #[arg(short = 'n', long, default_value_t = String::from("GuestVM"))]
     name: String,
-    /// The type of the VM, 0 for HostVM, 1 for RTOS, 2 for Linux.
-    #[arg(short = 't', long, default_value_t = 1)]
-    vm_type: usize,
+    /// The type of the VM, by name (host, rtos, linux) or number (0, 1, 2).
+    #[arg(short = 't', long, default_value_t = VMType::VMTRTOS)]
+    vm_type: VMType,
     /// The number of CPUs of the VM.
     #[arg(short = 'c', long, default_value_t = 1)]
     cpu_num: usize,
-    /// The entry point of the VM.
-    #[arg(short = 'e', long, default_value_t = 1)]
-    entry_point: usize,
+    /// The entry point of the VM, defaults to the BIOS load address on x86_64 and to the
+    /// kernel load address on other architectures.
+    #[arg(short = 'e', long, value_parser = parse_usize)]
+    entry_point: Option<usize>,
     /// The path of the kernel image, if the image_location is "fs", it should be the path of the kernel image file inside the ArceOS's rootfs.
     #[arg(short = 'k', long)]
     kernel_path: String,
@@ -85,8 +160,8 @@ pub struct TemplateArgs {
     /// The location of the kernel image：
     /// - "fs" for the kernel image file inside the ArceOS's rootfs
     /// - "memory" for the kernel image file in the memory.
-    #[arg(long, default_value_t = String::from("fs"))]
-    image_location: String,
+    #[arg(long, default_value_t = ImageLocation::Fs)]
+    image_location: ImageLocation,
     /// The command line of the kernel.
     #[arg(long)]
     cmdline: Option<String>,
@@ -95,12 +170,200 @@ pub struct TemplateArgs {
     output: Option<std::path::PathBuf>,
 }
 
+/// Arguments for the 'migrate' subcommand
+///
+/// Used to upgrade configuration files written for older schema versions, e.g. the legacy flat
+/// format which has no `[base]`/`[kernel]`/`[devices]` tables, to the current schema.
+#[derive(Debug, Args)]
+pub struct MigrateArgs {
+    /// Path to the TOML configuration file to upgrade
+    #[arg(short, long)]
+    config_path: String,
+    /// The output path of the migrated file, the input file is rewritten in place if omitted.
+    #[arg(short = 'O', long, value_hint = clap::ValueHint::FilePath)]
+    output: Option<std::path::PathBuf>,
+}
+
+/// Arguments for the 'fmt' subcommand
+///
+/// Used to rewrite configuration files into one layout, so that reviews only show real changes.
+#[derive(Debug, Args)]
+pub struct FmtArgs {
+    /// Paths of the TOML configuration files to format: files, directories (every `*.toml` file
+    /// in them) or glob patterns such as `configs/vms/*.toml`
+    #[arg(short, long = "config-path", num_args = 1.., required = true)]
+    config_paths: Vec<String>,
+    /// Do not rewrite the files, only list those that are not formatted and fail if there are
+    /// any
+    #[arg(long)]
+    check: bool,
+}
+
+/// Arguments for the 'diff' subcommand
+///
+/// Used to review changes of a VM configuration by what they change rather than by the lines
+/// they touch.
+#[derive(Debug, Args)]
+pub struct DiffArgs {
+    /// Path to the old TOML configuration file
+    old_config: String,
+    /// Path to the new TOML configuration file
+    new_config: String,
+    /// The output format: "text" for people, "json" for tools
+    #[arg(long, value_enum, default_value_t = DiffFormat::Text)]
+    format: DiffFormat,
+}
+
+/// Output formats of the 'diff' subcommand
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum DiffFormat {
+    /// One line per difference
+    Text,
+    /// A JSON list of the differences, with their kind, key path and old and new values
+    Json,
+}
+
+/// Arguments for the 'get' subcommand
+///
+/// Used by scripts to read a single value of a configuration file.
+#[derive(Debug, Args)]
+pub struct GetArgs {
+    /// Path to the TOML configuration file
+    #[arg(short, long)]
+    config_path: String,
+    /// The key path of the value, e.g. `base.cpu_num` or `kernel.memory_regions[0].size`;
+    /// array elements may be selected by a field, e.g. `[name=uart]` or `[gpa=0x8000_0000]`
+    path: String,
+}
+
+/// Arguments for the 'set' subcommand
+///
+/// Used by scripts to change a single value of a configuration file without depending on its
+/// layout.
+#[derive(Debug, Args)]
+pub struct SetArgs {
+    /// Path to the TOML configuration file
+    #[arg(short, long)]
+    config_path: String,
+    /// The key path of the value, written as for 'get'
+    path: String,
+    /// The new value in TOML, e.g. `4`, `0x8000_0000` or `["read", "write"]`; strings may be
+    /// given without quotes
+    value: String,
+    /// The output path of the edited file, the input file is rewritten in place if omitted.
+    #[arg(short = 'O', long, value_hint = clap::ValueHint::FilePath)]
+    output: Option<std::path::PathBuf>,
+    /// Write the file even if the new value introduces validation errors, e.g. when changing
+    /// fields that must agree one at a time
+    #[arg(long)]
+    force: bool,
+}
+
+/// Arguments for the 'resolve' subcommand
+///
+/// Used to see the configuration a file extending or including others amounts to, or to hand it
+/// to tools that do not merge files.
+#[derive(Debug, Args)]
+pub struct ResolveArgs {
+    /// Path to the TOML configuration file
+    #[arg(short, long)]
+    config_path: String,
+    /// The output path of the merged configuration, printed to stdout if omitted
+    #[arg(short = 'O', long, value_hint = clap::ValueHint::FilePath)]
+    output: Option<std::path::PathBuf>,
+}
+
+/// Arguments for the 'import-dtb' subcommand
+///
+/// Used to generate passthrough device entries from a host device tree blob, e.g.
+/// `/sys/firmware/fdt` or a dump of `/proc/device-tree` compiled with `dtc`.
+#[derive(Debug, Args)]
+pub struct ImportDtbArgs {
+    /// Path to the host device tree blob
+    #[arg(short, long, value_hint = clap::ValueHint::FilePath)]
+    dtb_path: std::path::PathBuf,
+    /// Path to a VM configuration file whose `devices.excluded_devices` are skipped
+    #[arg(short, long)]
+    config_path: Option<String>,
+    /// Path of a device tree node to skip, together with its children; may be repeated
+    #[arg(short = 'x', long)]
+    exclude: Vec<String>,
+    /// Print a table of the devices instead of TOML
+    #[arg(long)]
+    list: bool,
+    /// The output path of the TOML snippet, printed to stdout if omitted
+    #[arg(short = 'O', long, value_hint = clap::ValueHint::FilePath)]
+    output: Option<std::path::PathBuf>,
+}
+
+/// Arguments for the 'gen-dtb' subcommand
+///
+/// Used to generate the device tree of a guest from its VM configuration, so that the device
+/// tree does not have to be kept in sync with the configuration by hand.
+#[derive(Debug, Args)]
+pub struct GenDtbArgs {
+    /// Path to the TOML configuration file of the VM
+    #[arg(short, long)]
+    config_path: String,
+    /// The architecture of the guest, "riscv64" or "aarch64"
+    #[arg(short = 'a', long)]
+    arch: Arch,
+    /// Path to the host device tree blob the passthrough devices are taken from; gives them
+    /// their `compatible` and tells which one is the interrupt controller
+    #[arg(short, long, value_hint = clap::ValueHint::FilePath)]
+    dtb_path: Option<std::path::PathBuf>,
+    /// The output format; inferred from the extension of the output path if omitted, and
+    /// "dts" when printing to stdout
+    #[arg(short, long)]
+    format: Option<DtbFormat>,
+    /// The output path of the device tree, printed to stdout if omitted
+    #[arg(short = 'O', long, value_hint = clap::ValueHint::FilePath)]
+    output: Option<std::path::PathBuf>,
+}
+
+/// Arguments for the 'filter-dtb' subcommand
+///
+/// Used for VMs that get every host device except a few, e.g. the host VM: the guest device
+/// tree is the host device tree without the excluded devices.
+#[derive(Debug, Args)]
+pub struct FilterDtbArgs {
+    /// Path to the host device tree blob
+    #[arg(short, long, value_hint = clap::ValueHint::FilePath)]
+    dtb_path: std::path::PathBuf,
+    /// Path to a VM configuration file whose `devices.excluded_devices` are removed
+    #[arg(short, long)]
+    config_path: Option<String>,
+    /// Path of a device tree node to remove, together with its children; may be repeated
+    #[arg(short = 'x', long)]
+    exclude: Vec<String>,
+    /// The output format; inferred from the extension of the output path if omitted, and
+    /// "dts" when printing to stdout
+    #[arg(short, long)]
+    format: Option<DtbFormat>,
+    /// The output path of the device tree, printed to stdout if omitted
+    #[arg(short = 'O', long, value_hint = clap::ValueHint::FilePath)]
+    output: Option<std::path::PathBuf>,
+}
+
+/// Output formats of a generated device tree
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum DtbFormat {
+    /// Flattened device tree blob
+    Dtb,
+    /// Device tree source
+    Dts,
+}
+
 /// Parse numeric values from command line arguments
 ///
 /// Supports multiple number formats:
 /// - Hexadecimal (0x prefix): e.g., 0x80200000
-/// - Binary (0b prefix): e.g., 0b10101010  
+/// - Octal (0o prefix): e.g., 0o1000
+/// - Binary (0b prefix): e.g., 0b10101010
 /// - Decimal: e.g., 123456
+/// - Decimal with a binary unit suffix: e.g., 4K, 16MiB, 2G
+///
+/// Digits may be grouped with underscores, e.g., 0x8020_0000.
 ///
 /// # Arguments
 /// * `s` - String slice containing the number to parse
@@ -108,61 +371,1079 @@ pub struct TemplateArgs {
 /// # Returns
 /// * `Result<usize, Box<dyn Error + Send + Sync + 'static>>` - Parsed number or error
 fn parse_usize(s: &str) -> Result<usize, Box<dyn Error + Send + Sync + 'static>> {
-    if s.starts_with("0x") {
-        // Parse hexadecimal number
-        Ok(usize::from_str_radix(&s[2..], 16)?)
-    } else if s.starts_with("0b") {
-        // Parse binary number
-        Ok(usize::from_str_radix(&s[2..], 2)?)
-    } else {
-        // Parse decimal number
-        Ok(s.parse()?)
-    }
+    Ok(parse_size(s)?)
 }
 
-/// Main entry point for the CLI tool
+/// Look up the size of an image file on the host
 ///
-/// Parses command line arguments and dispatches to appropriate handlers
-/// for either configuration validation or template generation.
-pub fn run() {
-    let cli = CLI::parse();
-    match cli.subcmd {
-        // Handle configuration file validation
-        CLISubCmd::Check(args) => {
-            let file_path = &args.config_path;
+/// Relative paths are tried against the current directory first, then against the directory
+/// containing the configuration file.
+///
+/// # Returns
+/// * `Option<usize>` - Size of the image in bytes, or `None` if it cannot be found
+fn host_image_size(config_dir: &Path, image: &str) -> Option<usize> {
+    [Path::new(image).to_path_buf(), config_dir.join(image)]
+        .iter()
+        .find_map(|path| fs::metadata(path).ok())
+        .filter(|meta| meta.is_file())
+        .map(|meta| meta.len() as usize)
+}
 
-            // Check if the specified file exists
-            if !Path::new(file_path).exists() {
-                eprintln!("Error: File '{}' does not exist.", file_path);
-                std::process::exit(1);
-            }
+/// Load a platform description file
+///
+/// Exits the process if the file cannot be read or parsed.
+fn load_platform(file_path: &str) -> AxPlatformConfig {
+    let file_content = match fs::read_to_string(file_path) {
+        Ok(content) => content,
+        Err(err) => {
+            eprintln!("Error: Failed to read file '{}': {}", file_path, err);
+            std::process::exit(1);
+        }
+    };
+    match AxPlatformConfig::from_toml(&file_content) {
+        Ok(platform) => platform,
+        Err(err) => {
+            eprintln!("Error: Platform file '{}' is invalid: {}", file_path, err);
+            std::process::exit(1);
+        }
+    }
+}
 
-            // Read the configuration file content
-            let file_content = match fs::read_to_string(file_path) {
-                Ok(content) => content,
+/// Expand the paths given to `check` into the configuration files to check
+///
+/// Directories stand for the `*.toml` files directly in them, and paths containing `*`, `?` or
+/// `[` are glob patterns. Files are checked once, in the order they are first named. Exits the
+/// process if a pattern is invalid or a directory or pattern names no files.
+fn collect_config_files(paths: &[String]) -> Vec<String> {
+    let mut files = Vec::new();
+    for path in paths {
+        let matched = if path.contains(['*', '?', '[']) {
+            match glob::glob(path) {
+                Ok(entries) => entries
+                    .filter_map(Result::ok)
+                    .filter(|entry| entry.is_file())
+                    .map(|entry| entry.display().to_string())
+                    .collect(),
                 Err(err) => {
-                    eprintln!("Error: Failed to read file '{}': {}", file_path, err);
-                    std::process::exit(1);
+                    eprintln!("Error: Invalid pattern '{}': {}", path, err);
+                    std::process::exit(EXIT_IO_ERROR);
+                }
+            }
+        } else if Path::new(path).is_dir() {
+            let mut entries: Vec<_> = match fs::read_dir(path) {
+                Ok(entries) => entries
+                    .filter_map(Result::ok)
+                    .map(|entry| entry.path())
+                    .filter(|entry| entry.is_file() && entry.extension() == Some("toml".as_ref()))
+                    .map(|entry| entry.display().to_string())
+                    .collect(),
+                Err(err) => {
+                    eprintln!("Error: Failed to read directory '{}': {}", path, err);
+                    std::process::exit(EXIT_IO_ERROR);
                 }
             };
+            entries.sort();
+            entries
+        } else {
+            // Missing files are reported by the check itself
+            vec![path.clone()]
+        };
+        if matched.is_empty() {
+            eprintln!("Error: No configuration files found for '{}'", path);
+            std::process::exit(EXIT_IO_ERROR);
+        }
+        for file in matched {
+            if !files.contains(&file) {
+                files.push(file);
+            }
+        }
+    }
+    files
+}
+
+/// Exit code of `check` if a file cannot be read
+const EXIT_IO_ERROR: i32 = 1;
+/// Exit code of `check` if a file is not valid TOML or does not match the schema
+const EXIT_PARSE_ERROR: i32 = 3;
+/// Exit code of `check` if a configuration has validation errors
+const EXIT_INVALID: i32 = 4;
+/// Exit code of `check --deny-warnings` if a configuration is valid but has warnings
+const EXIT_WARNINGS: i32 = 5;
+
+/// A diagnostic of a checked file, with the byte range of the file it refers to, if known
+struct Finding {
+    diag: Diagnostic,
+    span: Option<Range<usize>>,
+    /// The offending source line, printed under the diagnostic in text mode
+    snippet: Option<String>,
+}
+
+/// The outcome of checking one configuration file
+struct CheckReport {
+    file: String,
+    source: String,
+    findings: Vec<Finding>,
+    /// The exit code for failures that stop the check before validation, i.e. I/O and parse
+    /// errors
+    failure: Option<i32>,
+}
+
+impl CheckReport {
+    /// Read the file to check; a read error is recorded as the outcome of the check
+    fn read(file: &str) -> Self {
+        let mut report = Self {
+            file: file.to_string(),
+            source: String::new(),
+            findings: Vec::new(),
+            failure: None,
+        };
+        match fs::read_to_string(file) {
+            Ok(source) => report.source = source,
+            Err(err) => report.fail(
+                EXIT_IO_ERROR,
+                Diagnostic::error("C000", "", format!("failed to read file: {}", err)),
+                None,
+            ),
+        }
+        report
+    }
+
+    /// Record a failure that stops the check
+    fn fail(&mut self, exit_code: i32, diag: Diagnostic, span: Option<Range<usize>>) {
+        self.findings.push(Finding {
+            diag,
+            span,
+            snippet: None,
+        });
+        self.failure = Some(exit_code);
+    }
 
-            // Parse and validate the TOML configuration
-            match AxVMCrateConfig::from_toml(&file_content) {
-                Ok(config) => {
-                    println!("Config file '{}' is valid.", file_path);
-                    println!("Config: {:#x?}", config);
+    /// Record a configuration that cannot be parsed
+    fn parse_failed(&mut self, err: ConfigParseError) {
+        self.fail(EXIT_PARSE_ERROR, err.to_diagnostic(), err.span());
+        if let Some(finding) = self.findings.last_mut() {
+            finding.snippet = err.excerpt.map(|excerpt| excerpt.snippet);
+        }
+    }
+
+    /// Record a configuration that cannot be merged with the files it extends or includes, or
+    /// parsed
+    fn resolve_failed(&mut self, err: ConfigResolveError) {
+        match err {
+            ConfigResolveError::Parse { path, error } if path == Path::new(&self.file) => {
+                self.parse_failed(error)
+            }
+            // Problems in other files are not located in this one
+            ConfigResolveError::Parse { path, error } => {
+                let location = error
+                    .location
+                    .map(|location| {
+                        format!(" at line {}, column {}", location.line, location.column)
+                    })
+                    .unwrap_or_default();
+                let message = format!("in '{}'{}: {}", path.display(), location, error.message);
+                let diag = Diagnostic::error(error.code(), error.path, message);
+                self.fail(EXIT_PARSE_ERROR, diag, None);
+            }
+            ConfigResolveError::Io { .. } => {
+                let diag = Diagnostic::error("C000", "", err.to_string());
+                self.fail(EXIT_IO_ERROR, diag, None);
+            }
+            ConfigResolveError::Cycle(_) => {
+                let diag = Diagnostic::error("C002", "extends", err.to_string());
+                self.fail(EXIT_PARSE_ERROR, diag, None);
+            }
+        }
+    }
+
+    /// Add validation diagnostics, locating them in the file by their key path
+    fn extend(&mut self, diags: impl IntoIterator<Item = Diagnostic>) {
+        for diag in diags {
+            let span = diag.span_in(&self.source);
+            self.findings.push(Finding {
+                diag,
+                span,
+                snippet: None,
+            });
+        }
+    }
+
+    fn errors(&self) -> usize {
+        self.findings
+            .iter()
+            .filter(|finding| finding.diag.is_error())
+            .count()
+    }
+
+    fn exit_code(&self) -> i32 {
+        match self.failure {
+            Some(code) => code,
+            None if self.errors() > 0 => EXIT_INVALID,
+            None if !self.findings.is_empty() => EXIT_WARNINGS,
+            None => 0,
+        }
+    }
+
+    /// A short word for the outcome, as used in the machine-readable reports
+    fn status(&self) -> &'static str {
+        match self.exit_code() {
+            0 => "valid",
+            EXIT_IO_ERROR => "io-error",
+            EXIT_PARSE_ERROR => "parse-error",
+            EXIT_INVALID => "invalid",
+            _ => "warnings",
+        }
+    }
+
+    /// Whether the file passes the check, i.e. has no errors, and no warnings either if
+    /// `deny_warnings` is set
+    fn passed(&self, deny_warnings: bool) -> bool {
+        match self.exit_code() {
+            0 => true,
+            EXIT_WARNINGS => !deny_warnings,
+            _ => false,
+        }
+    }
+
+    /// The start and end of a finding as 1-based lines and columns
+    fn locate(&self, finding: &Finding) -> Option<(SourceLocation, SourceLocation)> {
+        let span = finding.span.as_ref()?;
+        Some((
+            SourceLocation::from_offset(&self.source, span.start),
+            SourceLocation::from_offset(&self.source, span.end),
+        ))
+    }
+
+    /// Print the findings to stderr, prefixed with their location like compiler messages
+    fn print_text(&self) {
+        for finding in &self.findings {
+            match self.locate(finding) {
+                Some((start, _)) => eprintln!(
+                    "{}:{}:{}: {}",
+                    self.file, start.line, start.column, finding.diag
+                ),
+                None => eprintln!("{}: {}", self.file, finding.diag),
+            }
+            if let Some(snippet) = &finding.snippet {
+                eprintln!("{}", snippet);
+            }
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        let diagnostics: Vec<_> = self
+            .findings
+            .iter()
+            .map(|finding| {
+                let span = self.locate(finding).map(|(start, end)| {
+                    serde_json::json!({
+                        "start": { "line": start.line, "column": start.column },
+                        "end": { "line": end.line, "column": end.column },
+                    })
+                });
+                serde_json::json!({
+                    "code": finding.diag.code,
+                    "severity": finding.diag.severity.to_string(),
+                    "path": finding.diag.path,
+                    "message": finding.diag.message,
+                    "span": span,
+                })
+            })
+            .collect();
+        let errors = self.errors();
+        serde_json::json!({
+            "file": self.file,
+            "status": self.status(),
+            "errors": errors,
+            "warnings": self.findings.len() - errors,
+            "diagnostics": diagnostics,
+        })
+    }
+
+    fn to_sarif_results(&self) -> Vec<serde_json::Value> {
+        self.findings
+            .iter()
+            .map(|finding| {
+                let mut location = serde_json::json!({
+                    "physicalLocation": { "artifactLocation": { "uri": self.file } },
+                });
+                if let Some((start, end)) = self.locate(finding) {
+                    location["physicalLocation"]["region"] = serde_json::json!({
+                        "startLine": start.line,
+                        "startColumn": start.column,
+                        "endLine": end.line,
+                        "endColumn": end.column,
+                    });
                 }
+                if !finding.diag.path.is_empty() {
+                    location["logicalLocations"] =
+                        serde_json::json!([{ "fullyQualifiedName": finding.diag.path }]);
+                }
+                serde_json::json!({
+                    "ruleId": finding.diag.code,
+                    "level": finding.diag.severity.to_string(),
+                    "message": { "text": finding.diag.message },
+                    "locations": [location],
+                })
+            })
+            .collect()
+    }
+}
+
+/// The exit code of checking several files: that of the most severe outcome, where warnings
+/// only count if `deny_warnings` is set
+fn worst_exit_code(reports: &[CheckReport], deny_warnings: bool) -> i32 {
+    const BY_SEVERITY: [i32; 5] = [
+        0,
+        EXIT_WARNINGS,
+        EXIT_INVALID,
+        EXIT_PARSE_ERROR,
+        EXIT_IO_ERROR,
+    ];
+    let worst = reports
+        .iter()
+        .map(CheckReport::exit_code)
+        .max_by_key(|code| BY_SEVERITY.iter().position(|c| c == code))
+        .unwrap_or(0);
+    match worst {
+        EXIT_WARNINGS if !deny_warnings => 0,
+        code => code,
+    }
+}
+
+/// Print a table with the outcome of every checked file
+fn print_summary_table(reports: &[CheckReport], deny_warnings: bool) {
+    let width = reports
+        .iter()
+        .map(|report| report.file.len())
+        .chain([4])
+        .max()
+        .unwrap_or(0);
+    println!();
+    println!(
+        "{:<width$}  {:<6}  {:<11}  {:>6}  {:>8}",
+        "FILE", "RESULT", "STATUS", "ERRORS", "WARNINGS"
+    );
+    for report in reports {
+        let errors = report.errors();
+        println!(
+            "{:<width$}  {:<6}  {:<11}  {:>6}  {:>8}",
+            report.file,
+            if report.passed(deny_warnings) {
+                "pass"
+            } else {
+                "fail"
+            },
+            report.status(),
+            errors,
+            report.findings.len() - errors
+        );
+    }
+    let failed = reports
+        .iter()
+        .filter(|report| !report.passed(deny_warnings))
+        .count();
+    if failed > 0 {
+        eprintln!(
+            "Error: {} of {} config file(s) failed the check.",
+            failed,
+            reports.len()
+        );
+    } else {
+        println!("All {} config file(s) passed the check.", reports.len());
+    }
+}
+
+/// Print the reports of checked files in a machine-readable format
+fn print_reports(reports: &[CheckReport], format: CheckFormat) {
+    let output = match format {
+        CheckFormat::Text => return,
+        CheckFormat::Json => serde_json::json!({
+            "files": reports.iter().map(CheckReport::to_json).collect::<Vec<_>>(),
+        }),
+        CheckFormat::Sarif => {
+            let results: Vec<_> = reports
+                .iter()
+                .flat_map(CheckReport::to_sarif_results)
+                .collect();
+            serde_json::json!({
+                "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+                "version": "2.1.0",
+                "runs": [{
+                    "tool": {
+                        "driver": {
+                            "name": "axvmconfig",
+                            "version": env!("CARGO_PKG_VERSION"),
+                            "informationUri": env!("CARGO_PKG_HOMEPAGE"),
+                        }
+                    },
+                    "results": results,
+                }],
+            })
+        }
+    };
+    println!("{}", serde_json::to_string_pretty(&output).unwrap());
+}
+
+/// Check a VM configuration file
+///
+/// Returns the configuration along with the report if it could be parsed. If `verbose`, the
+/// schema version is printed as the file is checked.
+fn check_vm(
+    file_path: &str,
+    platform: Option<&AxPlatformConfig>,
+    verbose: bool,
+) -> (CheckReport, Option<AxVMCrateConfig>) {
+    let mut report = CheckReport::read(file_path);
+    if report.failure.is_some() {
+        return (report, None);
+    }
+
+    // Parse the TOML configuration, merged with the files it extends or includes
+    let (config, upgrade) = match AxVMCrateConfig::from_file_with_upgrade(file_path) {
+        Ok(result) => result,
+        Err(err) => {
+            report.resolve_failed(err);
+            return (report, None);
+        }
+    };
+
+    // Report the schema version and anything the upgrade had to fill in
+    if verbose {
+        println!(
+            "Config file '{}' is written for config_version {}{}.",
+            file_path,
+            upgrade.source_version,
+            if upgrade.inferred { " (inferred)" } else { "" }
+        );
+    }
+    report.extend(upgrade.warnings);
+
+    // Run the semantic checks and report every problem found, using the real sizes of
+    // images that are read from the host
+    let config_dir = Path::new(file_path).parent().unwrap_or(Path::new("."));
+    report.extend(config.validate_with_image_sizes(|image| host_image_size(config_dir, image)));
+    if let Some(platform) = platform {
+        report.extend(config.validate_against_platform(platform));
+    }
+    (report, Some(config))
+}
+
+/// Print the guest physical address map of a VM
+fn print_gpa_map(config: &AxVMCrateConfig) {
+    for range in config.gpa_map().ranges() {
+        println!("  {}", range);
+    }
+}
+
+/// Print a summary of a valid VM configuration
+fn print_vm_summary(args: &CheckArgs, file_path: &str, config: &AxVMCrateConfig) {
+    if args.gpa_map {
+        println!("Guest physical address map:");
+        print_gpa_map(config);
+    }
+
+    println!("Config file '{}' is valid.", file_path);
+    println!("Memory regions:");
+    for region in &config.kernel.memory_regions {
+        println!(
+            "  [{:#x}, {:#x}) {} {:?}",
+            region.gpa,
+            region.gpa.saturating_add(region.size),
+            region.flags,
+            region.map_type
+        );
+    }
+}
+
+/// Check a system configuration file and the VMs it lists
+///
+/// VM configuration files listed in `vm_configs` are resolved relative to the directory of the
+/// system configuration file. If `verbose`, the VMs are listed as the file is checked.
+fn check_system(
+    file_path: &str,
+    platform: Option<&AxPlatformConfig>,
+    verbose: bool,
+) -> CheckReport {
+    let mut report = CheckReport::read(file_path);
+    if report.failure.is_some() {
+        return report;
+    }
+
+    // Parse the system configuration, with its VMs merged with the files they extend or include
+    let system = match AxSystemConfig::from_file(file_path) {
+        Ok(system) => system,
+        Err(err) => {
+            report.resolve_failed(err);
+            return report;
+        }
+    };
+
+    if verbose {
+        println!(
+            "System config file '{}' lists {} VM(s):",
+            file_path,
+            system.vms.len()
+        );
+        for (i, vm) in system.vms.iter().enumerate() {
+            println!("  vm[{}]: id {} '{}'", i, vm.base.id, vm.base.name);
+        }
+    }
+
+    report.extend(system.validate());
+    if let Some(platform) = platform {
+        report.extend(system.validate_against_platform(platform));
+    }
+    report
+}
+
+/// Check the VM configurations of several files against each other
+///
+/// The files that could be parsed make up one system, with the VMs numbered in the order of
+/// the files. Each problem is reported on the file of the later VM involved.
+fn check_between_vms(
+    reports: &mut [CheckReport],
+    configs: &[Option<AxVMCrateConfig>],
+    verbose: bool,
+) {
+    let (indices, vms): (Vec<_>, Vec<_>) = configs
+        .iter()
+        .enumerate()
+        .filter_map(|(i, config)| Some((i, config.clone()?)))
+        .unzip();
+    if verbose {
+        println!("Checking {} VM(s) against each other:", vms.len());
+        for (vm_index, (vm, &i)) in vms.iter().zip(&indices).enumerate() {
+            println!(
+                "  vm[{}]: id {} '{}' from '{}'",
+                vm_index, vm.base.id, vm.base.name, reports[i].file
+            );
+        }
+    }
+
+    let system = AxSystemConfig { vms };
+    for diag in system.validate_between_vms() {
+        // Paths are prefixed with `vm[i].`, where `i` indexes `indices`
+        let Some((vm, path)) = diag
+            .path
+            .strip_prefix("vm[")
+            .and_then(|rest| rest.split_once("]."))
+        else {
+            continue;
+        };
+        let Some(&file) = vm.parse::<usize>().ok().and_then(|vm| indices.get(vm)) else {
+            continue;
+        };
+        let diag = Diagnostic {
+            path: path.to_string(),
+            ..diag
+        };
+        reports[file].extend([diag]);
+    }
+}
+
+/// Check VM or system configuration files and exit with a code describing the outcome
+///
+/// A single file is reported in detail; several files are followed by a summary table, and the
+/// exit code is that of the most severe outcome.
+fn check(args: CheckArgs) {
+    let platform = args.platform.as_deref().map(load_platform);
+    let files = collect_config_files(&args.config_paths);
+    let text = args.format == CheckFormat::Text;
+    let single = files.len() == 1 && !args.as_system;
+
+    let mut reports = Vec::with_capacity(files.len());
+    let mut configs = Vec::with_capacity(files.len());
+    for file in &files {
+        if args.system {
+            reports.push(check_system(file, platform.as_ref(), text && single));
+            configs.push(None);
+        } else {
+            let (report, config) = check_vm(file, platform.as_ref(), text && single);
+            reports.push(report);
+            configs.push(config);
+        }
+    }
+    if args.as_system {
+        check_between_vms(&mut reports, &configs, text);
+    }
+
+    if !text {
+        print_reports(&reports, args.format);
+        std::process::exit(worst_exit_code(&reports, args.deny_warnings));
+    }
+    for (report, config) in reports.iter().zip(&configs) {
+        report.print_text();
+        let errors = report.errors();
+        if errors > 0 || report.failure.is_some() {
+            eprintln!(
+                "Error: Config file '{}' is invalid: {} error(s), {} warning(s).",
+                report.file,
+                errors,
+                report.findings.len() - errors
+            );
+        } else if let Some(config) = config {
+            if single {
+                print_vm_summary(&args, &report.file, config);
+            } else if args.gpa_map {
+                println!("Guest physical address map of '{}':", report.file);
+                print_gpa_map(config);
+            }
+        } else if single {
+            println!("System config file '{}' is valid.", report.file);
+        }
+    }
+    if !single {
+        print_summary_table(&reports, args.deny_warnings);
+    }
+    std::process::exit(worst_exit_code(&reports, args.deny_warnings));
+}
+
+/// Format configuration files, or with `--check` list those that are not formatted
+///
+/// Exits with 1 if a file cannot be read, parsed or written, or with `--check` if a file is not
+/// formatted.
+fn fmt(args: FmtArgs) {
+    let mut failed = false;
+    for file_path in collect_config_files(&args.config_paths) {
+        let formatted = fs::read_to_string(&file_path)
+            .map_err(|err| format!("failed to read file: {}", err))
+            .and_then(|content| {
+                let formatted = format_toml(&content).map_err(|err| err.to_string())?;
+                Ok((formatted != content).then_some(formatted))
+            });
+        match formatted {
+            Ok(None) => {}
+            Ok(Some(_)) if args.check => {
+                println!("Config file '{}' is not formatted.", file_path);
+                failed = true;
+            }
+            Ok(Some(formatted)) => match fs::write(&file_path, formatted) {
+                Ok(_) => println!("Config file '{}' has been formatted.", file_path),
                 Err(err) => {
-                    eprintln!("Error: Config file '{}' is invalid: {}", file_path, err);
-                    std::process::exit(1);
+                    eprintln!(
+                        "Error: Failed to write config file '{}': {}",
+                        file_path, err
+                    );
+                    failed = true;
                 }
+            },
+            Err(err) => {
+                eprintln!("Error: Config file '{}' is invalid: {}", file_path, err);
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+/// Read and parse a VM configuration file, upgrading it to the current schema
+///
+/// Files with `extends` or `include` are merged with the files they name.
+fn load_vm_config(file_path: &str) -> Result<AxVMCrateConfig, String> {
+    AxVMCrateConfig::from_file(file_path).map_err(|err| err.to_string())
+}
+
+/// Print the differences between two VM configuration files
+///
+/// Exits with 0 if the configurations are the same, 1 if they differ and 2 if a file cannot be
+/// read or parsed, like `diff`.
+fn diff(args: DiffArgs) {
+    let load = |file_path: &str| match load_vm_config(file_path) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("Error: Config file '{}' is invalid: {}", file_path, err);
+            std::process::exit(2);
+        }
+    };
+    let (old, new) = (load(&args.old_config), load(&args.new_config));
+    let changes = old.diff(&new);
+
+    match args.format {
+        DiffFormat::Text if changes.is_empty() => println!("No differences."),
+        DiffFormat::Text => {
+            for change in &changes {
+                println!("{}", change);
+            }
+        }
+        DiffFormat::Json => {
+            let output = serde_json::json!({
+                "old": args.old_config,
+                "new": args.new_config,
+                "changes": changes,
+            });
+            println!("{}", serde_json::to_string_pretty(&output).unwrap());
+        }
+    }
+    std::process::exit(if changes.is_empty() { 0 } else { 1 });
+}
+
+/// Read a configuration file
+///
+/// Exits the process if the file cannot be read.
+fn read_config_file(file_path: &str) -> String {
+    match fs::read_to_string(file_path) {
+        Ok(content) => content,
+        Err(err) => {
+            eprintln!("Error: Failed to read file '{}': {}", file_path, err);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Print the value at a key path of a configuration file
+///
+/// Exits with 1 if the file cannot be read or parsed, or if there is no value at the path.
+fn get(args: GetArgs) {
+    let content = read_config_file(&args.config_path);
+    match get_config_value(&content, &args.path) {
+        Ok(value) => println!("{}", value),
+        Err(err) => {
+            eprintln!("Error: Config file '{}': {}", args.config_path, err);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Set the value at a key path of a configuration file
+///
+/// Exits with 1 if the file cannot be read, parsed or written, if there is no value at the
+/// path, or if the configuration would be invalid with the new value, or have validation
+/// errors it did not have before unless forced; the file is left untouched then.
+fn set(args: SetArgs) {
+    let content = read_config_file(&args.config_path);
+    let edited = match set_config_value(&content, &args.path, &args.value) {
+        Ok(edited) => edited,
+        Err(err) => {
+            eprintln!("Error: Config file '{}': {}", args.config_path, err);
+            std::process::exit(1);
+        }
+    };
+
+    // Files extending or including others are checked merged with them
+    let load = |source: &str| -> Result<AxVMCrateConfig, String> {
+        let resolved =
+            resolve_config(source, Path::new(&args.config_path)).map_err(|err| err.to_string())?;
+        AxVMCrateConfig::from_toml_with_upgrade(&resolved)
+            .map(|(config, _)| config)
+            .map_err(|err| format!("{}: {}", err.path, err.message))
+    };
+    let config = match load(&edited) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!(
+                "Error: Config file '{}': the new value makes the merged configuration invalid: {}",
+                args.config_path, err
+            );
+            std::process::exit(1);
+        }
+    };
+
+    // Refuse to introduce validation errors, so that scripted edits cannot silently break the
+    // configuration; errors the file already had do not block fixing it
+    let known = load(&content)
+        .map(|config| config.validate())
+        .unwrap_or_default();
+    let introduced: Vec<Diagnostic> = config
+        .validate()
+        .into_iter()
+        .filter(|diag| {
+            diag.is_error()
+                && !known
+                    .iter()
+                    .any(|old| old.code == diag.code && old.path == diag.path)
+        })
+        .collect();
+    if !introduced.is_empty() {
+        for diag in &introduced {
+            eprintln!("{}", diag);
+        }
+        if !args.force {
+            eprintln!(
+                "Error: Config file '{}': the new value makes the configuration invalid, use --force to write it anyway.",
+                args.config_path
+            );
+            std::process::exit(1);
+        }
+    }
+
+    // Rewrite the file in place unless an output path is given
+    let target_path = args
+        .output
+        .unwrap_or_else(|| std::path::PathBuf::from(&args.config_path));
+    if let Err(err) = fs::write(&target_path, edited) {
+        eprintln!(
+            "Error: Failed to write config file '{:?}': {}",
+            target_path, err
+        );
+        std::process::exit(1);
+    }
+}
+
+/// Print or write a configuration file merged with the files it extends or includes
+///
+/// Exits with 1 if a file cannot be read or merged, or the merged configuration is invalid.
+fn resolve(args: ResolveArgs) {
+    let config = match load_vm_config(&args.config_path) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!(
+                "Error: Config file '{}' is invalid: {}",
+                args.config_path, err
+            );
+            std::process::exit(1);
+        }
+    };
+    let config_toml = config.to_toml();
+    match args.output {
+        None => print!("{}", config_toml),
+        Some(target_path) => match fs::write(&target_path, config_toml) {
+            Ok(_) => println!(
+                "Config file '{}' has been resolved to '{:?}'.",
+                args.config_path, target_path
+            ),
+            Err(err) => {
+                eprintln!(
+                    "Error: Failed to write config file '{:?}': {}",
+                    target_path, err
+                );
+                std::process::exit(1);
+            }
+        },
+    }
+}
+
+/// Format passthrough devices as a `passthrough_devices` array in the tuple form
+fn format_passthrough_devices(devices: &[PassThroughDeviceConfig]) -> String {
+    let mut out =
+        String::from("# Passthrough devices format: [name, base_gpa, base_hpa, length, irq_id]\n");
+    out.push_str("passthrough_devices = [\n");
+    for dev in devices {
+        out.push_str(&format!(
+            "    [{:?}, {:#x}, {:#x}, {:#x}, {:#x}],\n",
+            dev.name, dev.base_gpa, dev.base_hpa, dev.length, dev.irq_id
+        ));
+    }
+    out.push_str("]\n");
+    out
+}
+
+/// Read and parse a device tree blob
+///
+/// Exits the process if the file cannot be read or is not a valid device tree.
+fn read_fdt(dtb_path: &Path) -> Fdt {
+    let dtb = match fs::read(dtb_path) {
+        Ok(dtb) => dtb,
+        Err(err) => {
+            eprintln!(
+                "Error: Failed to read file '{}': {}",
+                dtb_path.display(),
+                err
+            );
+            std::process::exit(1);
+        }
+    };
+    match Fdt::from_bytes(&dtb) {
+        Ok(fdt) => fdt,
+        Err(err) => {
+            eprintln!(
+                "Error: Device tree '{}' is invalid: {}",
+                dtb_path.display(),
+                err
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Collect the excluded device tree nodes of a VM configuration file, plus those given on the
+/// command line
+///
+/// Exits the process if the configuration file cannot be read or parsed.
+fn excluded_devices(config_path: Option<&str>, exclude: Vec<String>) -> Vec<Vec<String>> {
+    let mut excluded = Vec::new();
+    if let Some(config_path) = config_path {
+        match load_vm_config(config_path) {
+            Ok(config) => excluded.extend(config.devices.excluded_devices),
+            Err(err) => {
+                eprintln!("Error: Config file '{}' is invalid: {}", config_path, err);
+                std::process::exit(1);
             }
         }
+    }
+    excluded.push(exclude);
+    excluded
+}
+
+/// Write a device tree as a blob or as source, to `output` or to stdout
+///
+/// Without an explicit format, the extension of `output` decides (`.dts` for source), and
+/// source is printed to stdout. Exits the process if writing fails.
+fn write_fdt(fdt: &Fdt, format: Option<DtbFormat>, output: Option<&Path>) {
+    let format = format.unwrap_or(match output.map(|path| path.extension()) {
+        Some(Some(ext)) if ext == "dts" => DtbFormat::Dts,
+        Some(_) => DtbFormat::Dtb,
+        None => DtbFormat::Dts,
+    });
+    let bytes = match format {
+        DtbFormat::Dtb => fdt.to_bytes(),
+        DtbFormat::Dts => fdt.to_dts().into_bytes(),
+    };
+    let result = match output {
+        None => std::io::stdout().write_all(&bytes),
+        Some(path) => fs::write(path, bytes),
+    };
+    if let Err(err) = result {
+        let target = output.map_or(String::from("stdout"), |path| {
+            format!("file '{}'", path.display())
+        });
+        eprintln!("Error: Failed to write {}: {}", target, err);
+        std::process::exit(1);
+    }
+}
+
+/// Import passthrough devices from a host device tree blob
+///
+/// Exits the process if the device tree or the configuration file cannot be read.
+fn import_dtb(args: ImportDtbArgs) {
+    let fdt = read_fdt(&args.dtb_path);
+    let excluded = excluded_devices(args.config_path.as_deref(), args.exclude);
+
+    let devices = fdt.passthrough_devices(&excluded);
+    eprintln!(
+        "Found {} memory-mapped device region(s) in '{}'.",
+        devices.len(),
+        args.dtb_path.display()
+    );
+
+    let output = if args.list {
+        let mut out = String::new();
+        for dev in &devices {
+            out.push_str(&format!(
+                "{:<48} [{:#x}, {:#x}) irq {:#x}\n",
+                dev.name,
+                dev.base_hpa,
+                dev.base_hpa.saturating_add(dev.length),
+                dev.irq_id
+            ));
+        }
+        out
+    } else {
+        format_passthrough_devices(&devices)
+    };
+    match &args.output {
+        None => print!("{}", output),
+        Some(path) => {
+            if let Err(err) = fs::write(path, output) {
+                eprintln!("Error: Failed to write file '{}': {}", path.display(), err);
+                std::process::exit(1);
+            }
+            println!(
+                "Passthrough devices have been written to '{}'.",
+                path.display()
+            );
+        }
+    }
+}
+
+/// Generate the device tree of a guest from its VM configuration
+///
+/// The size of the ramdisk, needed for `linux,initrd-end`, is read from the ramdisk image on
+/// the host if it can be found. Exits the process if the configuration cannot be read or is
+/// invalid.
+fn gen_dtb(args: GenDtbArgs) {
+    let guest_arch = match args.arch {
+        Arch::Riscv64 => GuestArch::Riscv64,
+        Arch::Aarch64 => GuestArch::Aarch64,
+        Arch::X86_64 => {
+            eprintln!("Error: x86_64 guests are not described by device trees");
+            std::process::exit(1);
+        }
+    };
+    let config = match load_vm_config(&args.config_path) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!(
+                "Error: Config file '{}' is invalid: {}",
+                args.config_path, err
+            );
+            std::process::exit(1);
+        }
+    };
+    let diagnostics = config.validate();
+    for diag in &diagnostics {
+        eprintln!("{}", diag);
+    }
+    if diagnostics.iter().any(|diag| diag.is_error()) {
+        eprintln!(
+            "Error: Config file '{}' is invalid, no device tree generated.",
+            args.config_path
+        );
+        std::process::exit(1);
+    }
+
+    let config_dir = Path::new(&args.config_path)
+        .parent()
+        .unwrap_or(Path::new("."));
+    let ramdisk_size = config
+        .kernel
+        .ramdisk_path
+        .as_deref()
+        .and_then(|ramdisk| host_image_size(config_dir, ramdisk));
+    if config.kernel.ramdisk_path.is_some() && ramdisk_size.is_none() {
+        eprintln!("Warning: ramdisk image not found on the host, /chosen has no initrd range");
+    }
+    let host = args.dtb_path.as_deref().map(read_fdt);
+    let fdt = config.guest_fdt(guest_arch, ramdisk_size, host.as_ref());
+
+    write_fdt(&fdt, args.format, args.output.as_deref());
+    if let Some(path) = &args.output {
+        println!(
+            "Device tree of VM '{}' has been written to '{}'.",
+            config.base.name,
+            path.display()
+        );
+    }
+}
+
+/// Derive the device tree of a VM from the host device tree by removing excluded devices
+///
+/// Exits the process if the device tree or the configuration file cannot be read.
+fn filter_dtb(args: FilterDtbArgs) {
+    let mut fdt = read_fdt(&args.dtb_path);
+    let excluded = excluded_devices(args.config_path.as_deref(), args.exclude);
+    let removed = fdt.remove_devices(&excluded);
+    eprintln!(
+        "Removed {} node(s) from '{}':",
+        removed.len(),
+        args.dtb_path.display()
+    );
+    for path in &removed {
+        eprintln!("  {}", path);
+    }
+
+    write_fdt(&fdt, args.format, args.output.as_deref());
+    if let Some(path) = &args.output {
+        println!("Device tree has been written to '{}'.", path.display());
+    }
+}
+
+/// Main entry point for the CLI tool
+///
+/// Parses command line arguments and dispatches to appropriate handlers
+/// for either configuration validation or template generation.
+pub fn run() {
+    let cli = CLI::parse();
+    match cli.subcmd {
+        // Handle configuration file validation
+        CLISubCmd::Check(args) => check(args),
         // Handle template generation
         CLISubCmd::Generate(args) => {
             // Determine the kernel path based on image location
             // For memory-based images, use absolute path; for fs-based, use relative path
-            let kernel_path = if args.image_location == "memory" {
+            let kernel_path = if args.image_location == ImageLocation::Memory {
                 Path::new(&args.kernel_path)
                     .canonicalize()
                     .unwrap()
@@ -175,8 +1456,9 @@ pub fn run() {
 
             // Generate the VM configuration template with provided parameters
             let template = get_vm_config_template(
+                args.arch,
                 args.id,
-                args.name + "-" + args.arch.as_str(),
+                format!("{}-{}", args.name, args.arch),
                 args.vm_type,
                 args.cpu_num,
                 args.entry_point,
@@ -186,8 +1468,8 @@ pub fn run() {
                 args.cmdline,
             );
 
-            // Convert the configuration template to TOML format
-            let template_toml = toml::to_string(&template).unwrap();
+            // Convert the configuration template to TOML in the style of hand-written files
+            let template_toml = template.to_toml();
 
             // Determine output file path (use provided path or default to current directory)
             let target_path = match args.output {
@@ -209,5 +1491,72 @@ pub fn run() {
                 }
             }
         }
+        // Handle legacy configuration migration
+        CLISubCmd::Migrate(args) => {
+            let file_path = &args.config_path;
+
+            let file_content = match fs::read_to_string(file_path) {
+                Ok(content) => content,
+                Err(err) => {
+                    eprintln!("Error: Failed to read file '{}': {}", file_path, err);
+                    std::process::exit(1);
+                }
+            };
+
+            let (config_toml, upgrade) = match migrate_toml(&file_content) {
+                Ok(result) => result,
+                Err(err) => {
+                    eprintln!("Error: Config file '{}' is invalid: {}", file_path, err);
+                    std::process::exit(1);
+                }
+            };
+
+            // Files already in the current schema are left untouched
+            if upgrade.source_version == CURRENT_CONFIG_VERSION {
+                println!(
+                    "Config file '{}' is already in the current format.",
+                    file_path
+                );
+                return;
+            }
+            for warning in &upgrade.warnings {
+                eprintln!("{}", warning);
+            }
+
+            // Rewrite the file in place unless an output path is given
+            let target_path = args
+                .output
+                .unwrap_or_else(|| std::path::PathBuf::from(file_path));
+            match fs::write(&target_path, config_toml) {
+                Ok(_) => {
+                    println!(
+                        "Config file '{}' has been migrated to '{:?}'.",
+                        file_path, target_path
+                    );
+                }
+                Err(err) => {
+                    eprintln!(
+                        "Error: Failed to write config file '{:?}': {}",
+                        target_path, err
+                    );
+                    std::process::exit(1);
+                }
+            }
+        }
+        // Handle configuration formatting
+        CLISubCmd::Fmt(args) => fmt(args),
+        // Handle semantic comparison
+        CLISubCmd::Diff(args) => diff(args),
+        // Handle scripted reads and edits of single values
+        CLISubCmd::Get(args) => get(args),
+        CLISubCmd::Set(args) => set(args),
+        // Handle configuration inheritance
+        CLISubCmd::Resolve(args) => resolve(args),
+        // Handle device tree import
+        CLISubCmd::ImportDtb(args) => import_dtb(args),
+        // Handle guest device tree generation
+        CLISubCmd::GenDtb(args) => gen_dtb(args),
+        // Handle host device tree filtering
+        CLISubCmd::FilterDtb(args) => filter_dtb(args),
     }
 }
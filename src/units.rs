@@ -0,0 +1,119 @@
+//! Human-readable sizes and addresses.
+//!
+//! Sizes such as `0x4000_0000` are hard to audit, so size fields also accept strings with a
+//! binary unit suffix, e.g. `"1G"`, `"16MiB"` or `"4K"`. Integers keep working as before.
+use alloc::string::{String, ToString};
+use core::fmt::{Display, Formatter};
+
+/// Error returned by [`parse_size`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseSizeError {
+    /// The string is empty.
+    Empty,
+    /// The numeric part is not a valid number in its radix.
+    InvalidNumber(String),
+    /// The unit suffix is not one of the supported units.
+    UnknownUnit(String),
+    /// The value does not fit into `usize`.
+    Overflow,
+}
+
+impl Display for ParseSizeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ParseSizeError::Empty => write!(f, "empty size"),
+            ParseSizeError::InvalidNumber(s) => write!(f, "invalid number '{s}'"),
+            ParseSizeError::UnknownUnit(unit) => write!(
+                f,
+                "unknown unit '{unit}', expected one of B, K, KiB, M, MiB, G, GiB, T, TiB"
+            ),
+            ParseSizeError::Overflow => write!(f, "size does not fit into usize"),
+        }
+    }
+}
+
+impl core::error::Error for ParseSizeError {}
+
+/// Returns the multiplier of a unit suffix. Units are always binary, so `K`, `KB` and `KiB` all
+/// mean 1024 bytes, matching how memory sizes are usually given.
+fn unit_multiplier(unit: &str) -> Option<usize> {
+    let shift = match unit.to_ascii_lowercase().as_str() {
+        "" | "b" => 0,
+        "k" | "kb" | "kib" => 10,
+        "m" | "mb" | "mib" => 20,
+        "g" | "gb" | "gib" => 30,
+        "t" | "tb" | "tib" => 40,
+        _ => return None,
+    };
+    1usize.checked_shl(shift)
+}
+
+/// Parses a size or address given as a string.
+///
+/// Supported formats, where `_` may be used to group digits:
+/// - Hexadecimal (0x prefix), octal (0o prefix) and binary (0b prefix): e.g. `0x4000_0000`
+/// - Decimal with an optional binary unit suffix: e.g. `4096`, `4K`, `16MiB`, `1 G`
+pub fn parse_size(s: &str) -> Result<usize, ParseSizeError> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Err(ParseSizeError::Empty);
+    }
+
+    let prefix = s.get(..2).map(str::to_ascii_lowercase);
+    let (radix, digits, unit) = match prefix.as_deref() {
+        Some("0x") => (16, &s[2..], ""),
+        Some("0o") => (8, &s[2..], ""),
+        Some("0b") => (2, &s[2..], ""),
+        _ => {
+            let end = s
+                .find(|c: char| !c.is_ascii_digit() && c != '_')
+                .unwrap_or(s.len());
+            (10, &s[..end], s[end..].trim_start())
+        }
+    };
+
+    let digits = digits.replace('_', "");
+    if digits.is_empty() {
+        return Err(ParseSizeError::InvalidNumber(s.to_string()));
+    }
+    let value = usize::from_str_radix(&digits, radix).map_err(|err| match err.kind() {
+        core::num::IntErrorKind::PosOverflow => ParseSizeError::Overflow,
+        _ => ParseSizeError::InvalidNumber(s.to_string()),
+    })?;
+    let multiplier =
+        unit_multiplier(unit).ok_or_else(|| ParseSizeError::UnknownUnit(unit.to_string()))?;
+    value
+        .checked_mul(multiplier)
+        .ok_or(ParseSizeError::Overflow)
+}
+
+/// Deserializes a size from either an integer or a string accepted by [`parse_size`].
+pub(crate) fn deserialize_size<'de, D>(deserializer: D) -> Result<usize, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    struct SizeVisitor;
+
+    impl serde::de::Visitor<'_> for SizeVisitor {
+        type Value = usize;
+
+        fn expecting(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+            write!(f, "an integer or a size string like \"16MiB\"")
+        }
+
+        fn visit_u64<E: serde::de::Error>(self, v: u64) -> Result<usize, E> {
+            usize::try_from(v).map_err(|_| E::custom(ParseSizeError::Overflow))
+        }
+
+        fn visit_i64<E: serde::de::Error>(self, v: i64) -> Result<usize, E> {
+            usize::try_from(v)
+                .map_err(|_| E::invalid_value(serde::de::Unexpected::Signed(v), &self))
+        }
+
+        fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<usize, E> {
+            parse_size(v).map_err(E::custom)
+        }
+    }
+
+    deserializer.deserialize_any(SizeVisitor)
+}
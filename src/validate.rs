@@ -0,0 +1,471 @@
+//! Semantic validation of [`AxVMCrateConfig`].
+//!
+//! Deserializing a TOML file only guarantees that every field has the right shape. The checks in
+//! this module look at the configuration as a whole and report cross-field problems, e.g. a
+//! `phys_cpu_sets` array whose length does not match `cpu_num`, or two memory regions sharing the
+//! same guest physical addresses.
+use alloc::collections::BTreeSet;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{Display, Formatter};
+use core::ops::Range;
+
+use crate::addr_map::{GpaOwnerKind, GpaRange};
+use crate::location::locate_key;
+use crate::{
+    AxVMCrateConfig, EmulatedDeviceType, ImageLocation, VMBaseConfig, VMDevicesConfig,
+    VMInterruptMode, VMKernelConfig, VmMemFlags,
+};
+
+/// The severity of a [`Diagnostic`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// The configuration is usable, but probably not what the user intended.
+    Warning,
+    /// The configuration cannot be used to create a VM.
+    Error,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// A single problem found by [`AxVMCrateConfig::validate`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// How serious the problem is.
+    pub severity: Severity,
+    /// A stable code identifying the kind of problem, e.g. `C104`, for tools that filter or
+    /// suppress diagnostics. The message may change between releases, the code does not.
+    pub code: &'static str,
+    /// The TOML key path of the offending field, e.g. `base.phys_cpu_sets` or
+    /// `kernel.memory_regions[1]`.
+    pub path: String,
+    /// A human-readable description of the problem.
+    pub message: String,
+}
+
+impl Diagnostic {
+    /// Creates an error diagnostic.
+    pub fn error(code: &'static str, path: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Error,
+            code,
+            path: path.into(),
+            message: message.into(),
+        }
+    }
+
+    /// Creates a warning diagnostic.
+    pub fn warning(
+        code: &'static str,
+        path: impl Into<String>,
+        message: impl Into<String>,
+    ) -> Self {
+        Self {
+            severity: Severity::Warning,
+            code,
+            path: path.into(),
+            message: message.into(),
+        }
+    }
+
+    /// Returns true if the diagnostic is an error.
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+
+    /// Returns the byte range of the offending field in `source`, the TOML file the diagnostic
+    /// was reported for. See [`locate_key`] for how the key path is resolved.
+    pub fn span_in(&self, source: &str) -> Option<Range<usize>> {
+        locate_key(source, &self.path)
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}[{}]: ", self.severity, self.code)?;
+        if !self.path.is_empty() {
+            write!(f, "{}: ", self.path)?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Returns true if the two half-open ranges `[a_start, a_end)` and `[b_start, b_end)` overlap.
+pub(crate) fn ranges_overlap(a_start: usize, a_end: usize, b_start: usize, b_end: usize) -> bool {
+    a_start < b_end && b_start < a_end
+}
+
+impl AxVMCrateConfig {
+    /// Checks the configuration for semantic errors that TOML deserialization cannot catch.
+    ///
+    /// Returns every problem found, ordered by section. An empty list means the configuration is
+    /// valid; a list containing only [`Severity::Warning`]s means it is usable.
+    ///
+    /// Image load addresses are only checked for placement, since image sizes are unknown here;
+    /// use [`AxVMCrateConfig::validate_with_image_sizes`] to also check the loaded images.
+    pub fn validate(&self) -> Vec<Diagnostic> {
+        self.validate_with_image_sizes(|_| None)
+    }
+
+    /// Same as [`AxVMCrateConfig::validate`], but uses `image_size` to look up the size of the
+    /// kernel, BIOS, DTB and ramdisk images by their configured path.
+    ///
+    /// `image_size` is only consulted if `kernel.image_location` is [`ImageLocation::Memory`], i.e. the images
+    /// are read from the host when the hypervisor is built. It returns `None` for images whose
+    /// size is unknown, for which only the load address is checked.
+    pub fn validate_with_image_sizes(
+        &self,
+        image_size: impl Fn(&str) -> Option<usize>,
+    ) -> Vec<Diagnostic> {
+        let mut diags = Vec::new();
+        validate_base(&self.base, &mut diags);
+        validate_kernel(&self.kernel, &mut diags);
+        validate_devices(&self.devices, &mut diags);
+        validate_address_map(self, &mut diags);
+        validate_images(&self.kernel, image_size, &mut diags);
+        diags
+    }
+}
+
+fn validate_base(base: &VMBaseConfig, diags: &mut Vec<Diagnostic>) {
+    if base.name.is_empty() {
+        diags.push(Diagnostic::warning("C101", "base.name", "VM name is empty"));
+    }
+
+    if base.cpu_num == 0 {
+        diags.push(Diagnostic::error(
+            "C102",
+            "base.cpu_num",
+            "VM must have at least one vCPU",
+        ));
+    }
+
+    if let Some(ids) = &base.phys_cpu_ids {
+        if ids.len() != base.cpu_num {
+            diags.push(Diagnostic::error(
+                "C103",
+                "base.phys_cpu_ids",
+                format!("has {} entries but cpu_num is {}", ids.len(), base.cpu_num),
+            ));
+        }
+        let mut seen = BTreeSet::new();
+        for (i, id) in ids.iter().enumerate() {
+            if !seen.insert(*id) {
+                diags.push(Diagnostic::error(
+                    "C104",
+                    format!("base.phys_cpu_ids[{i}]"),
+                    format!("physical CPU id {id:#x} is assigned to more than one vCPU"),
+                ));
+            }
+        }
+    }
+
+    if let Some(sets) = &base.phys_cpu_sets {
+        if sets.len() != base.cpu_num {
+            diags.push(Diagnostic::error(
+                "C105",
+                "base.phys_cpu_sets",
+                format!("has {} entries but cpu_num is {}", sets.len(), base.cpu_num),
+            ));
+        }
+        for (i, mask) in sets.iter().enumerate() {
+            if *mask == 0 {
+                diags.push(Diagnostic::error(
+                    "C106",
+                    format!("base.phys_cpu_sets[{i}]"),
+                    format!("vCPU {i} has an empty physical CPU mask and can never run"),
+                ));
+            }
+        }
+    }
+}
+
+fn validate_kernel(kernel: &VMKernelConfig, diags: &mut Vec<Diagnostic>) {
+    if kernel.kernel_path.is_empty() {
+        diags.push(Diagnostic::error(
+            "C201",
+            "kernel.kernel_path",
+            "kernel image path is empty",
+        ));
+    }
+
+    // An image without a load address cannot be loaded, a load address without an image is
+    // merely suspicious.
+    let images = [
+        ("bios", &kernel.bios_path, &kernel.bios_load_addr),
+        ("ramdisk", &kernel.ramdisk_path, &kernel.ramdisk_load_addr),
+    ];
+    for (image, path, load_addr) in images {
+        match (path, load_addr) {
+            (Some(_), None) => diags.push(Diagnostic::error(
+                "C202",
+                format!("kernel.{image}_load_addr"),
+                format!("{image}_path is set but {image}_load_addr is missing"),
+            )),
+            (None, Some(_)) => diags.push(Diagnostic::warning(
+                "C203",
+                format!("kernel.{image}_path"),
+                format!("{image}_load_addr is set but {image}_path is missing"),
+            )),
+            _ => {}
+        }
+    }
+    if kernel.dtb_path.is_some() && kernel.dtb_load_addr.is_none() {
+        diags.push(Diagnostic::error(
+            "C202",
+            "kernel.dtb_load_addr",
+            "dtb_path is set but dtb_load_addr is missing",
+        ));
+    }
+
+    if kernel.memory_regions.is_empty() {
+        diags.push(Diagnostic::error(
+            "C204",
+            "kernel.memory_regions",
+            "VM has no memory regions",
+        ));
+    }
+    for (i, region) in kernel.memory_regions.iter().enumerate() {
+        let path = format!("kernel.memory_regions[{i}]");
+        if region.size == 0 {
+            diags.push(Diagnostic::error(
+                "C205",
+                path,
+                "memory region has zero size",
+            ));
+        } else if region.gpa.checked_add(region.size).is_none() {
+            diags.push(Diagnostic::error(
+                "C206",
+                path,
+                format!(
+                    "memory region [{:#x}, +{:#x}) exceeds the address space",
+                    region.gpa, region.size
+                ),
+            ));
+        }
+    }
+}
+
+fn validate_devices(devices: &VMDevicesConfig, diags: &mut Vec<Diagnostic>) {
+    for (i, dev) in devices.emu_devices.iter().enumerate() {
+        if dev.length == 0 {
+            diags.push(Diagnostic::error(
+                "C301",
+                format!("devices.emu_devices[{i}]"),
+                format!("emulated device '{}' has zero length", dev.name),
+            ));
+        }
+    }
+
+    let names = devices
+        .emu_devices
+        .iter()
+        .enumerate()
+        .map(|(i, dev)| (format!("devices.emu_devices[{i}]"), &dev.name))
+        .chain(
+            devices
+                .passthrough_devices
+                .iter()
+                .enumerate()
+                .map(|(i, dev)| (format!("devices.passthrough_devices[{i}]"), &dev.name)),
+        );
+    let mut seen = BTreeSet::new();
+    for (path, name) in names {
+        if name.is_empty() {
+            diags.push(Diagnostic::warning("C302", path, "device name is empty"));
+        } else if !seen.insert(name) {
+            diags.push(Diagnostic::warning(
+                "C303",
+                path,
+                format!("device name '{name}' is used more than once"),
+            ));
+        }
+    }
+
+    if devices.interrupt_mode == VMInterruptMode::Emulated
+        && !devices
+            .emu_devices
+            .iter()
+            .any(|dev| dev.emu_type == EmulatedDeviceType::InterruptController)
+    {
+        diags.push(Diagnostic::warning(
+            "C304",
+            "devices.interrupt_mode",
+            "interrupt mode is \"emulated\" but no emulated interrupt controller is configured",
+        ));
+    }
+}
+
+fn validate_address_map(config: &AxVMCrateConfig, diags: &mut Vec<Diagnostic>) {
+    let kernel = &config.kernel;
+    for overlap in config.gpa_map().overlaps() {
+        let (first, second) = (&overlap.first, &overlap.second);
+        let message = format!("{second} overlaps {first}");
+
+        // Identity-mapping an MMIO window as a device memory region in addition to passing the
+        // device through is redundant, but harmless.
+        let device_region = |range: &GpaRange, other: &GpaRange| {
+            range.kind == GpaOwnerKind::MemoryRegion
+                && other.kind == GpaOwnerKind::PassthroughDevice
+                && kernel.memory_regions[range.index]
+                    .flags
+                    .contains(VmMemFlags::DEVICE)
+        };
+        if device_region(first, second) || device_region(second, first) {
+            diags.push(Diagnostic::warning("C401", second.path.clone(), message));
+        } else {
+            diags.push(Diagnostic::error("C401", second.path.clone(), message));
+        }
+    }
+}
+
+/// An image loaded into guest memory before the VM starts.
+struct LoadedImage {
+    name: &'static str,
+    path: String,
+    load_addr: usize,
+    size: Option<usize>,
+}
+
+impl LoadedImage {
+    fn end(&self) -> usize {
+        self.load_addr.saturating_add(self.size.unwrap_or(1))
+    }
+}
+
+impl Display for LoadedImage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self.size {
+            Some(size) => write!(
+                f,
+                "{} image [{:#x}, {:#x})",
+                self.name,
+                self.load_addr,
+                self.load_addr.saturating_add(size)
+            ),
+            None => write!(f, "{} image at {:#x}", self.name, self.load_addr),
+        }
+    }
+}
+
+/// Returns true if `[start, end)` is fully covered by the half-open `ranges`, which must be
+/// sorted by start address. Adjacent ranges are treated as one contiguous range.
+pub(crate) fn covered_by(ranges: &[(usize, usize)], start: usize, end: usize) -> bool {
+    let mut cursor = start;
+    for &(range_start, range_end) in ranges {
+        if range_start > cursor {
+            break;
+        }
+        if range_end > cursor {
+            cursor = range_end;
+        }
+        if cursor >= end {
+            return true;
+        }
+    }
+    false
+}
+
+fn validate_images(
+    kernel: &VMKernelConfig,
+    image_size: impl Fn(&str) -> Option<usize>,
+    diags: &mut Vec<Diagnostic>,
+) {
+    let host_images = kernel.image_location == ImageLocation::Memory;
+
+    let mut ram: Vec<(usize, usize)> = kernel
+        .memory_regions
+        .iter()
+        .filter(|region| !region.flags.contains(VmMemFlags::DEVICE))
+        .map(|region| (region.gpa, region.gpa.saturating_add(region.size)))
+        .collect();
+    ram.sort_unstable();
+    if ram.is_empty() {
+        // Already reported as missing memory regions, or everything is device memory.
+        return;
+    }
+
+    let candidates = [
+        (
+            "kernel",
+            Some(kernel.kernel_load_addr),
+            Some(&kernel.kernel_path),
+        ),
+        ("bios", kernel.bios_load_addr, kernel.bios_path.as_ref()),
+        ("dtb", kernel.dtb_load_addr, kernel.dtb_path.as_ref()),
+        (
+            "ramdisk",
+            kernel.ramdisk_load_addr,
+            kernel.ramdisk_path.as_ref(),
+        ),
+    ];
+    let images: Vec<LoadedImage> = candidates
+        .into_iter()
+        .filter_map(|(name, load_addr, file)| {
+            let file = file.map(String::as_str).filter(|file| !file.is_empty());
+            Some(LoadedImage {
+                name,
+                path: format!("kernel.{name}_load_addr"),
+                load_addr: load_addr?,
+                size: file.filter(|_| host_images).and_then(&image_size),
+            })
+        })
+        .collect();
+
+    for image in &images {
+        if !covered_by(&ram, image.load_addr, image.end()) {
+            let what = if image.size.is_some() {
+                "does not fit in guest RAM"
+            } else {
+                "is not inside guest RAM"
+            };
+            diags.push(Diagnostic::error(
+                "C501",
+                image.path.clone(),
+                format!("{image} {what}"),
+            ));
+        }
+    }
+
+    for (i, a) in images.iter().enumerate() {
+        for b in &images[i + 1..] {
+            // Without sizes, only images placed at the very same address are known to collide.
+            let collide = match (a.size, b.size) {
+                (Some(_), Some(_)) => ranges_overlap(a.load_addr, a.end(), b.load_addr, b.end()),
+                (Some(_), None) => (a.load_addr..a.end()).contains(&b.load_addr),
+                (None, Some(_)) => (b.load_addr..b.end()).contains(&a.load_addr),
+                (None, None) => a.load_addr == b.load_addr,
+            };
+            if collide {
+                diags.push(Diagnostic::error(
+                    "C502",
+                    b.path.clone(),
+                    format!("{b} collides with {a}"),
+                ));
+            }
+        }
+    }
+
+    if !covered_by(
+        &ram,
+        kernel.entry_point,
+        kernel.entry_point.saturating_add(1),
+    ) {
+        diags.push(Diagnostic::error(
+            "C503",
+            "kernel.entry_point",
+            format!(
+                "entry point {:#x} is not inside guest RAM",
+                kernel.entry_point
+            ),
+        ));
+    }
+}
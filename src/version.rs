@@ -0,0 +1,137 @@
+//! Versioning of the configuration schema.
+//!
+//! Every configuration file is written for a version of the schema, given by the optional
+//! top-level `config_version` key. Files written for older versions are upgraded to the current
+//! version step by step when they are loaded, and each step reports what it had to fill in or
+//! convert.
+//!
+//! Schema versions:
+//! - 0: the legacy flat format, with all fields at the top level.
+//! - 1: fields are split into the `[base]`, `[kernel]` and `[devices]` tables.
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::{locate_key, AxVMCrateConfig, ConfigParseError, ConfigParseErrorKind, Diagnostic};
+
+/// The version of the configuration schema implemented by this crate.
+pub const CURRENT_CONFIG_VERSION: u32 = 1;
+
+/// An upgrade step, converting a configuration table from version `i` to `i + 1`, where `i` is
+/// the index of the step in [`UPGRADE_STEPS`].
+type UpgradeStep = fn(&mut toml::Table, &mut Vec<Diagnostic>) -> Result<(), toml::de::Error>;
+
+const UPGRADE_STEPS: [UpgradeStep; CURRENT_CONFIG_VERSION as usize] = [upgrade_v0];
+
+/// Describes how a configuration was upgraded to [`CURRENT_CONFIG_VERSION`] while loading it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigUpgrade {
+    /// The schema version the configuration was written for.
+    pub source_version: u32,
+    /// True if the configuration has no `config_version` key and `source_version` was inferred
+    /// from its layout.
+    pub inferred: bool,
+    /// Warnings about fields that were filled in or converted by the upgrade.
+    pub warnings: Vec<Diagnostic>,
+}
+
+/// Upgrades the legacy flat format to the sectioned format.
+fn upgrade_v0(
+    table: &mut toml::Table,
+    warnings: &mut Vec<Diagnostic>,
+) -> Result<(), toml::de::Error> {
+    warnings.push(Diagnostic::warning(
+        "C010",
+        "config_version",
+        "config uses the legacy flat format, fields are moved into [base], [kernel] and [devices]",
+    ));
+    *table = crate::legacy::upgrade_legacy_table(table)?;
+    Ok(())
+}
+
+/// Determines the schema version of a configuration table, returning the version and whether it
+/// was inferred from the layout.
+fn detect_version(table: &toml::Table) -> Result<(u32, bool), String> {
+    match table.get("config_version") {
+        Some(toml::Value::Integer(version)) => match u32::try_from(*version) {
+            Ok(version) if version <= CURRENT_CONFIG_VERSION => Ok((version, false)),
+            _ => Err(format!(
+                "config_version {version} is not supported, the latest version is {CURRENT_CONFIG_VERSION}"
+            )),
+        },
+        Some(_) => Err(String::from("config_version must be an integer")),
+        None if crate::legacy::is_legacy_table(table) => Ok((0, true)),
+        None => Ok((1, true)),
+    }
+}
+
+impl AxVMCrateConfig {
+    /// Deserialize the toml string to `AxVMCrateConfig`, upgrading configurations written for
+    /// older schema versions to [`CURRENT_CONFIG_VERSION`].
+    ///
+    /// Returns the configuration together with a description of the upgrade. The returned
+    /// configuration always has `config_version` set to [`CURRENT_CONFIG_VERSION`]. Errors tell
+    /// where the file is wrong, except for files that were upgraded from the legacy flat
+    /// format, whose errors are only described.
+    pub fn from_toml_with_upgrade(
+        raw_cfg_str: &str,
+    ) -> Result<(Self, ConfigUpgrade), ConfigParseError> {
+        let mut table: toml::Table = toml::from_str(raw_cfg_str).map_err(|err| {
+            ConfigParseError::from_toml(ConfigParseErrorKind::Syntax, &err, raw_cfg_str)
+        })?;
+        let (source_version, inferred) = detect_version(&table).map_err(|message| {
+            ConfigParseError::new(
+                ConfigParseErrorKind::Version,
+                message,
+                raw_cfg_str,
+                Some("config_version"),
+                locate_key(raw_cfg_str, "config_version"),
+            )
+        })?;
+
+        let original = table.clone();
+        let mut warnings = Vec::new();
+        for step in &UPGRADE_STEPS[source_version as usize..] {
+            step(&mut table, &mut warnings).map_err(|err| {
+                ConfigParseError::new(
+                    ConfigParseErrorKind::Version,
+                    format!(
+                        "cannot upgrade from config_version {source_version}: {}",
+                        err.message()
+                    ),
+                    raw_cfg_str,
+                    Some(""),
+                    None,
+                )
+            })?;
+        }
+
+        // Deserialize from the original string unless the layout was changed, so that errors
+        // point into the file as written.
+        let mut config: AxVMCrateConfig = if table == original {
+            toml::from_str(raw_cfg_str).map_err(|err| {
+                ConfigParseError::from_toml(ConfigParseErrorKind::Schema, &err, raw_cfg_str)
+            })?
+        } else {
+            toml::Value::Table(table).try_into().map_err(|err| {
+                ConfigParseError::new(
+                    ConfigParseErrorKind::Schema,
+                    err.message(),
+                    raw_cfg_str,
+                    Some(""),
+                    None,
+                )
+            })?
+        };
+        config.config_version = Some(CURRENT_CONFIG_VERSION);
+
+        Ok((
+            config,
+            ConfigUpgrade {
+                source_version,
+                inferred,
+                warnings,
+            },
+        ))
+    }
+}
@@ -0,0 +1,115 @@
+//! Parsing and (de)serialization of [`VMType`].
+//!
+//! In TOML a VM type is written either as its number, e.g. `vm_type = 1`, or as its name, e.g.
+//! `vm_type = "rtos"`. Unknown numbers and names are rejected. It is always serialized as a
+//! number.
+use alloc::string::{String, ToString};
+use core::fmt::{Display, Formatter};
+use core::str::FromStr;
+
+use crate::VMType;
+
+/// Error returned when parsing a [`VMType`] from a string or a number that names no VM type.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseVMTypeError(String);
+
+impl Display for ParseVMTypeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "unknown VM type '{}', expected one of host (0), rtos (1), linux (2)",
+            self.0
+        )
+    }
+}
+
+impl core::error::Error for ParseVMTypeError {}
+
+impl VMType {
+    /// Returns the VM type with the given number, or `None` if there is none.
+    ///
+    /// Unlike `From<usize>`, unknown values are not mapped to the default type.
+    pub fn from_usize(value: usize) -> Option<Self> {
+        match value {
+            0 => Some(Self::VMTHostVM),
+            1 => Some(Self::VMTRTOS),
+            2 => Some(Self::VMTLinux),
+            _ => None,
+        }
+    }
+
+    /// Returns the short name of the VM type, as accepted in configuration files.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::VMTHostVM => "host",
+            Self::VMTRTOS => "rtos",
+            Self::VMTLinux => "linux",
+        }
+    }
+}
+
+impl Display for VMType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for VMType {
+    type Err = ParseVMTypeError;
+
+    /// Parses a VM type from its number or, case-insensitively, its name. Besides the short
+    /// names, `hostvm` and the variant names such as `VMTRTOS` are accepted.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Ok(value) = s.parse::<usize>() {
+            return Self::from_usize(value).ok_or_else(|| ParseVMTypeError(s.to_string()));
+        }
+        match s.to_ascii_lowercase().as_str() {
+            "host" | "hostvm" | "vmthostvm" => Ok(Self::VMTHostVM),
+            "rtos" | "vmtrtos" => Ok(Self::VMTRTOS),
+            "linux" | "vmtlinux" => Ok(Self::VMTLinux),
+            _ => Err(ParseVMTypeError(s.to_string())),
+        }
+    }
+}
+
+impl serde::Serialize for VMType {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(usize::from(*self) as u64)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for VMType {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct VMTypeVisitor;
+
+        impl serde::de::Visitor<'_> for VMTypeVisitor {
+            type Value = VMType;
+
+            fn expecting(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+                write!(
+                    f,
+                    "a VM type number or one of \"host\", \"rtos\", \"linux\""
+                )
+            }
+
+            fn visit_u64<E: serde::de::Error>(self, v: u64) -> Result<VMType, E> {
+                usize::try_from(v)
+                    .ok()
+                    .and_then(VMType::from_usize)
+                    .ok_or_else(|| E::custom(ParseVMTypeError(v.to_string())))
+            }
+
+            fn visit_i64<E: serde::de::Error>(self, v: i64) -> Result<VMType, E> {
+                let v = u64::try_from(v).map_err(|_| E::custom(ParseVMTypeError(v.to_string())))?;
+                self.visit_u64(v)
+            }
+
+            fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<VMType, E> {
+                v.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_any(VMTypeVisitor)
+    }
+}
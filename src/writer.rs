@@ -0,0 +1,382 @@
+//! Writing configurations back to TOML in the hand-written style.
+//!
+//! `toml::to_string` writes memory regions and devices as tables and addresses in decimal.
+//! [`AxVMCrateConfig::to_toml`] writes them like the files in `templates/`: memory regions and
+//! devices as tuple arrays with aligned columns, addresses and sizes in hexadecimal grouped by
+//! `_`, and a comment explaining each array.
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+use crate::{
+    AxVMCrateConfig, EmulatedDeviceConfig, PassThroughDeviceConfig, VMInterruptMode, VmMemConfig,
+    VmMemFlags, VmMemMappingType, CURRENT_CONFIG_VERSION,
+};
+
+/// The order of the top-level keys and tables.
+const SECTION_ORDER: &[&str] = &[
+    "extends",
+    "include",
+    "config_version",
+    "base",
+    "kernel",
+    "devices",
+];
+
+/// The order of the keys of each table, following the fields of the configuration structures.
+/// Keys not listed keep their relative order after the listed ones.
+const KEY_ORDER: &[(&str, &[&str])] = &[
+    ("", SECTION_ORDER),
+    (
+        "base",
+        &[
+            "id",
+            "name",
+            "vm_type",
+            "cpu_num",
+            "phys_cpu_ids",
+            "phys_cpu_sets",
+        ],
+    ),
+    (
+        "kernel",
+        &[
+            "entry_point",
+            "kernel_path",
+            "kernel_load_addr",
+            "bios_path",
+            "bios_load_addr",
+            "dtb_path",
+            "dtb_load_addr",
+            "ramdisk_path",
+            "ramdisk_load_addr",
+            "disk_path",
+            "cmdline",
+            "image_location",
+            "memory_regions",
+        ],
+    ),
+    (
+        "devices",
+        &[
+            "emu_devices",
+            "passthrough_devices",
+            "interrupt_mode",
+            "excluded_devices",
+        ],
+    ),
+    (
+        "kernel.memory_regions",
+        &["gpa", "size", "flags", "map_type"],
+    ),
+    (
+        "devices.emu_devices",
+        &[
+            "name", "base_gpa", "length", "irq_id", "emu_type", "cfg_list",
+        ],
+    ),
+    (
+        "devices.passthrough_devices",
+        &["name", "base_gpa", "base_hpa", "length", "irq_id"],
+    ),
+];
+
+/// The keys of the table at `path` in order, or the fields of the elements of the array at
+/// `path`. Empty for tables and arrays whose keys are not known.
+pub(crate) fn key_order(path: &str) -> &'static [&'static str] {
+    KEY_ORDER
+        .iter()
+        .find(|(table_path, _)| *table_path == path)
+        .map_or(&[], |(_, order)| order)
+}
+
+/// Formats a value in hexadecimal with the digits grouped by four, e.g. `0x8000_0000`.
+pub(crate) fn format_hex(value: usize) -> String {
+    let digits = format!("{value:x}");
+    let mut out = String::from("0x");
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i) % 4 == 0 {
+            out.push('_');
+        }
+        out.push(digit);
+    }
+    out
+}
+
+/// Formats a string as a TOML string, quoted and escaped.
+pub(crate) fn format_str(s: &str) -> String {
+    toml::Value::String(s.to_string()).to_string()
+}
+
+/// Formats a list of values as a TOML inline array.
+pub(crate) fn format_list(items: impl IntoIterator<Item = String>) -> String {
+    format!("[{}]", items.into_iter().collect::<Vec<_>>().join(", "))
+}
+
+/// Formats mapping flags as a list of flag names, e.g. `["read", "write", "exec"]`.
+fn format_flags(flags: VmMemFlags) -> String {
+    const NAMES: [(VmMemFlags, &str); 6] = [
+        (VmMemFlags::READ, "read"),
+        (VmMemFlags::WRITE, "write"),
+        (VmMemFlags::EXECUTE, "exec"),
+        (VmMemFlags::USER, "user"),
+        (VmMemFlags::DEVICE, "device"),
+        (VmMemFlags::UNCACHED, "uncached"),
+    ];
+    format_list(
+        NAMES
+            .iter()
+            .filter(|(flag, _)| flags.contains(*flag))
+            .map(|(_, name)| format_str(name)),
+    )
+}
+
+/// Formats a size as a comment, e.g. `16 MiB`, or in hexadecimal if it is not a whole number
+/// of KiB.
+fn format_size_comment(size: usize) -> String {
+    const UNITS: [(u32, &str); 4] = [(40, "TiB"), (30, "GiB"), (20, "MiB"), (10, "KiB")];
+    UNITS
+        .iter()
+        .find(|(shift, _)| size != 0 && size.is_multiple_of(1 << shift))
+        .map(|(shift, unit)| format!("{} {unit}", size >> shift))
+        .unwrap_or_else(|| format!("{} bytes", format_hex(size)))
+}
+
+/// The name of an interrupt mode, as written in TOML.
+pub(crate) fn interrupt_mode_name(mode: VMInterruptMode) -> &'static str {
+    match mode {
+        VMInterruptMode::NoIrq => "no_irq",
+        VMInterruptMode::Emulated => "emu",
+        VMInterruptMode::Passthrough => "passthrough",
+    }
+}
+
+impl VmMemConfig {
+    /// The fields of the region as written in the tuple form.
+    pub(crate) fn tuple_cells(&self) -> Vec<String> {
+        let map_type = match self.map_type {
+            VmMemMappingType::MapAlloc => 0,
+            VmMemMappingType::MapIdentical => 1,
+        };
+        Vec::from([
+            format_hex(self.gpa),
+            format_hex(self.size),
+            format_flags(self.flags),
+            map_type.to_string(),
+        ])
+    }
+}
+
+impl EmulatedDeviceConfig {
+    /// The fields of the device as written in the tuple form.
+    pub(crate) fn tuple_cells(&self) -> Vec<String> {
+        Vec::from([
+            format_str(&self.name),
+            format_hex(self.base_gpa),
+            format_hex(self.length),
+            format_hex(self.irq_id),
+            format_hex(self.emu_type as usize),
+            format_list(self.cfg_list.iter().copied().map(format_hex)),
+        ])
+    }
+}
+
+impl PassThroughDeviceConfig {
+    /// The fields of the device as written in the tuple form.
+    pub(crate) fn tuple_cells(&self) -> Vec<String> {
+        Vec::from([
+            format_str(&self.name),
+            format_hex(self.base_gpa),
+            format_hex(self.base_hpa),
+            format_hex(self.length),
+            format_hex(self.irq_id),
+        ])
+    }
+}
+
+/// Writes `key = value`.
+fn write_value(out: &mut String, key: &str, value: impl core::fmt::Display) {
+    let _ = writeln!(out, "{key} = {value}");
+}
+
+/// Writes an array of tuples, one per line, with the columns and trailing comments aligned.
+fn write_tuples(out: &mut String, key: &str, rows: &[(Vec<String>, Option<String>)]) {
+    if rows.is_empty() {
+        write_value(out, key, "[]");
+        return;
+    }
+
+    let columns = rows.iter().map(|(cells, _)| cells.len()).max().unwrap_or(0);
+    let widths: Vec<usize> = (0..columns)
+        .map(|i| {
+            rows.iter()
+                .filter_map(|(cells, _)| cells.get(i))
+                .map(String::len)
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+    let lines: Vec<String> = rows
+        .iter()
+        .map(|(cells, _)| {
+            let mut line = String::from("    [");
+            for (i, cell) in cells.iter().enumerate() {
+                if i + 1 == cells.len() {
+                    line.push_str(cell);
+                } else {
+                    let _ = write!(
+                        line,
+                        "{:<width$} ",
+                        format!("{cell},"),
+                        width = widths[i] + 1
+                    );
+                }
+            }
+            line.push_str("],");
+            line
+        })
+        .collect();
+    let line_width = lines.iter().map(String::len).max().unwrap_or(0);
+
+    let _ = writeln!(out, "{key} = [");
+    for (line, (_, comment)) in lines.iter().zip(rows) {
+        match comment {
+            Some(comment) => {
+                let _ = writeln!(out, "{line:<line_width$} # {comment}");
+            }
+            None => {
+                let _ = writeln!(out, "{line}");
+            }
+        }
+    }
+    out.push_str("]\n");
+}
+
+impl AxVMCrateConfig {
+    /// Serializes the configuration to TOML in the style of the hand-written configuration
+    /// files.
+    ///
+    /// Memory regions and devices are written as tuple arrays with aligned columns, addresses,
+    /// sizes, IRQs and CPU masks in hexadecimal, and each array is preceded by a comment giving
+    /// its format. `config_version` is always [`CURRENT_CONFIG_VERSION`], and optional fields
+    /// that are `None` are left out. Parsing the output gives back an equal configuration.
+    pub fn to_toml(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# Version of the configuration schema.\n");
+        write_value(&mut out, "config_version", CURRENT_CONFIG_VERSION);
+
+        let base = &self.base;
+        out.push_str("\n[base]\n");
+        write_value(&mut out, "id", base.id);
+        write_value(&mut out, "name", format_str(&base.name));
+        out.push_str("# VM type: \"host\", \"rtos\" or \"linux\".\n");
+        write_value(&mut out, "vm_type", format_str(base.vm_type.name()));
+        write_value(&mut out, "cpu_num", base.cpu_num);
+        if let Some(ids) = &base.phys_cpu_ids {
+            out.push_str("# Physical CPU ids of the vCPUs.\n");
+            write_value(
+                &mut out,
+                "phys_cpu_ids",
+                format_list(ids.iter().copied().map(format_hex)),
+            );
+        }
+        if let Some(sets) = &base.phys_cpu_sets {
+            out.push_str("# Masks of the physical CPUs each vCPU may run on.\n");
+            write_value(
+                &mut out,
+                "phys_cpu_sets",
+                format_list(sets.iter().copied().map(format_hex)),
+            );
+        }
+
+        let kernel = &self.kernel;
+        out.push_str("\n[kernel]\n");
+        write_value(&mut out, "entry_point", format_hex(kernel.entry_point));
+        write_value(&mut out, "kernel_path", format_str(&kernel.kernel_path));
+        write_value(
+            &mut out,
+            "kernel_load_addr",
+            format_hex(kernel.kernel_load_addr),
+        );
+        let images = [
+            ("bios", &kernel.bios_path, kernel.bios_load_addr),
+            ("dtb", &kernel.dtb_path, kernel.dtb_load_addr),
+            ("ramdisk", &kernel.ramdisk_path, kernel.ramdisk_load_addr),
+        ];
+        for (image, path, load_addr) in images {
+            if let Some(path) = path {
+                write_value(&mut out, &format!("{image}_path"), format_str(path));
+            }
+            if let Some(load_addr) = load_addr {
+                write_value(
+                    &mut out,
+                    &format!("{image}_load_addr"),
+                    format_hex(load_addr),
+                );
+            }
+        }
+        if let Some(disk_path) = &kernel.disk_path {
+            write_value(&mut out, "disk_path", format_str(disk_path));
+        }
+        if let Some(cmdline) = &kernel.cmdline {
+            write_value(&mut out, "cmdline", format_str(cmdline));
+        }
+        out.push_str("# Where the images are read from: \"fs\" or \"memory\".\n");
+        write_value(
+            &mut out,
+            "image_location",
+            format_str(kernel.image_location.name()),
+        );
+        out.push('\n');
+        out.push_str(
+            "# Memory regions with format [base_gpa, size, flags, map_type], where map_type is\n\
+             # 0 (allocated by the VM monitor) or 1 (identical to host physical memory).\n",
+        );
+        let regions: Vec<_> = kernel
+            .memory_regions
+            .iter()
+            .map(|region| (region.tuple_cells(), Some(format_size_comment(region.size))))
+            .collect();
+        write_tuples(&mut out, "memory_regions", &regions);
+
+        let devices = &self.devices;
+        out.push_str("\n[devices]\n");
+        out.push_str(
+            "# Emulated devices with format [name, base_gpa, length, irq_id, emu_type, cfg_list].\n",
+        );
+        let emu_devices: Vec<_> = devices
+            .emu_devices
+            .iter()
+            .map(|dev| (dev.tuple_cells(), Some(dev.emu_type.to_string())))
+            .collect();
+        write_tuples(&mut out, "emu_devices", &emu_devices);
+        out.push_str(
+            "# Passthrough devices with format [name, base_gpa, base_hpa, length, irq_id].\n",
+        );
+        let passthrough_devices: Vec<_> = devices
+            .passthrough_devices
+            .iter()
+            .map(|dev| (dev.tuple_cells(), None))
+            .collect();
+        write_tuples(&mut out, "passthrough_devices", &passthrough_devices);
+        out.push_str("# Interrupt mode: \"no_irq\", \"emu\" or \"passthrough\".\n");
+        write_value(
+            &mut out,
+            "interrupt_mode",
+            format_str(interrupt_mode_name(devices.interrupt_mode)),
+        );
+        out.push_str("# Host devices not given to the VM, as groups of device tree node paths.\n");
+        if devices.excluded_devices.is_empty() {
+            write_value(&mut out, "excluded_devices", "[]");
+        } else {
+            out.push_str("excluded_devices = [\n");
+            for group in &devices.excluded_devices {
+                let group = format_list(group.iter().map(|path| format_str(path)));
+                let _ = writeln!(out, "    {group},");
+            }
+            out.push_str("]\n");
+        }
+        out
+    }
+}